@@ -1,170 +1,7738 @@
 use base64::{engine::general_purpose, Engine as _};
-use bytemuck;
+use ebur128::{EbuR128, Mode};
+use flacenc::bitsink::ByteSink;
+use flacenc::component::BitRepr;
+use flacenc::error::Verify;
+use flacenc::source::MemSource;
+use mp3lame_encoder::{
+    max_required_buffer_size, Bitrate, Builder as Mp3Builder, FlushNoGap, InterleavedPcm, MonoPcm,
+    Quality as Mp3Quality,
+};
+use numpy::{IntoPyArray, PyArray1, PyReadonlyArray1};
+use ogg::writing::{PacketWriteEndInfo, PacketWriter};
+use pyo3::create_exception;
+use pyo3::exceptions::{PyException, PyTypeError};
 use pyo3::prelude::*;
+use pyo3::pybacked::PyBackedBytes;
 use pyo3::pyfunction;
-use std::io::Cursor;
+use pyo3::types::{PyBytes, PyDict};
+use rayon::prelude::*;
+use rubato::{FftFixedIn, Resampler};
+use rustfft::num_complex::Complex;
+use rustfft::FftPlanner;
+use std::borrow::Cow;
+use std::cell::RefCell;
+use std::collections::HashMap;
+use std::fmt;
+use std::fs::File;
+use std::io::{BufWriter, Cursor, Read, Seek, SeekFrom, Write};
 use std::num::{NonZeroU32, NonZeroU8};
+use std::path::{Path, PathBuf};
+use std::rc::Rc;
+use std::str::FromStr;
+use std::sync::Arc;
+use std::time::{Duration, Instant};
 use symphonia::core::audio::SampleBuffer;
-use symphonia::core::formats::FormatOptions;
-use symphonia::core::io::MediaSourceStream;
-use symphonia::core::meta::MetadataOptions;
-use symphonia::core::probe::Hint;
+use symphonia::core::codecs::{CodecType, Decoder};
+use symphonia::core::formats::{FormatOptions, FormatReader, SeekMode, SeekTo, Track};
+use symphonia::core::io::{MediaSource, MediaSourceStream};
+use symphonia::core::meta::{MetadataOptions, MetadataRevision, StandardTagKey, Value};
+use symphonia::core::probe::{Hint, Probe, ProbeResult};
+use symphonia::core::units::{Time, TimeBase};
 use symphonia::default;
-use vorbis_rs::{VorbisEncoderBuilder, VorbisError};
+use symphonia::default::formats::{
+    AdtsReader, FlacReader, IsoMp4Reader, MkvReader, MpaReader, OggReader, WavReader,
+};
+use symphonia_metadata::id3v2::Id3v2Reader;
+use unsafe_libopus::{
+    opus_encode_float, opus_encoder_create, opus_encoder_ctl, opus_encoder_destroy, opus_strerror,
+    OpusEncoder, OPUS_APPLICATION_AUDIO, OPUS_APPLICATION_RESTRICTED_LOWDELAY,
+    OPUS_APPLICATION_VOIP, OPUS_GET_LOOKAHEAD_REQUEST, OPUS_OK,
+};
+use vorbis_rs::{VorbisBitrateManagementStrategy, VorbisEncoder, VorbisEncoderBuilder};
+use xxhash_rust::xxh3::Xxh3;
+
+create_exception!(waveform, WaveformError, PyException);
+create_exception!(waveform, DecodeError, WaveformError);
+create_exception!(waveform, UnsupportedFormatError, DecodeError);
+create_exception!(waveform, EncodeError, WaveformError);
+create_exception!(waveform, InvalidParameterError, WaveformError);
+create_exception!(waveform, LimitExceededError, WaveformError);
+#[cfg(feature = "reqwest")]
+create_exception!(waveform, NetworkError, WaveformError);
+
+/// Errors raised by the decode/encode stages. They must be `Send` so that the stages can run
+/// with the GIL released.
+type BoxError = Box<dyn std::error::Error + Send + Sync>;
 
 #[pyclass]
 struct AudioResult {
     ogg_data: Vec<u8>,
+    #[pyo3(get)]
     waveform_base64: String,
+    /// The points packed into `waveform_base64`, so callers don't have to unpack them.
+    #[pyo3(get)]
+    waveform: Vec<f32>,
+    /// Number of values packed into `waveform_base64`: one per `f32` or byte.
+    #[pyo3(get)]
+    waveform_len: usize,
+    /// Smallest value in `waveform`, in `0..=255` for the `u8` format; 0 for an empty waveform.
+    #[pyo3(get)]
+    waveform_min: f32,
+    /// Largest value in `waveform`, in `0..=255` for the `u8` format; 0 for an empty waveform.
+    #[pyo3(get)]
+    waveform_max: f32,
+    #[pyo3(get)]
     duration_seconds: f64,
+    /// Length of the decoded range according to the container, before any DSP; `None` when it
+    /// doesn't say. Much longer than `duration_seconds` usually means a truncated input.
+    #[pyo3(get)]
+    reported_duration_seconds: Option<f64>,
+    /// Why decoding stopped before the end of the input, when it did; everything up to that
+    /// point is still processed into the result.
+    #[pyo3(get)]
+    truncation_error: Option<String>,
+    #[pyo3(get)]
+    sample_rate: u32,
+    #[pyo3(get)]
+    channels: usize,
+    /// One waveform per channel, only computed when `per_channel` is requested.
+    #[pyo3(get)]
+    channel_waveforms: Option<Vec<String>>,
+    /// Start time in seconds of the chunk behind each value of `waveform`, only computed when
+    /// `timestamps` is requested.
+    #[pyo3(get)]
+    timestamps: Option<Vec<f64>>,
+    /// The main waveform packed in each format of `waveform_formats`, keyed by format name.
+    #[pyo3(get)]
+    waveforms: Option<HashMap<String, String>>,
+    /// Base64 waveform of `preview_points` points, computed like the main one.
+    #[pyo3(get)]
+    preview_waveform_base64: Option<String>,
+    /// Integrated loudness of the input in LUFS, only measured when `target_lufs` is requested.
+    #[pyo3(get)]
+    input_lufs: Option<f64>,
+    /// Largest absolute sample value of the processed audio.
+    #[pyo3(get)]
+    peak: f32,
+    /// Number of samples whose magnitude reaches [`CLIP_THRESHOLD`].
+    #[pyo3(get)]
+    clip_count: usize,
+    /// Pearson correlation between the left and right channels of the processed audio: near 1.0
+    /// is effectively mono, near -1.0 is out of phase. `None` unless the output is stereo, or
+    /// when a channel is silent.
+    #[pyo3(get)]
+    stereo_correlation: Option<f64>,
+    /// Milliseconds of silence removed from the start when `trim_silence` is set.
+    #[pyo3(get)]
+    trimmed_start_ms: f64,
+    /// Milliseconds of silence removed from the end when `trim_silence` is set.
+    #[pyo3(get)]
+    trimmed_end_ms: f64,
+    /// Number of corrupt packets skipped while decoding; always 0 in `strict` mode.
+    #[pyo3(get)]
+    dropped_packets: usize,
+    /// Short name of the source codec, e.g. `"mp3"`, `"flac"` or `"pcm_s16le"`.
+    #[pyo3(get)]
+    codec: String,
+    /// Bit depth of the source, or `None` for lossy codecs that don't have one.
+    #[pyo3(get)]
+    bits_per_sample: Option<u32>,
+    /// Hex xxh3-128 digest of the processed samples with their channel count and sample rate,
+    /// only computed with `pcm_hash`. Inputs that decode to the same audio share it whatever
+    /// their container or codec.
+    #[pyo3(get)]
+    pcm_hash: Option<String>,
+    /// Milliseconds spent decoding and processing, only set with `collect_timings`.
+    #[pyo3(get)]
+    decode_ms: Option<f64>,
+    /// Milliseconds spent encoding, only set with `collect_timings`.
+    #[pyo3(get)]
+    encode_ms: Option<f64>,
+    /// Milliseconds spent computing waveforms, only set with `collect_timings`.
+    #[pyo3(get)]
+    waveform_ms: Option<f64>,
 }
 
-fn decode_to_pcm(input: &[u8]) -> Result<(Vec<f32>, usize, u32), Box<dyn std::error::Error>> {
-    let hint = Hint::new();
-    let cursor = Cursor::new(input.to_vec());
-    let boxed_cursor: Box<dyn symphonia::core::io::MediaSource> = Box::new(cursor);
-    let mss = MediaSourceStream::new(boxed_cursor, Default::default());
+#[pymethods]
+impl AudioResult {
+    #[getter]
+    fn ogg_data<'py>(&self, py: Python<'py>) -> Bound<'py, PyBytes> {
+        PyBytes::new(py, &self.ogg_data)
+    }
 
-    let probed = default::get_probe().format(
-        &hint,
-        mss,
-        &FormatOptions::default(),
-        &MetadataOptions::default(),
-    )?;
-    let mut format = probed.format;
+    /// Whether decoding stopped before the end of the input, see `truncation_error`.
+    #[getter]
+    fn truncated(&self) -> bool {
+        self.truncation_error.is_some()
+    }
+}
 
-    let track = format
-        .tracks()
-        .iter()
-        .find(|t| t.codec_params.codec != symphonia::core::codecs::CODEC_TYPE_NULL)
-        .ok_or("No supported audio tracks")?;
-    let mut decoder = default::get_codecs().make(&track.codec_params, &Default::default())?;
-
-    let sample_rate = track
-        .codec_params
-        .sample_rate
-        .ok_or("Unknown sample rate")?;
-    let channels = track
-        .codec_params
-        .channels
-        .ok_or("Unknown channels")?
-        .count();
-
-    let mut pcm = Vec::new();
-    let track_id = track.id;
+/// How the waveform points are packed into bytes before base64 encoding.
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+enum WaveformFormat {
+    /// Native `f32` peaks, 4 bytes per point.
+    #[default]
+    F32,
+    /// One byte per point, matching Discord's voice message waveform. Each peak is clamped to
+    /// `0.0..=1.0` and mapped linearly onto `0..=255` with `round(peak * 255)`.
+    U8,
+}
 
-    loop {
-        let packet = match format.next_packet() {
-            Ok(pkt) => pkt,
-            Err(err) => {
-                use symphonia::core::errors::Error;
-                match err {
-                    Error::ResetRequired => break,
-                    Error::IoError(_) | Error::DecodeError(_) => continue,
-                    _ => break,
-                }
-            }
+impl WaveformFormat {
+    fn name(self) -> &'static str {
+        match self {
+            WaveformFormat::F32 => "f32",
+            WaveformFormat::U8 => "u8",
+        }
+    }
+}
+
+impl FromStr for WaveformFormat {
+    type Err = ProcessError;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "f32" => Ok(WaveformFormat::F32),
+            "u8" => Ok(WaveformFormat::U8),
+            _ => Err(ProcessError::InvalidParameter(format!(
+                "unknown waveform_format {s:?}, expected \"f32\" or \"u8\""
+            ))),
+        }
+    }
+}
+
+/// How each chunk of samples is reduced to waveform points.
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+enum WaveformMode {
+    /// Maximum absolute sample value.
+    #[default]
+    Peak,
+    /// Root-mean-square of the samples, closer to perceived loudness.
+    Rms,
+    /// Two points per chunk, the signed minimum followed by the signed maximum sample, so the
+    /// waveform is laid out as `min0, max0, min1, max1, ...`. Only the `f32` format can
+    /// represent the negative values.
+    MinMax,
+    /// Spectral magnitude of each Hann-windowed chunk, optionally limited to `spectral_band`.
+    /// Heavier than the other modes, as every chunk goes through an FFT; see [`spectral`] for
+    /// how the values are normalized.
+    Spectral,
+}
+
+impl FromStr for WaveformMode {
+    type Err = ProcessError;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "peak" => Ok(WaveformMode::Peak),
+            "rms" => Ok(WaveformMode::Rms),
+            "minmax" => Ok(WaveformMode::MinMax),
+            "spectral" => Ok(WaveformMode::Spectral),
+            _ => Err(ProcessError::InvalidParameter(format!(
+                "unknown mode {s:?}, expected \"peak\", \"rms\", \"minmax\" or \"spectral\""
+            ))),
+        }
+    }
+}
+
+/// How waveform values are mapped before packing.
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+enum WaveformScale {
+    /// Raw amplitudes.
+    #[default]
+    Linear,
+    /// Amplitudes converted to dBFS and mapped linearly from `db_floor..=0` onto `0.0..=1.0`,
+    /// the way audio editors draw waveforms. The sign is kept so `minmax` stays symmetric.
+    Db,
+}
+
+impl FromStr for WaveformScale {
+    type Err = ProcessError;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "linear" => Ok(WaveformScale::Linear),
+            "db" => Ok(WaveformScale::Db),
+            _ => Err(ProcessError::InvalidParameter(format!(
+                "unknown scale {s:?}, expected \"linear\" or \"db\""
+            ))),
+        }
+    }
+}
+
+/// Which signal of a stereo source the main waveform is computed from.
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+enum StereoMode {
+    /// The left and right channels as decoded.
+    #[default]
+    LeftRight,
+    /// `(L + R) / 2`, the content common to both channels.
+    Mid,
+    /// `(L - R) / 2`, which shows how wide the stereo image is.
+    Side,
+}
+
+impl FromStr for StereoMode {
+    type Err = ProcessError;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "lr" => Ok(StereoMode::LeftRight),
+            "mid" => Ok(StereoMode::Mid),
+            "side" => Ok(StereoMode::Side),
+            _ => Err(ProcessError::InvalidParameter(format!(
+                "unknown stereo_mode {s:?}, expected \"lr\", \"mid\" or \"side\""
+            ))),
+        }
+    }
+}
+
+/// Frequency weighting applied to the analyzed samples before they are reduced to points.
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+enum Weighting {
+    /// The samples as they are.
+    #[default]
+    None,
+    /// The IEC 61672 A-weighting curve, which follows the ear's sensitivity at moderate levels,
+    /// so `rms` points track perceived loudness more closely than flat RMS.
+    A,
+}
+
+impl FromStr for Weighting {
+    type Err = ProcessError;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "none" => Ok(Weighting::None),
+            "a" => Ok(Weighting::A),
+            _ => Err(ProcessError::InvalidParameter(format!(
+                "unknown weighting {s:?}, expected \"none\" or \"a\""
+            ))),
+        }
+    }
+}
+
+#[derive(Clone)]
+struct WaveformOptions {
+    /// Samples per waveform point. For interleaved multichannel audio this is snapped down to
+    /// whole frames, see [`WaveformOptions::aligned`].
+    chunk_size: usize,
+    /// When set, the waveform always has exactly this many points regardless of `chunk_size`.
+    num_points: Option<usize>,
+    format: WaveformFormat,
+    /// Extra formats to pack the main waveform in, sharing one computation of its points.
+    formats: Option<Vec<WaveformFormat>>,
+    mode: WaveformMode,
+    /// Also compute a separate waveform for each deinterleaved channel.
+    per_channel: bool,
+    scale: WaveformScale,
+    /// The dBFS level mapped to `0.0` in the `db` scale.
+    db_floor: f32,
+    /// Fraction in `0.0..=0.9` by which consecutive `chunk_size` windows overlap.
+    overlap: f32,
+    /// Peak-hold falloff in `0.0..=1.0`: each point is at least the previous one times this,
+    /// for the decaying look of a VU meter. `0.0` leaves the points as they are.
+    decay: f32,
+    /// Compute the main waveform from this channel only instead of all of them.
+    channel: Option<usize>,
+    /// Compute the main waveform from the mid or side signal; only valid for stereo audio.
+    stereo_mode: StereoMode,
+    /// Pad the finished waveform with zeros or truncate it to exactly this many values.
+    pad_to: Option<usize>,
+    /// `(low, high)` frequencies the `spectral` mode measures, as fractions of the Nyquist
+    /// frequency in `0.0..=1.0`; the whole spectrum when unset.
+    spectral_band: Option<(f32, f32)>,
+    weighting: Weighting,
+    /// Also return the start time in seconds of the chunk behind each waveform value.
+    timestamps: bool,
+    /// Base64 encode waveforms with the URL-safe alphabet and no padding, so they can go in a
+    /// query string as they are.
+    url_safe: bool,
+    /// Also compute a low-resolution preview with exactly this many points from the same
+    /// samples.
+    preview_points: Option<usize>,
+}
+
+impl Default for WaveformOptions {
+    fn default() -> Self {
+        WaveformOptions {
+            chunk_size: 1024,
+            num_points: None,
+            format: WaveformFormat::default(),
+            formats: None,
+            mode: WaveformMode::default(),
+            per_channel: false,
+            scale: WaveformScale::default(),
+            db_floor: -60.0,
+            overlap: 0.0,
+            decay: 0.0,
+            channel: None,
+            stereo_mode: StereoMode::default(),
+            pad_to: None,
+            spectral_band: None,
+            weighting: Weighting::default(),
+            timestamps: false,
+            url_safe: false,
+            preview_points: None,
+        }
+    }
+}
+
+impl WaveformOptions {
+    /// Distance in samples between the starts of consecutive windows:
+    /// `max(1, round(chunk_size * (1 - overlap)))`. A waveform of `n` samples then has
+    /// `ceil(n / step)` points, matching plain chunking when there is no overlap.
+    fn step_size(&self) -> usize {
+        ((self.chunk_size as f32 * (1.0 - self.overlap)).round() as usize).max(1)
+    }
+
+    /// The start time in seconds of the chunk behind each of `len` waveform values computed
+    /// from `samples`, which hold `samples_per_frame` values per frame. Both values of a
+    /// `minmax` pair share their chunk's time, and padding continues the chunk spacing.
+    fn point_times(
+        &self,
+        len: usize,
+        samples: usize,
+        samples_per_frame: usize,
+        sample_rate: u32,
+    ) -> Vec<f64> {
+        let values_per_chunk = if self.mode == WaveformMode::MinMax {
+            2
+        } else {
+            1
         };
+        let samples_per_second = f64::from(sample_rate) * samples_per_frame.max(1) as f64;
+        (0..len)
+            .map(|index| {
+                let chunk = index / values_per_chunk;
+                let start = match self.num_points {
+                    Some(num_points) => chunk * samples / num_points,
+                    None => chunk * self.step_size(),
+                };
+                start as f64 / samples_per_second
+            })
+            .collect()
+    }
 
-        if packet.track_id() != track_id {
-            continue;
+    /// [`WaveformOptions::point_times`] for a waveform computed from `samples` analyzed
+    /// samples of `channels`-channel audio, or `None` unless `timestamps` is set.
+    fn timestamps_for(
+        &self,
+        len: usize,
+        samples: usize,
+        channels: usize,
+        sample_rate: u32,
+    ) -> Option<Vec<f64>> {
+        let samples_per_frame = self.samples_per_frame(channels);
+        self.timestamps
+            .then(|| self.point_times(len, samples, samples_per_frame, sample_rate))
+    }
+
+    /// How many values per frame [`WaveformOptions::analyzed_samples`] returns for
+    /// `channels`-channel audio: all of them, unless a single signal is picked or derived.
+    fn samples_per_frame(&self, channels: usize) -> usize {
+        if self.stereo_mode != StereoMode::LeftRight
+            || self.channel.is_some()
+            || self.mode == WaveformMode::Spectral
+        {
+            1
+        } else {
+            channels.max(1)
+        }
+    }
+
+    /// `self` with `chunk_size` and the overlap step rounded down to whole frames of the
+    /// analyzed samples, but never below one frame. Otherwise windows over interleaved audio
+    /// would start and end mid-frame, so neighbouring points would each see part of a frame.
+    fn aligned(&self, channels: usize) -> Cow<'_, WaveformOptions> {
+        let frame = self.samples_per_frame(channels);
+        let snap = |samples: usize| (samples - samples % frame).max(frame);
+        if self.chunk_size.is_multiple_of(frame) && self.step_size().is_multiple_of(frame) {
+            return Cow::Borrowed(self);
+        }
+        let chunk_size = snap(self.chunk_size);
+        let step = snap(self.step_size());
+        Cow::Owned(WaveformOptions {
+            chunk_size,
+            overlap: if self.overlap > 0.0 {
+                1.0 - step as f32 / chunk_size as f32
+            } else {
+                0.0
+            },
+            ..self.clone()
+        })
+    }
+
+    /// The options of the `preview_points` waveform: these ones with a fixed number of points
+    /// and no overlap or padding.
+    fn preview(&self) -> Option<WaveformOptions> {
+        self.preview_points.map(|points| WaveformOptions {
+            num_points: Some(points),
+            overlap: 0.0,
+            pad_to: None,
+            ..self.clone()
+        })
+    }
+
+    /// Applies the `decay` peak hold to points from [`compute_points`]: each point becomes
+    /// `max(point, previous * decay)`. The `minmax` minimums are held separately with `min`, so
+    /// both edges of the envelope fall back towards zero.
+    fn hold_peaks(&self, mut points: Vec<f32>) -> Vec<f32> {
+        if self.decay > 0.0 {
+            let minmax = self.mode == WaveformMode::MinMax;
+            let stride = if minmax { 2 } else { 1 };
+            for i in stride..points.len() {
+                let held = points[i - stride] * self.decay;
+                points[i] = if minmax && i % 2 == 0 {
+                    points[i].min(held)
+                } else {
+                    points[i].max(held)
+                };
+            }
+        }
+        points
+    }
+
+    fn fit_to_length(&self, mut values: Vec<f32>) -> Vec<f32> {
+        if let Some(len) = self.pad_to {
+            values.resize(len, 0.0);
+        }
+        values
+    }
+
+    /// The samples the main waveform is computed from: `pcm` itself, the selected `channel` or
+    /// the mid/side signal, with `weighting` applied. Both can only be checked against the
+    /// decoded audio, hence the error here. The `spectral` mode needs a single signal, so it
+    /// analyzes a mono downmix of multichannel audio unless one is picked.
+    fn analyzed_samples<'a>(
+        &self,
+        pcm: &'a [f32],
+        channels: usize,
+        sample_rate: u32,
+    ) -> Result<Cow<'a, [f32]>, ProcessError> {
+        // Everything but `pcm` itself is a single derived signal.
+        Ok(match self.selected_signal(pcm, channels)? {
+            Cow::Borrowed(pcm) => self.weighted(pcm, channels, sample_rate),
+            Cow::Owned(signal) => match self.weighted(&signal, 1, sample_rate) {
+                Cow::Borrowed(_) => Cow::Owned(signal),
+                Cow::Owned(weighted) => Cow::Owned(weighted),
+            },
+        })
+    }
+
+    /// `samples` with `weighting` applied, filtering each interleaved channel separately.
+    fn weighted<'a>(
+        &self,
+        samples: &'a [f32],
+        channels: usize,
+        sample_rate: u32,
+    ) -> Cow<'a, [f32]> {
+        match self.weighting {
+            Weighting::None => Cow::Borrowed(samples),
+            Weighting::A => Cow::Owned(a_weight(samples, channels, sample_rate)),
+        }
+    }
+
+    fn selected_signal<'a>(
+        &self,
+        pcm: &'a [f32],
+        channels: usize,
+    ) -> Result<Cow<'a, [f32]>, ProcessError> {
+        let (name, sign) = match self.stereo_mode {
+            StereoMode::LeftRight => ("lr", 0.0),
+            StereoMode::Mid => ("mid", 1.0),
+            StereoMode::Side => ("side", -1.0),
+        };
+        if self.stereo_mode != StereoMode::LeftRight {
+            if channels != 2 {
+                return Err(ProcessError::InvalidParameter(format!(
+                    "stereo_mode {name:?} requires stereo audio, got {channels} channels"
+                )));
+            }
+            return Ok(Cow::Owned(
+                pcm.chunks_exact(2)
+                    .map(|frame| (frame[0] + sign * frame[1]) / 2.0)
+                    .collect(),
+            ));
         }
+        match self.channel {
+            None if self.mode == WaveformMode::Spectral && channels > 1 => {
+                Ok(Cow::Owned(downmix_to_mono(pcm, channels)))
+            }
+            None => Ok(Cow::Borrowed(pcm)),
+            Some(channel) if channel < channels => Ok(Cow::Owned(
+                pcm.iter()
+                    .skip(channel)
+                    .step_by(channels)
+                    .copied()
+                    .collect(),
+            )),
+            Some(channel) => Err(ProcessError::InvalidParameter(format!(
+                "channel {channel} is out of range for audio with {channels} channels"
+            ))),
+        }
+    }
 
-        match decoder.decode(&packet) {
-            Ok(audio_buf) => {
-                let mut sample_buf =
-                    SampleBuffer::<f32>::new(audio_buf.capacity() as u64, *audio_buf.spec());
-                sample_buf.copy_interleaved_ref(audio_buf);
-                pcm.extend_from_slice(sample_buf.samples());
+    fn validate(&self) -> Result<(), ProcessError> {
+        if self.chunk_size == 0 {
+            return Err(ProcessError::InvalidParameter(
+                "chunk_size must be greater than zero".to_string(),
+            ));
+        }
+        if self.pad_to == Some(0) {
+            return Err(ProcessError::InvalidParameter(
+                "pad_to must be greater than zero".to_string(),
+            ));
+        }
+        if self.num_points == Some(0) {
+            return Err(ProcessError::InvalidParameter(
+                "num_points must be greater than zero".to_string(),
+            ));
+        }
+        if self.preview_points == Some(0) {
+            return Err(ProcessError::InvalidParameter(
+                "preview_points must be greater than zero".to_string(),
+            ));
+        }
+        if self
+            .formats
+            .as_ref()
+            .is_some_and(|formats| formats.is_empty())
+        {
+            return Err(ProcessError::InvalidParameter(
+                "waveform_formats must not be empty".to_string(),
+            ));
+        }
+        let any_u8 = self.format == WaveformFormat::U8
+            || self
+                .formats
+                .iter()
+                .flatten()
+                .any(|&format| format == WaveformFormat::U8);
+        if self.mode == WaveformMode::MinMax && any_u8 {
+            return Err(ProcessError::InvalidParameter(
+                "minmax mode requires the f32 waveform format".to_string(),
+            ));
+        }
+        if let Some((low, high)) = self.spectral_band {
+            if self.mode != WaveformMode::Spectral {
+                return Err(ProcessError::InvalidParameter(
+                    "spectral_band only applies to the spectral mode".to_string(),
+                ));
+            }
+            if !(0.0 <= low && low < high && high <= 1.0) {
+                return Err(ProcessError::InvalidParameter(format!(
+                    "spectral_band must satisfy 0.0 <= low < high <= 1.0, got ({low}, {high})"
+                )));
             }
-            Err(_) => continue,
         }
+        if !(0.0..=0.9).contains(&self.overlap) {
+            return Err(ProcessError::InvalidParameter(format!(
+                "overlap must be between 0.0 and 0.9, got {}",
+                self.overlap
+            )));
+        }
+        if !(0.0..=1.0).contains(&self.decay) {
+            return Err(ProcessError::InvalidParameter(format!(
+                "decay must be between 0.0 and 1.0, got {}",
+                self.decay
+            )));
+        }
+        if self.channel.is_some() && self.stereo_mode != StereoMode::LeftRight {
+            return Err(ProcessError::InvalidParameter(
+                "channel and stereo_mode cannot be combined".to_string(),
+            ));
+        }
+        if self.overlap > 0.0 && self.num_points.is_some() {
+            return Err(ProcessError::InvalidParameter(
+                "overlap cannot be combined with num_points".to_string(),
+            ));
+        }
+        if !self.db_floor.is_finite() || self.db_floor >= 0.0 {
+            return Err(ProcessError::InvalidParameter(format!(
+                "db_floor must be a finite negative value, got {}",
+                self.db_floor
+            )));
+        }
+        Ok(())
     }
+}
 
-    Ok((pcm, channels, sample_rate))
+/// The codec used for the OGG output.
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+enum OutputCodec {
+    #[default]
+    Vorbis,
+    Opus,
 }
 
-fn encode_to_ogg(pcm: &[f32], channels: usize, sample_rate: u32) -> Result<Vec<u8>, VorbisError> {
-    let mut output = Vec::new();
+impl FromStr for OutputCodec {
+    type Err = ProcessError;
 
-    let rate_nz = NonZeroU32::new(sample_rate).expect("blah.");
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "vorbis" => Ok(OutputCodec::Vorbis),
+            "opus" => Ok(OutputCodec::Opus),
+            _ => Err(ProcessError::InvalidParameter(format!(
+                "unknown output_codec {s:?}, expected \"vorbis\" or \"opus\""
+            ))),
+        }
+    }
+}
 
-    let ch_nz = NonZeroU8::new(channels as u8).expect("blah.");
+/// The container/format of the encoded output.
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+enum OutputFormat {
+    /// OGG using the configured [`OutputCodec`].
+    #[default]
+    Ogg,
+    /// Uncompressed RIFF/WAVE using the configured [`SampleFormat`].
+    Wav,
+    /// MPEG-1/2 Layer III at a constant `bitrate_kbps`.
+    Mp3,
+    /// Lossless FLAC with 16-bit samples at the given `compression_level`.
+    Flac,
+    /// G.711 mu-law telephony audio: 8-bit companded samples at 8 kHz in a WAV container.
+    MuLaw,
+    /// G.711 A-law telephony audio: 8-bit companded samples at 8 kHz in a WAV container.
+    ALaw,
+}
 
-    let mut builder = VorbisEncoderBuilder::new(rate_nz, ch_nz, &mut output)?;
-    let mut encoder = builder.build()?;
+impl FromStr for OutputFormat {
+    type Err = ProcessError;
 
-    let frame_count = pcm.len() / channels;
-    let mut planar = vec![Vec::with_capacity(frame_count); channels];
-    for (i, &sample) in pcm.iter().enumerate() {
-        planar[i % channels].push(sample);
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "ogg" => Ok(OutputFormat::Ogg),
+            "wav" => Ok(OutputFormat::Wav),
+            "mp3" => Ok(OutputFormat::Mp3),
+            "flac" => Ok(OutputFormat::Flac),
+            "mulaw" => Ok(OutputFormat::MuLaw),
+            "alaw" => Ok(OutputFormat::ALaw),
+            _ => Err(ProcessError::InvalidParameter(format!(
+                "unknown output_format {s:?}, expected \"ogg\", \"wav\", \"mp3\", \"flac\", \
+                 \"mulaw\" or \"alaw\""
+            ))),
+        }
     }
-    let planar_refs: Vec<&[f32]> = planar.iter().map(Vec::as_slice).collect();
+}
 
-    encoder.encode_audio_block(&planar_refs)?;
-    encoder.finish()?;
+/// The sample encoding used for uncompressed output. The companded formats are only written
+/// by the `mulaw` and `alaw` outputs, not picked through `sample_format`.
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+enum SampleFormat {
+    #[default]
+    F32,
+    I16,
+    I24,
+    MuLaw,
+    ALaw,
+}
 
-    Ok(output)
+impl SampleFormat {
+    fn bits_per_sample(self) -> u16 {
+        match self {
+            SampleFormat::F32 => 32,
+            SampleFormat::I16 => 16,
+            SampleFormat::I24 => 24,
+            SampleFormat::MuLaw | SampleFormat::ALaw => 8,
+        }
+    }
 }
-fn compute_waveform_base64(pcm: &[f32], chunk_size: usize) -> String {
-    let waveform: Vec<f32> = pcm
-        .chunks(chunk_size)
-        .map(|chunk| chunk.iter().map(|v| v.abs()).fold(0.0f32, f32::max))
-        .collect();
 
-    let bytes: &[u8] = bytemuck::cast_slice(&waveform);
+impl FromStr for SampleFormat {
+    type Err = ProcessError;
 
-    general_purpose::STANDARD.encode(bytes)
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "f32" => Ok(SampleFormat::F32),
+            "i16" => Ok(SampleFormat::I16),
+            "i24" => Ok(SampleFormat::I24),
+            _ => Err(ProcessError::InvalidParameter(format!(
+                "unknown sample_format {s:?}, expected \"f32\", \"i16\" or \"i24\""
+            ))),
+        }
+    }
 }
 
-fn process_audio(input_data: &[u8]) -> AudioResult {
-    let (pcm, channels, sample_rate) =
-        decode_to_pcm(input_data).expect("Unable to process data as PCM.");
-    let ogg_data = encode_to_ogg(&pcm, channels, sample_rate)
-        .expect("Unable to encode the audio data to OGG format.");
-    let waveform_base64 = compute_waveform_base64(&pcm, 1024);
+/// How the Vorbis encoder manages its bitrate.
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+enum BitrateMode {
+    /// Variable bitrate, steered by `quality` or a rough `bitrate_bps` target.
+    #[default]
+    Vbr,
+    /// Average bitrate: short swings are allowed but the mean stays at `bitrate_bps`.
+    Abr,
+    /// Constrained bitrate: never above `bitrate_bps`, Vorbis' closest mode to CBR.
+    Cbr,
+}
 
-    let total_samples = pcm.len();
-    let duration_seconds = total_samples as f64 / (channels as f64 * sample_rate as f64);
+impl FromStr for BitrateMode {
+    type Err = ProcessError;
 
-    AudioResult {
-        ogg_data,
-        waveform_base64,
-        duration_seconds,
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "vbr" => Ok(BitrateMode::Vbr),
+            "abr" => Ok(BitrateMode::Abr),
+            "cbr" => Ok(BitrateMode::Cbr),
+            _ => Err(ProcessError::InvalidParameter(format!(
+                "unknown bitrate_mode {s:?}, expected \"vbr\", \"abr\" or \"cbr\""
+            ))),
+        }
     }
 }
 
-#[pyfunction]
-#[pyo3(name = "generate")]
-fn generate_waveform_from_audio(audio: &[u8]) -> AudioResult {
-    process_audio(audio)
+/// What the Opus encoder tunes for.
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+enum OpusApplication {
+    /// General music and mixed content.
+    #[default]
+    Audio,
+    /// Speech, favouring intelligibility at low bitrates.
+    Voip,
+    /// The lowest delay, with the speech-oriented modes disabled.
+    RestrictedLowdelay,
 }
 
-#[pymodule]
-pub fn waveform(m: &Bound<'_, PyModule>) -> PyResult<()> {
-    m.add_function(wrap_pyfunction!(generate_waveform_from_audio, m)?)?;
+impl OpusApplication {
+    fn to_libopus(self) -> i32 {
+        match self {
+            OpusApplication::Audio => OPUS_APPLICATION_AUDIO,
+            OpusApplication::Voip => OPUS_APPLICATION_VOIP,
+            OpusApplication::RestrictedLowdelay => OPUS_APPLICATION_RESTRICTED_LOWDELAY,
+        }
+    }
+}
 
-    Ok(())
+impl FromStr for OpusApplication {
+    type Err = ProcessError;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "audio" => Ok(OpusApplication::Audio),
+            "voip" => Ok(OpusApplication::Voip),
+            "restricted_lowdelay" => Ok(OpusApplication::RestrictedLowdelay),
+            _ => Err(ProcessError::InvalidParameter(format!(
+                "unknown opus_application {s:?}, expected \"audio\", \"voip\" or \"restricted_lowdelay\""
+            ))),
+        }
+    }
 }
 
-#[cfg(test)]
-mod tests {
-    use std::io::Read as _;
+#[derive(Default)]
+struct EncodeOptions {
+    format: OutputFormat,
+    codec: OutputCodec,
+    sample_format: SampleFormat,
+    /// Vorbis VBR quality in `-0.2..=1.0`, or the library default when unset.
+    quality: Option<f32>,
+    bitrate_mode: BitrateMode,
+    /// Vorbis target bitrate in bits per second; required by the `abr` and `cbr` modes.
+    bitrate_bps: Option<u32>,
+    opus_application: OpusApplication,
+    /// MP3 bitrate in kbps, or [`DEFAULT_MP3_BITRATE_KBPS`] when unset.
+    bitrate_kbps: Option<u32>,
+    /// FLAC compression level in `0..=8`, or [`DEFAULT_FLAC_COMPRESSION_LEVEL`] when unset.
+    compression_level: Option<u8>,
+    /// Add TPDF dither when converting to integer samples, instead of plain rounding.
+    dither: bool,
+    /// Seed of the dither noise, so dithered output can be reproduced; a fixed seed is used when
+    /// unset.
+    dither_seed: Option<u32>,
+    /// Expected size of the encoded output in bytes, reserved up front instead of the estimate
+    /// from the duration. Only the Ogg outputs use it; the other encoders know their exact size.
+    expected_size_hint: Option<usize>,
+    /// Return an Ogg Vorbis input unchanged instead of re-encoding it, as long as it decodes
+    /// cleanly and nothing would alter its audio. Only the `generate` family takes it.
+    passthrough_if_ogg: bool,
+    /// Serial number of the Vorbis Ogg stream. It is random when unset, which is the only thing
+    /// keeping repeated encodes of the same audio from matching byte for byte.
+    ogg_serial: Option<i32>,
+}
 
-    use super::*;
+/// Bitrate per channel assumed when sizing the Ogg output buffer, enough for Vorbis around its
+/// default quality and for Opus' default bitrate.
+const ESTIMATED_OGG_BITS_PER_SECOND_PER_CHANNEL: u64 = 64_000;
 
-    #[test]
-    fn test_lib() {
-        let mut file = std::fs::File::open("test.mp3").expect("Unable to open test mp3.");
+/// Upper bound on what is reserved from duration estimates alone; container headers can claim
+/// any length, and larger outputs still grow as usual.
+const MAX_PREALLOCATION: usize = 1 << 25;
 
-        let mut buf = Vec::new();
-        file.read_to_end(&mut buf)
-            .expect("Unable to read audio file into buffer");
+impl EncodeOptions {
+    /// Sets the encoder option `key` from a keyword argument, returning whether `key` is one.
+    /// Shared by [`ProcessOptions::from_kwargs`] and `StreamingEncoder`, which takes only these.
+    fn set_kwarg(&mut self, key: &str, value: &Bound<'_, PyAny>) -> PyResult<bool> {
+        match key {
+            "quality" => self.quality = value.extract()?,
+            "bitrate_kbps" => self.bitrate_kbps = value.extract()?,
+            "compression_level" => self.compression_level = value.extract()?,
+            "dither" => self.dither = value.extract()?,
+            "dither_seed" => self.dither_seed = value.extract()?,
+            "ogg_serial" => self.ogg_serial = value.extract()?,
+            "expected_size_hint" => self.expected_size_hint = value.extract()?,
+            "passthrough_if_ogg" => self.passthrough_if_ogg = value.extract()?,
+            "bitrate_mode" => self.bitrate_mode = value.extract::<String>()?.parse()?,
+            "bitrate_bps" => self.bitrate_bps = value.extract()?,
+            "output_codec" => self.codec = value.extract::<String>()?.parse()?,
+            "opus_application" => self.opus_application = value.extract::<String>()?.parse()?,
+            "output_format" => self.format = value.extract::<String>()?.parse()?,
+            "sample_format" => self.sample_format = value.extract::<String>()?.parse()?,
+            _ => return Ok(false),
+        }
+        Ok(true)
+    }
 
-        let result = generate_waveform_from_audio(&buf);
-        println!(
-            "{:#?}\n\n{:#?}",
-            result.duration_seconds, result.waveform_base64
-        )
+    /// Bytes to reserve for Ogg output of `frames` frames: `expected_size_hint` when given,
+    /// otherwise an estimate from the duration and `bitrate_bps` or a typical bitrate.
+    fn output_capacity(&self, frames: usize, channels: usize, sample_rate: u32) -> usize {
+        if let Some(hint) = self.expected_size_hint {
+            return hint;
+        }
+        let bits_per_second = self.bitrate_bps.map_or(
+            ESTIMATED_OGG_BITS_PER_SECOND_PER_CHANNEL * channels as u64,
+            u64::from,
+        );
+        let bytes =
+            (frames as u64).saturating_mul(bits_per_second) / 8 / u64::from(sample_rate.max(1));
+        (bytes as usize).min(MAX_PREALLOCATION)
+    }
+
+    /// The dither generator for integer conversion, or `None` when `dither` is off.
+    fn make_dither(&self) -> Option<Dither> {
+        self.dither
+            .then(|| self.dither_seed.map_or_else(Dither::default, Dither::new))
+    }
+
+    fn validate(&self) -> Result<(), ProcessError> {
+        if self.format != OutputFormat::Ogg && self.codec != OutputCodec::Vorbis {
+            return Err(ProcessError::InvalidParameter(
+                "output_codec only applies to ogg output".to_string(),
+            ));
+        }
+        if self.format != OutputFormat::Wav && self.sample_format != SampleFormat::F32 {
+            return Err(ProcessError::InvalidParameter(
+                "sample_format only applies to wav output".to_string(),
+            ));
+        }
+        if self.opus_application != OpusApplication::Audio
+            && (self.format, self.codec) != (OutputFormat::Ogg, OutputCodec::Opus)
+        {
+            return Err(ProcessError::InvalidParameter(
+                "opus_application only applies to the opus codec".to_string(),
+            ));
+        }
+        if self.passthrough_if_ogg
+            && (self.format != OutputFormat::Ogg || self.codec != OutputCodec::Vorbis)
+        {
+            return Err(ProcessError::InvalidParameter(
+                "passthrough_if_ogg requires ogg output with the vorbis codec".to_string(),
+            ));
+        }
+        if self.ogg_serial.is_some()
+            && (self.format != OutputFormat::Ogg || self.codec != OutputCodec::Vorbis)
+        {
+            return Err(ProcessError::InvalidParameter(
+                "ogg_serial is only supported by the vorbis codec".to_string(),
+            ));
+        }
+        if self.quality.is_some()
+            && (self.format != OutputFormat::Ogg || self.codec != OutputCodec::Vorbis)
+        {
+            return Err(ProcessError::InvalidParameter(
+                "quality is only supported by the vorbis codec".to_string(),
+            ));
+        }
+        if let Some(quality) = self.quality {
+            if !(-0.2..=1.0).contains(&quality) {
+                return Err(ProcessError::InvalidParameter(format!(
+                    "quality must be between -0.2 and 1.0, got {quality}"
+                )));
+            }
+        }
+        if (self.bitrate_mode != BitrateMode::Vbr || self.bitrate_bps.is_some())
+            && (self.format != OutputFormat::Ogg || self.codec != OutputCodec::Vorbis)
+        {
+            return Err(ProcessError::InvalidParameter(
+                "bitrate_mode and bitrate_bps are only supported by the vorbis codec".to_string(),
+            ));
+        }
+        match (self.bitrate_mode, self.quality, self.bitrate_bps) {
+            (_, _, Some(0)) => {
+                return Err(ProcessError::InvalidParameter(
+                    "bitrate_bps must be greater than zero".to_string(),
+                ))
+            }
+            (BitrateMode::Vbr, Some(_), Some(_)) => {
+                return Err(ProcessError::InvalidParameter(
+                    "quality and bitrate_bps cannot be combined".to_string(),
+                ))
+            }
+            (BitrateMode::Abr | BitrateMode::Cbr, Some(_), _) => {
+                return Err(ProcessError::InvalidParameter(
+                    "quality only applies to the vbr bitrate_mode".to_string(),
+                ))
+            }
+            (BitrateMode::Abr | BitrateMode::Cbr, None, None) => {
+                return Err(ProcessError::InvalidParameter(
+                    "the abr and cbr bitrate modes require bitrate_bps".to_string(),
+                ))
+            }
+            _ => {}
+        }
+        let integer_output = self.format == OutputFormat::Flac
+            || (self.format == OutputFormat::Wav && self.sample_format != SampleFormat::F32);
+        if self.dither && !integer_output {
+            return Err(ProcessError::InvalidParameter(
+                "dither only applies to flac and integer wav output".to_string(),
+            ));
+        }
+        match self.dither_seed {
+            Some(_) if !self.dither => {
+                return Err(ProcessError::InvalidParameter(
+                    "dither_seed requires dither".to_string(),
+                ))
+            }
+            // Zero is the one state the xorshift generator never leaves.
+            Some(0) => {
+                return Err(ProcessError::InvalidParameter(
+                    "dither_seed must be non-zero".to_string(),
+                ))
+            }
+            _ => {}
+        }
+        if let Some(bitrate_kbps) = self.bitrate_kbps {
+            if self.format != OutputFormat::Mp3 {
+                return Err(ProcessError::InvalidParameter(
+                    "bitrate_kbps only applies to mp3 output".to_string(),
+                ));
+            }
+            if mp3_bitrate(bitrate_kbps).is_none() {
+                return Err(ProcessError::InvalidParameter(format!(
+                    "bitrate_kbps {bitrate_kbps} is not a valid mp3 bitrate"
+                )));
+            }
+        }
+        if let Some(compression_level) = self.compression_level {
+            if self.format != OutputFormat::Flac {
+                return Err(ProcessError::InvalidParameter(
+                    "compression_level only applies to flac output".to_string(),
+                ));
+            }
+            if compression_level > 8 {
+                return Err(ProcessError::InvalidParameter(format!(
+                    "compression_level must be between 0 and 8, got {compression_level}"
+                )));
+            }
+        }
+        Ok(())
+    }
+}
+
+/// Default `max_consecutive_errors`: far more bad packets in a row than a damaged but playable
+/// file has, while still bounding the work an adversarial input can cause.
+const DEFAULT_MAX_CONSECUTIVE_ERRORS: usize = 100;
+
+struct DecodeOptions {
+    /// A file extension (`"mp3"`) or MIME type (`"audio/ogg"`) to help probing.
+    hint: Option<String>,
+    /// Offset in seconds of the first decoded sample.
+    start_seconds: f64,
+    /// Stop decoding after this many seconds from `start_seconds`.
+    duration_seconds: Option<f64>,
+    /// Python callable invoked with the decoded fraction every [`PROGRESS_INTERVAL`] packets.
+    progress: Option<PyObject>,
+    /// Fail on the first corrupt packet instead of skipping it.
+    strict: bool,
+    /// Stop decoding once this many packets in a row are corrupt, returning what came before
+    /// with a `truncation_error`; `None` skips them indefinitely.
+    max_consecutive_errors: Option<usize>,
+    /// Abort with [`LimitExceeded`] as soon as more than this many seconds have been decoded.
+    max_duration_seconds: Option<f64>,
+    /// Which of the container's audio tracks to decode, counting from 0; the first when unset.
+    track_index: Option<usize>,
+    /// Only probe for these containers instead of every supported one.
+    allowed_formats: Option<Vec<InputFormat>>,
+}
+
+impl Default for DecodeOptions {
+    fn default() -> Self {
+        DecodeOptions {
+            hint: None,
+            start_seconds: 0.0,
+            duration_seconds: None,
+            progress: None,
+            strict: false,
+            max_consecutive_errors: Some(DEFAULT_MAX_CONSECUTIVE_ERRORS),
+            max_duration_seconds: None,
+            track_index: None,
+            allowed_formats: None,
+        }
+    }
+}
+
+/// A container format the decoder can probe for.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+enum InputFormat {
+    /// Raw ADTS AAC streams.
+    Aac,
+    Flac,
+    Mkv,
+    Mp3,
+    /// ISO MP4, including `.m4a`.
+    Mp4,
+    Ogg,
+    Wav,
+}
+
+impl FromStr for InputFormat {
+    type Err = ProcessError;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "aac" => Ok(InputFormat::Aac),
+            "flac" => Ok(InputFormat::Flac),
+            "mkv" => Ok(InputFormat::Mkv),
+            "mp3" => Ok(InputFormat::Mp3),
+            "mp4" => Ok(InputFormat::Mp4),
+            "ogg" => Ok(InputFormat::Ogg),
+            "wav" => Ok(InputFormat::Wav),
+            _ => Err(ProcessError::InvalidParameter(format!(
+                "unknown input format {s:?}, expected \"aac\", \"flac\", \"mkv\", \"mp3\", \
+                 \"mp4\", \"ogg\" or \"wav\""
+            ))),
+        }
+    }
+}
+
+impl InputFormat {
+    fn register(self, probe: &mut Probe) {
+        match self {
+            InputFormat::Aac => probe.register_all::<AdtsReader>(),
+            InputFormat::Flac => probe.register_all::<FlacReader>(),
+            InputFormat::Mkv => probe.register_all::<MkvReader>(),
+            InputFormat::Mp3 => probe.register_all::<MpaReader>(),
+            InputFormat::Mp4 => probe.register_all::<IsoMp4Reader>(),
+            InputFormat::Ogg => probe.register_all::<OggReader>(),
+            InputFormat::Wav => probe.register_all::<WavReader>(),
+        }
+    }
+}
+
+impl DecodeOptions {
+    fn validate(&self) -> Result<(), ProcessError> {
+        if self
+            .allowed_formats
+            .as_ref()
+            .is_some_and(|formats| formats.is_empty())
+        {
+            return Err(ProcessError::InvalidParameter(
+                "allowed_formats must not be empty".to_string(),
+            ));
+        }
+        if self.max_consecutive_errors == Some(0) {
+            return Err(ProcessError::InvalidParameter(
+                "max_consecutive_errors must be greater than zero".to_string(),
+            ));
+        }
+        if !self.start_seconds.is_finite() || self.start_seconds < 0.0 {
+            return Err(ProcessError::InvalidParameter(format!(
+                "start_seconds must be a finite non-negative value, got {}",
+                self.start_seconds
+            )));
+        }
+        if let Some(duration_seconds) = self.duration_seconds {
+            if !duration_seconds.is_finite() || duration_seconds <= 0.0 {
+                return Err(ProcessError::InvalidParameter(format!(
+                    "duration_seconds must be a finite positive value, got {duration_seconds}"
+                )));
+            }
+        }
+        if let Some(max_duration_seconds) = self.max_duration_seconds {
+            if !max_duration_seconds.is_finite() || max_duration_seconds <= 0.0 {
+                return Err(ProcessError::InvalidParameter(format!(
+                    "max_duration_seconds must be a finite positive value, got \
+                     {max_duration_seconds}"
+                )));
+            }
+        }
+        Ok(())
+    }
+
+    /// The `[start, end)` range of frames to decode at `sample_rate`.
+    fn frame_range(&self, sample_rate: u32) -> (u64, u64) {
+        let start = (self.start_seconds * f64::from(sample_rate)).round() as u64;
+        let end = self.duration_seconds.map_or(u64::MAX, |duration| {
+            start.saturating_add((duration * f64::from(sample_rate)).round() as u64)
+        });
+        (start, end)
+    }
+
+    fn hint(&self) -> Hint {
+        let mut hint = Hint::new();
+        match self.hint.as_deref() {
+            Some(mime) if mime.contains('/') => {
+                hint.mime_type(mime);
+            }
+            Some(extension) => {
+                hint.with_extension(extension.trim_start_matches('.'));
+            }
+            None => {}
+        }
+        hint
+    }
+}
+
+/// Shape of the gain ramp used by `fade_in_ms` and `fade_out_ms`.
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+enum FadeCurve {
+    /// Gain rises in a straight line.
+    #[default]
+    Linear,
+    /// Gain follows a quarter sine, which sounds smoother for longer fades.
+    Sine,
+}
+
+impl FadeCurve {
+    /// Gain at `progress` through the ramp, from `0.0` (silent) to `1.0` (untouched).
+    fn gain(self, progress: f32) -> f32 {
+        match self {
+            FadeCurve::Linear => progress,
+            FadeCurve::Sine => (progress * std::f32::consts::FRAC_PI_2).sin(),
+        }
+    }
+}
+
+impl FromStr for FadeCurve {
+    type Err = ProcessError;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "linear" => Ok(FadeCurve::Linear),
+            "sine" => Ok(FadeCurve::Sine),
+            _ => Err(ProcessError::InvalidParameter(format!(
+                "unknown fade_curve {s:?}, expected \"linear\" or \"sine\""
+            ))),
+        }
+    }
+}
+
+/// Processing applied to the decoded PCM before it is encoded and turned into a waveform.
+#[derive(Clone)]
+struct DspOptions {
+    /// Cut-off in Hz of a second-order Butterworth high-pass run first, to remove DC offset
+    /// and rumble; `None` or 0 leaves the audio untouched.
+    highpass_hz: Option<f32>,
+    /// Gain in dB applied right after decoding, clamped to full scale unless `limiter` is set.
+    gain_db: f32,
+    /// Source channel indices to keep, in output order; channels left out are dropped. Runs
+    /// before every other step.
+    channel_map: Option<Vec<usize>>,
+    /// Average all channels into one.
+    mono: bool,
+    /// Resample to this rate after decoding.
+    target_sample_rate: Option<u32>,
+    /// Remove leading and trailing frames quieter than `silence_threshold_db`.
+    trim_silence: bool,
+    /// Threshold in dBFS below which a frame counts as silent.
+    silence_threshold_db: f32,
+    /// Shortest leading or trailing run of silence, in milliseconds, that gets trimmed.
+    min_silence_ms: f32,
+    /// Play the audio backwards, applied after trimming and before the fades.
+    reverse: bool,
+    /// Length of the fade from silence at the start, in milliseconds.
+    fade_in_ms: f32,
+    /// Length of the fade to silence at the end, in milliseconds.
+    fade_out_ms: f32,
+    fade_curve: FadeCurve,
+    /// Scale the audio so its global peak reaches `normalize_target_db`.
+    normalize: bool,
+    /// Peak level in dBFS that `normalize` scales to. It is capped at full scale, or at
+    /// [`LIMITER_THRESHOLD`] with the limiter on, so normalizing never drives the peak into it.
+    normalize_target_db: f32,
+    /// Integrated loudness in LUFS to scale the audio to, measured per EBU R128.
+    target_lufs: Option<f32>,
+    /// Soft-clip samples above [`LIMITER_THRESHOLD`] instead of hard-clamping gain to full scale.
+    limiter: bool,
+}
+
+/// Measurements taken while running [`DspOptions::apply`].
+#[derive(Default)]
+struct DspReport {
+    /// Integrated loudness of the audio before loudness normalization, when it was measured and
+    /// the clip was long enough to be gated.
+    input_lufs: Option<f64>,
+    /// Milliseconds of silence removed from the start by `trim_silence`.
+    trimmed_start_ms: f64,
+    /// Milliseconds of silence removed from the end by `trim_silence`.
+    trimmed_end_ms: f64,
+}
+
+impl Default for DspOptions {
+    fn default() -> Self {
+        DspOptions {
+            highpass_hz: None,
+            gain_db: 0.0,
+            channel_map: None,
+            mono: false,
+            target_sample_rate: None,
+            trim_silence: false,
+            silence_threshold_db: -60.0,
+            min_silence_ms: 0.0,
+            reverse: false,
+            fade_in_ms: 0.0,
+            fade_out_ms: 0.0,
+            fade_curve: FadeCurve::default(),
+            normalize: false,
+            normalize_target_db: 0.0,
+            target_lufs: None,
+            limiter: false,
+        }
+    }
+}
+
+impl DspOptions {
+    fn validate(&self) -> Result<(), ProcessError> {
+        if !self.gain_db.is_finite() {
+            return Err(ProcessError::InvalidParameter(format!(
+                "gain_db must be finite, got {}",
+                self.gain_db
+            )));
+        }
+        if !self.silence_threshold_db.is_finite() {
+            return Err(ProcessError::InvalidParameter(format!(
+                "silence_threshold_db must be finite, got {}",
+                self.silence_threshold_db
+            )));
+        }
+        if !self.min_silence_ms.is_finite() || self.min_silence_ms < 0.0 {
+            return Err(ProcessError::InvalidParameter(format!(
+                "min_silence_ms must be a finite value of at least 0, got {}",
+                self.min_silence_ms
+            )));
+        }
+        for (name, value) in [
+            ("fade_in_ms", self.fade_in_ms),
+            ("fade_out_ms", self.fade_out_ms),
+        ] {
+            if !value.is_finite() || value < 0.0 {
+                return Err(ProcessError::InvalidParameter(format!(
+                    "{name} must be a finite value of at least 0, got {value}"
+                )));
+            }
+        }
+        if let Some(highpass_hz) = self.highpass_hz {
+            if !highpass_hz.is_finite() || highpass_hz < 0.0 {
+                return Err(ProcessError::InvalidParameter(format!(
+                    "highpass_hz must be a finite value of at least 0, got {highpass_hz}"
+                )));
+            }
+        }
+        if !self.normalize_target_db.is_finite() {
+            return Err(ProcessError::InvalidParameter(format!(
+                "normalize_target_db must be finite, got {}",
+                self.normalize_target_db
+            )));
+        }
+        if self.normalize_target_db != 0.0 && !self.normalize {
+            return Err(ProcessError::InvalidParameter(
+                "normalize_target_db requires normalize".to_string(),
+            ));
+        }
+        if let Some(target_lufs) = self.target_lufs {
+            if !target_lufs.is_finite() || target_lufs > 0.0 {
+                return Err(ProcessError::InvalidParameter(format!(
+                    "target_lufs must be a finite value of at most 0, got {target_lufs}"
+                )));
+            }
+            if self.normalize {
+                return Err(ProcessError::InvalidParameter(
+                    "normalize and target_lufs cannot be combined".to_string(),
+                ));
+            }
+        }
+        if self.target_sample_rate == Some(0) {
+            return Err(ProcessError::InvalidParameter(
+                "target_sample_rate must be greater than zero".to_string(),
+            ));
+        }
+        if let Some(map) = &self.channel_map {
+            if map.is_empty() {
+                return Err(ProcessError::InvalidParameter(
+                    "channel_map must not be empty".to_string(),
+                ));
+            }
+            if let Some((i, &index)) = map
+                .iter()
+                .enumerate()
+                .find(|&(i, index)| map[..i].contains(index))
+            {
+                return Err(ProcessError::InvalidParameter(format!(
+                    "channel_map lists channel {index} more than once (at position {i})"
+                )));
+            }
+        }
+        Ok(())
+    }
+
+    /// Checks `channel_map` against the `channels` the source decoded to and returns how many
+    /// channels are left once it and `mono` have run.
+    fn output_channels(&self, channels: usize) -> Result<usize, ProcessError> {
+        let mapped = match &self.channel_map {
+            Some(map) => {
+                if let Some(index) = map.iter().find(|&&index| index >= channels) {
+                    return Err(ProcessError::InvalidParameter(format!(
+                        "channel_map index {index} is out of range for audio with {channels} \
+                         channels"
+                    )));
+                }
+                map.len()
+            }
+            None => channels,
+        };
+        Ok(if self.mono { 1 } else { mapped })
+    }
+
+    /// Runs the enabled steps over `audio` and returns it along with anything measured on the
+    /// way. `channel_map` must already have been checked with [`DspOptions::output_channels`].
+    fn apply(&self, mut audio: DecodedAudio) -> Result<(DecodedAudio, DspReport), BoxError> {
+        let mut report = DspReport::default();
+        if let Some(map) = &self.channel_map {
+            audio.pcm = remap_channels(&audio.pcm, audio.channels, map);
+            audio.channels = map.len();
+        }
+        if let Some(mut filters) = self.highpass_filters(audio.channels, audio.sample_rate)? {
+            filter_interleaved(&mut audio.pcm, &mut filters);
+        }
+        if self.gain_db != 0.0 {
+            self.apply_gain(&mut audio.pcm, db_to_amplitude(self.gain_db));
+        }
+        if self.mono && audio.channels > 1 {
+            audio.pcm = downmix_to_mono(&audio.pcm, audio.channels);
+            audio.channels = 1;
+        }
+        if let Some(target_sample_rate) = self
+            .target_sample_rate
+            .filter(|&rate| rate != audio.sample_rate)
+        {
+            audio.pcm = resample(
+                &audio.pcm,
+                audio.channels,
+                audio.sample_rate,
+                target_sample_rate,
+            )?;
+            audio.sample_rate = target_sample_rate;
+        }
+        if self.trim_silence {
+            let rate = f64::from(audio.sample_rate);
+            let min_frames = (f64::from(self.min_silence_ms) / 1000.0 * rate).ceil() as usize;
+            let (leading, trailing) = trim_silence(
+                &mut audio.pcm,
+                audio.channels,
+                db_to_amplitude(self.silence_threshold_db),
+                min_frames,
+            );
+            report.trimmed_start_ms = leading as f64 * 1000.0 / rate;
+            report.trimmed_end_ms = trailing as f64 * 1000.0 / rate;
+            audio.duration_seconds =
+                (audio.duration_seconds - (leading + trailing) as f64 / rate).max(0.0);
+        }
+        if self.reverse {
+            reverse_frames(&mut audio.pcm, audio.channels);
+        }
+        if self.fade_in_ms > 0.0 || self.fade_out_ms > 0.0 {
+            let ms_to_frames =
+                |ms: f32| (f64::from(ms) / 1000.0 * f64::from(audio.sample_rate)).round() as usize;
+            apply_fades(
+                &mut audio.pcm,
+                audio.channels,
+                ms_to_frames(self.fade_in_ms),
+                ms_to_frames(self.fade_out_ms),
+                self.fade_curve,
+            );
+        }
+        if self.normalize {
+            let ceiling = if self.limiter { LIMITER_THRESHOLD } else { 1.0 };
+            normalize_peak(
+                &mut audio.pcm,
+                db_to_amplitude(self.normalize_target_db).min(ceiling),
+            );
+        }
+        if let Some(target_lufs) = self.target_lufs {
+            // Clips shorter than the 400ms gating block have no integrated loudness; leave
+            // those untouched rather than failing.
+            report.input_lufs = integrated_loudness(&audio.pcm, audio.channels, audio.sample_rate)?;
+            if let Some(input_lufs) = report.input_lufs {
+                self.apply_gain(
+                    &mut audio.pcm,
+                    db_to_amplitude((f64::from(target_lufs) - input_lufs) as f32),
+                );
+            }
+        }
+        if self.limiter {
+            soft_limit(&mut audio.pcm, LIMITER_THRESHOLD);
+        }
+        Ok((audio, report))
+    }
+
+    /// Whether any step would change the decoded samples.
+    fn alters_audio(&self) -> bool {
+        self.highpass_hz.is_some_and(|cutoff| cutoff > 0.0)
+            || self.channel_map.is_some()
+            || self.gain_db != 0.0
+            || self.mono
+            || self.target_sample_rate.is_some()
+            || self.trim_silence
+            || self.reverse
+            || self.fade_in_ms > 0.0
+            || self.fade_out_ms > 0.0
+            || self.normalize
+            || self.target_lufs.is_some()
+            || self.limiter
+    }
+
+    /// One high-pass filter per channel for `highpass_hz`, or `None` when it is off. The cut-off
+    /// has to stay below the Nyquist frequency, which is only known once decoding starts.
+    fn highpass_filters(
+        &self,
+        channels: usize,
+        sample_rate: u32,
+    ) -> Result<Option<Vec<Biquad>>, BoxError> {
+        let Some(cutoff) = self.highpass_hz.filter(|&cutoff| cutoff > 0.0) else {
+            return Ok(None);
+        };
+        let nyquist = sample_rate as f32 / 2.0;
+        if cutoff >= nyquist {
+            return Err(format!(
+                "highpass_hz {cutoff} must be below the Nyquist frequency of {nyquist} Hz"
+            )
+            .into());
+        }
+        let filter = highpass_filter(f64::from(cutoff), sample_rate);
+        Ok(Some(vec![filter; channels]))
+    }
+
+    /// Applies `gain`, leaving overs for the limiter to shape when it is enabled.
+    fn apply_gain(&self, pcm: &mut [f32], gain: f32) {
+        if self.limiter {
+            pcm.iter_mut().for_each(|sample| *sample *= gain);
+        } else {
+            apply_gain(pcm, gain);
+        }
+    }
+}
+
+#[derive(Default)]
+struct ProcessOptions {
+    decode: DecodeOptions,
+    dsp: DspOptions,
+    waveform: WaveformOptions,
+    encode: EncodeOptions,
+    /// Decode, process, analyze and encode packet by packet so the full PCM is never held in
+    /// memory. Only the steps that work on one block at a time are available.
+    streaming: bool,
+    /// Report how long each stage took on the result.
+    collect_timings: bool,
+    /// Report a hash of the processed samples on the result.
+    pcm_hash: bool,
+}
+
+impl ProcessOptions {
+    /// Builds the options from the keyword arguments shared by every `generate*` function.
+    fn from_kwargs(kwargs: Option<&Bound<'_, PyDict>>) -> PyResult<Self> {
+        let mut options = ProcessOptions::default();
+        let Some(kwargs) = kwargs else {
+            return Ok(options);
+        };
+
+        for (key, value) in kwargs {
+            let key: String = key.extract()?;
+            match key.as_str() {
+                "hint" => options.decode.hint = value.extract()?,
+                "start_seconds" => options.decode.start_seconds = value.extract()?,
+                "duration_seconds" => options.decode.duration_seconds = value.extract()?,
+                "strict" => options.decode.strict = value.extract()?,
+                "max_consecutive_errors" => {
+                    options.decode.max_consecutive_errors = value.extract()?
+                }
+                "max_duration_seconds" => options.decode.max_duration_seconds = value.extract()?,
+                "track_index" => options.decode.track_index = value.extract()?,
+                "allowed_formats" => {
+                    options.decode.allowed_formats = Some(
+                        value
+                            .extract::<Vec<String>>()?
+                            .iter()
+                            .map(|format| format.parse())
+                            .collect::<Result<_, _>>()?,
+                    )
+                }
+                "streaming" => options.streaming = value.extract()?,
+                "collect_timings" => options.collect_timings = value.extract()?,
+                "pcm_hash" => options.pcm_hash = value.extract()?,
+                "progress" => {
+                    if !value.is_none() && !value.is_callable() {
+                        return Err(PyTypeError::new_err("progress must be callable"));
+                    }
+                    options.decode.progress = value.extract()?;
+                }
+                "highpass_hz" => options.dsp.highpass_hz = value.extract()?,
+                "gain_db" => options.dsp.gain_db = value.extract()?,
+                "mono" => options.dsp.mono = value.extract()?,
+                "channel_map" => options.dsp.channel_map = value.extract()?,
+                "target_sample_rate" => options.dsp.target_sample_rate = value.extract()?,
+                "trim_silence" => options.dsp.trim_silence = value.extract()?,
+                "silence_threshold_db" => options.dsp.silence_threshold_db = value.extract()?,
+                "min_silence_ms" => options.dsp.min_silence_ms = value.extract()?,
+                "fade_in_ms" => options.dsp.fade_in_ms = value.extract()?,
+                "fade_out_ms" => options.dsp.fade_out_ms = value.extract()?,
+                "fade_curve" => options.dsp.fade_curve = value.extract::<String>()?.parse()?,
+                "normalize" => options.dsp.normalize = value.extract()?,
+                "normalize_target_db" => options.dsp.normalize_target_db = value.extract()?,
+                "limiter" => options.dsp.limiter = value.extract()?,
+                "reverse" => options.dsp.reverse = value.extract()?,
+                "target_lufs" => options.dsp.target_lufs = value.extract()?,
+                "chunk_size" => options.waveform.chunk_size = value.extract()?,
+                "num_points" => options.waveform.num_points = value.extract()?,
+                "preview_points" => options.waveform.preview_points = value.extract()?,
+                "waveform_format" => {
+                    options.waveform.format = value.extract::<String>()?.parse()?
+                }
+                "waveform_formats" => {
+                    options.waveform.formats = Some(
+                        value
+                            .extract::<Vec<String>>()?
+                            .iter()
+                            .map(|format| format.parse())
+                            .collect::<Result<_, _>>()?,
+                    )
+                }
+                "mode" => options.waveform.mode = value.extract::<String>()?.parse()?,
+                "per_channel" => options.waveform.per_channel = value.extract()?,
+                "channel" => options.waveform.channel = value.extract()?,
+                "pad_to" => options.waveform.pad_to = value.extract()?,
+                "timestamps" => options.waveform.timestamps = value.extract()?,
+                "url_safe" => options.waveform.url_safe = value.extract()?,
+                "stereo_mode" => {
+                    options.waveform.stereo_mode = value.extract::<String>()?.parse()?
+                }
+                "scale" => options.waveform.scale = value.extract::<String>()?.parse()?,
+                "db_floor" => options.waveform.db_floor = value.extract()?,
+                "overlap" => options.waveform.overlap = value.extract()?,
+                "decay" => options.waveform.decay = value.extract()?,
+                "spectral_band" => options.waveform.spectral_band = value.extract()?,
+                "weighting" => options.waveform.weighting = value.extract::<String>()?.parse()?,
+                _ => {
+                    if !options.encode.set_kwarg(&key, &value)? {
+                        return Err(PyTypeError::new_err(format!(
+                            "unexpected keyword argument {key:?}"
+                        )));
+                    }
+                }
+            }
+        }
+
+        Ok(options)
+    }
+
+    fn validate(&self) -> Result<(), ProcessError> {
+        self.decode.validate()?;
+        self.dsp.validate()?;
+        self.waveform.validate()?;
+        self.encode.validate()?;
+        if self.streaming {
+            self.check_streamable()?;
+        }
+        Ok(())
+    }
+
+    /// Rejects the options that need the whole clip at once and so can't be used with
+    /// `streaming`.
+    fn check_streamable(&self) -> Result<(), ProcessError> {
+        let whole_clip_options = [
+            ("target_sample_rate", self.dsp.target_sample_rate.is_some()),
+            ("trim_silence", self.dsp.trim_silence),
+            ("reverse", self.dsp.reverse),
+            ("fade_in_ms", self.dsp.fade_in_ms > 0.0),
+            ("fade_out_ms", self.dsp.fade_out_ms > 0.0),
+            ("normalize", self.dsp.normalize),
+            ("target_lufs", self.dsp.target_lufs.is_some()),
+            ("num_points", self.waveform.num_points.is_some()),
+            ("preview_points", self.waveform.preview_points.is_some()),
+            ("overlap", self.waveform.overlap > 0.0),
+            ("per_channel", self.waveform.per_channel),
+            ("waveform_formats", self.waveform.formats.is_some()),
+            ("timestamps", self.waveform.timestamps),
+            ("passthrough_if_ogg", self.encode.passthrough_if_ogg),
+            ("weighting", self.waveform.weighting != Weighting::None),
+        ];
+        if let Some((name, _)) = whole_clip_options.iter().find(|(_, set)| *set) {
+            return Err(ProcessError::InvalidParameter(format!(
+                "{name} cannot be combined with streaming"
+            )));
+        }
+        if (self.encode.format, self.encode.codec) != (OutputFormat::Ogg, OutputCodec::Vorbis) {
+            return Err(ProcessError::InvalidParameter(
+                "streaming only supports ogg output with the vorbis codec".to_string(),
+            ));
+        }
+        Ok(())
+    }
+
+    /// Rejects `passthrough_if_ogg` in `function`, which always encodes the audio it returns.
+    fn check_no_passthrough(&self, function: &str) -> Result<(), ProcessError> {
+        if self.encode.passthrough_if_ogg {
+            return Err(ProcessError::InvalidParameter(format!(
+                "passthrough_if_ogg cannot be used with {function}"
+            )));
+        }
+        Ok(())
+    }
+}
+
+#[derive(Debug)]
+enum ProcessError {
+    Decode(BoxError),
+    Encode(BoxError),
+    InvalidParameter(String),
+}
+
+impl fmt::Display for ProcessError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            ProcessError::Decode(err) => write!(f, "Unable to process data as PCM: {err}"),
+            ProcessError::Encode(err) => write!(f, "Unable to encode the audio data: {err}"),
+            ProcessError::InvalidParameter(msg) => write!(f, "Invalid parameter: {msg}"),
+        }
+    }
+}
+
+impl std::error::Error for ProcessError {}
+
+impl From<ProcessError> for PyErr {
+    /// Also sets the exception's `symphonia_error` to the name of the Symphonia error behind it,
+    /// if any, e.g. `"Unsupported"` or `"IoError"`.
+    fn from(err: ProcessError) -> PyErr {
+        let message = err.to_string();
+        let symphonia_error = match &err {
+            ProcessError::Decode(err) | ProcessError::Encode(err) => symphonia_error_kind(&**err),
+            ProcessError::InvalidParameter(_) => None,
+        };
+        let py_err = match err {
+            ProcessError::Decode(err) if is_unsupported(&*err) => {
+                UnsupportedFormatError::new_err(message)
+            }
+            ProcessError::Decode(err) if err.is::<LimitExceeded>() => {
+                LimitExceededError::new_err(message)
+            }
+            ProcessError::Decode(_) => DecodeError::new_err(message),
+            ProcessError::Encode(_) => EncodeError::new_err(message),
+            ProcessError::InvalidParameter(_) => InvalidParameterError::new_err(message),
+        };
+        Python::with_gil(
+            |py| match py_err.value(py).setattr("symphonia_error", symphonia_error) {
+                Ok(()) => py_err,
+                Err(err) => err,
+            },
+        )
+    }
+}
+
+/// Raised from the decode loop when the input runs past `max_duration_seconds`.
+#[derive(Debug)]
+struct LimitExceeded {
+    max_duration_seconds: f64,
+}
+
+impl fmt::Display for LimitExceeded {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(
+            f,
+            "audio is longer than max_duration_seconds ({}s)",
+            self.max_duration_seconds
+        )
+    }
+}
+
+impl std::error::Error for LimitExceeded {}
+
+/// Whether Symphonia gave up because it has no reader or decoder for the input, rather than
+/// because the data was corrupt.
+fn is_unsupported(err: &(dyn std::error::Error + 'static)) -> bool {
+    matches!(
+        err.downcast_ref::<symphonia::core::errors::Error>(),
+        Some(symphonia::core::errors::Error::Unsupported(_))
+    )
+}
+
+/// Name of the Symphonia error variant found in `err` or its sources.
+fn symphonia_error_kind(err: &(dyn std::error::Error + 'static)) -> Option<&'static str> {
+    use symphonia::core::errors::Error;
+    std::iter::successors(Some(err), |err| err.source()).find_map(|err| {
+        err.downcast_ref::<Error>().map(|err| match err {
+            Error::IoError(_) => "IoError",
+            Error::DecodeError(_) => "DecodeError",
+            Error::SeekError(_) => "SeekError",
+            Error::Unsupported(_) => "Unsupported",
+            Error::LimitError(_) => "LimitError",
+            Error::ResetRequired => "ResetRequired",
+        })
+    })
+}
+
+/// Wraps an in-memory buffer as a media source without copying it.
+fn bytes_source<T: AsRef<[u8]> + Send + Sync + 'static>(input: T) -> Box<dyn MediaSource> {
+    Box::new(Cursor::new(input))
+}
+
+/// Audio passed in from Python: either a bytes-like object, or any object with a `read(size)`
+/// method, which is then read incrementally instead of being materialized up front.
+enum AudioInput {
+    Bytes(PyBackedBytes),
+    Reader(PyReader),
+}
+
+impl<'py> FromPyObject<'py> for AudioInput {
+    fn extract_bound(ob: &Bound<'py, PyAny>) -> PyResult<Self> {
+        if let Ok(bytes) = ob.extract() {
+            return Ok(AudioInput::Bytes(bytes));
+        }
+        if ob.hasattr("read")? {
+            return Ok(AudioInput::Reader(PyReader::new(ob)?));
+        }
+        Err(PyTypeError::new_err(format!(
+            "expected bytes or a file-like object, got {}",
+            ob.get_type().name()?
+        )))
+    }
+}
+
+impl AudioInput {
+    fn into_source(self) -> Box<dyn MediaSource> {
+        match self {
+            AudioInput::Bytes(bytes) => bytes_source(bytes),
+            AudioInput::Reader(reader) => Box::new(reader),
+        }
+    }
+}
+
+/// A Python file-like object read through its `read`, and when `seekable()` says so, `seek`
+/// methods. The GIL is only reacquired around each call, so decoding can still run with it
+/// released.
+struct PyReader {
+    file: PyObject,
+    /// Total length in bytes; only known, and seeking only used, for seekable files.
+    byte_len: Option<u64>,
+}
+
+impl PyReader {
+    fn new(file: &Bound<'_, PyAny>) -> PyResult<Self> {
+        let seekable = file.hasattr("seekable")? && file.call_method0("seekable")?.extract()?;
+        let byte_len = if seekable {
+            let position: u64 = file.call_method0("tell")?.extract()?;
+            let end = file.call_method1("seek", (0, 2))?.extract()?;
+            file.call_method1("seek", (position, 0))?;
+            Some(end)
+        } else {
+            None
+        };
+        Ok(PyReader {
+            file: file.clone().unbind(),
+            byte_len,
+        })
+    }
+}
+
+impl Read for PyReader {
+    fn read(&mut self, buf: &mut [u8]) -> std::io::Result<usize> {
+        Python::with_gil(|py| {
+            let chunk = self.file.call_method1(py, "read", (buf.len(),))?;
+            let chunk: PyBackedBytes = chunk.extract(py)?;
+            let len = chunk.len().min(buf.len());
+            buf[..len].copy_from_slice(&chunk[..len]);
+            Ok(len)
+        })
+        .map_err(|err: PyErr| std::io::Error::other(err))
+    }
+}
+
+impl Seek for PyReader {
+    fn seek(&mut self, pos: SeekFrom) -> std::io::Result<u64> {
+        let (offset, whence) = match pos {
+            SeekFrom::Start(offset) => (offset as i64, 0),
+            SeekFrom::Current(offset) => (offset, 1),
+            SeekFrom::End(offset) => (offset, 2),
+        };
+        Python::with_gil(|py| {
+            self.file
+                .call_method1(py, "seek", (offset, whence))?
+                .extract(py)
+        })
+        .map_err(|err: PyErr| std::io::Error::other(err))
+    }
+}
+
+impl MediaSource for PyReader {
+    fn is_seekable(&self) -> bool {
+        self.byte_len.is_some()
+    }
+
+    fn byte_len(&self) -> Option<u64> {
+        self.byte_len
+    }
+}
+
+/// Probes `source` for its container format. `default::get_probe` and `default::get_codecs`
+/// hand out `&'static` registries that Symphonia builds once on first use and that are `Sync`,
+/// so there is nothing to rebuild or lock per call, even from `generate_batch`'s worker threads.
+/// With `allowed_formats`, a probe holding just those readers is built per call, which only
+/// costs a few descriptors; input in any other format is then unsupported.
+fn probe(source: Box<dyn MediaSource>, options: &DecodeOptions) -> Result<ProbeResult, BoxError> {
+    let hint = options.hint();
+    let mss = MediaSourceStream::new(source, Default::default());
+
+    let restricted = options.allowed_formats.as_ref().map(|formats| {
+        let mut probe = Probe::default();
+        for format in formats {
+            format.register(&mut probe);
+        }
+        // ID3v2 tags in front of MP3 streams are read by a metadata reader, not a container one.
+        probe.register_all::<Id3v2Reader>();
+        probe
+    });
+    let probe = restricted.as_ref().unwrap_or_else(|| default::get_probe());
+    Ok(probe.format(
+        &hint,
+        mss,
+        &FormatOptions::default(),
+        &MetadataOptions::default(),
+    )?)
+}
+
+/// The audio tracks of `format`, i.e. those with a codec, in container order.
+fn audio_tracks(format: &dyn FormatReader) -> impl Iterator<Item = &Track> {
+    format
+        .tracks()
+        .iter()
+        .filter(|t| t.codec_params.codec != symphonia::core::codecs::CODEC_TYPE_NULL)
+}
+
+/// The audio track `options.track_index` selects, or the first one by default.
+fn select_track<'f>(
+    format: &'f dyn FormatReader,
+    options: &DecodeOptions,
+) -> Result<&'f Track, BoxError> {
+    let index = options.track_index.unwrap_or(0);
+    audio_tracks(format)
+        .nth(index)
+        .ok_or_else(|| match audio_tracks(format).count() {
+            0 => "No supported audio tracks".into(),
+            count => {
+                format!("track_index {index} is out of range, the input has {count} audio tracks")
+                    .into()
+            }
+        })
+}
+
+/// Short name of `codec`, e.g. `"mp3"` or `"pcm_s16le"`.
+fn codec_name(codec: CodecType) -> String {
+    default::get_codecs()
+        .get_codec(codec)
+        .map_or("unknown", |descriptor| descriptor.short_name)
+        .to_string()
+}
+
+/// Stream properties of one audio track, as listed by `list_tracks`.
+struct TrackInfo {
+    index: usize,
+    codec: String,
+    language: Option<String>,
+    channels: Option<usize>,
+    sample_rate: Option<u32>,
+}
+
+fn list_audio_tracks(
+    source: Box<dyn MediaSource>,
+    options: &DecodeOptions,
+) -> Result<Vec<TrackInfo>, BoxError> {
+    let format = probe(source, options)?.format;
+    Ok(audio_tracks(&*format)
+        .enumerate()
+        .map(|(index, track)| TrackInfo {
+            index,
+            codec: codec_name(track.codec_params.codec),
+            language: track.language.clone(),
+            channels: track.codec_params.channels.map(|channels| channels.count()),
+            sample_rate: track.codec_params.sample_rate,
+        })
+        .collect())
+}
+
+/// Interleaved samples produced by [`decode_to_pcm`].
+struct DecodedAudio {
+    pcm: Vec<f32>,
+    channels: usize,
+    sample_rate: u32,
+    /// Length of the decoded range according to the stream's own frame counts, including
+    /// packets that failed to decode and so contributed no samples.
+    duration_seconds: f64,
+    /// Length of the decoded range according to the container's frame count, if it has one.
+    reported_duration_seconds: Option<f64>,
+    /// Why decoding stopped short of the end of the stream, if it did.
+    truncation_error: Option<String>,
+    /// Packets skipped because they could not be read or decoded.
+    dropped_packets: usize,
+    /// Short name of the source codec, e.g. `"mp3"` or `"pcm_s16le"`.
+    codec: String,
+    /// Bit depth of the source samples, when the codec has one.
+    bits_per_sample: Option<u32>,
+}
+
+fn decode_to_pcm(
+    source: Box<dyn MediaSource>,
+    options: &DecodeOptions,
+) -> Result<DecodedAudio, BoxError> {
+    let mut stream = PcmStream::open(source, options)?;
+    let mut pcm =
+        Vec::with_capacity((stream.expected_frames() * stream.channels).min(MAX_PREALLOCATION));
+    while let Some(samples) = stream.next_block()? {
+        pcm.extend_from_slice(samples);
+    }
+    Ok(stream.into_decoded(pcm))
+}
+
+/// Decodes the first audio track packet by packet, handing out the interleaved samples of each
+/// packet that fall inside the requested range. [`decode_to_pcm`] collects them all, while the
+/// `streaming` path consumes every block as it arrives.
+struct PcmStream<'a> {
+    options: &'a DecodeOptions,
+    format: Box<dyn FormatReader>,
+    decoder: Box<dyn Decoder>,
+    track_id: u32,
+    time_base: Option<TimeBase>,
+    channels: usize,
+    sample_rate: u32,
+    codec: String,
+    bits_per_sample: Option<u32>,
+    start_frame: u64,
+    end_frame: u64,
+    /// Without a known length only the final 1.0 can be reported.
+    total_frames: Option<u64>,
+    packets: u64,
+    /// Index of the next decoded frame within the whole stream.
+    position: u64,
+    /// Frames of the stream that fall inside the requested range, whether decoded or not.
+    covered_frames: u64,
+    dropped_packets: usize,
+    /// Corrupt packets skipped since the last one that decoded.
+    consecutive_errors: usize,
+    /// Samples of the packet returned by the last [`PcmStream::next_block`] call.
+    block: Vec<f32>,
+    /// `block` holds a packet decoded by `open` that hasn't been handed out yet.
+    primed: bool,
+    finished: bool,
+    /// Why the stream ended early, see [`DecodedAudio::truncation_error`].
+    truncation_error: Option<String>,
+}
+
+impl<'a> PcmStream<'a> {
+    fn open(source: Box<dyn MediaSource>, options: &'a DecodeOptions) -> Result<Self, BoxError> {
+        let mut format = probe(source, options)?.format;
+
+        let track = select_track(&*format, options)?;
+        let mut decoder = default::get_codecs().make(&track.codec_params, &Default::default())?;
+        let codec = codec_name(track.codec_params.codec);
+        let bits_per_sample = track.codec_params.bits_per_sample;
+
+        let sample_rate = track
+            .codec_params
+            .sample_rate
+            .ok_or("Unknown sample rate")?;
+        // AAC in MP4 only describes its channels inside the decoder configuration, so those are
+        // taken from the first decoded packet instead.
+        let channels = track.codec_params.channels.map(|channels| channels.count());
+
+        if sample_rate == 0 {
+            return Err(format!("invalid sample rate {sample_rate}").into());
+        }
+        if channels == Some(0) {
+            return Err("invalid channel count 0".into());
+        }
+
+        let track_id = track.id;
+        let time_base = track.codec_params.time_base;
+        let (start_frame, end_frame) = options.frame_range(sample_rate);
+        let total_frames = track
+            .codec_params
+            .n_frames
+            .map(|n_frames| n_frames.min(end_frame))
+            .filter(|&total_frames| total_frames > start_frame);
+        let mut position = 0;
+
+        if start_frame > 0 {
+            let seeked = format.seek(
+                SeekMode::Accurate,
+                SeekTo::Time {
+                    time: Time::from(options.start_seconds),
+                    track_id: Some(track_id),
+                },
+            );
+            // Formats that can't seek are decoded from the beginning and discarded up to the
+            // start.
+            if let Ok(seeked) = seeked {
+                position = ts_to_frame(seeked.actual_ts, time_base, sample_rate);
+                decoder.reset();
+            }
+        }
+
+        let mut stream = PcmStream {
+            options,
+            format,
+            decoder,
+            track_id,
+            time_base,
+            channels: channels.unwrap_or(0),
+            sample_rate,
+            codec,
+            bits_per_sample,
+            start_frame,
+            end_frame,
+            total_frames,
+            packets: 0,
+            position,
+            covered_frames: 0,
+            dropped_packets: 0,
+            consecutive_errors: 0,
+            block: Vec::new(),
+            primed: false,
+            finished: false,
+            truncation_error: None,
+        };
+        if channels.is_none() {
+            stream.primed = stream.next_block()?.is_some();
+            if stream.channels == 0 {
+                return Err("Unknown channels".into());
+            }
+        }
+        Ok(stream)
+    }
+
+    /// Decodes packets until one yields samples inside the range, or returns `None` once the
+    /// range or the stream is exhausted.
+    fn next_block(&mut self) -> Result<Option<&[f32]>, BoxError> {
+        if self.primed {
+            self.primed = false;
+            return Ok(Some(&self.block));
+        }
+        if self.finished {
+            return Ok(None);
+        }
+        let (start_frame, end_frame) = (self.start_frame, self.end_frame);
+
+        while self.position < end_frame {
+            let packet = match self.format.next_packet() {
+                Ok(pkt) => pkt,
+                Err(err) => {
+                    use symphonia::core::errors::Error;
+                    match err {
+                        // A chained Ogg stream starts a new logical bitstream, which needs a
+                        // fresh decoder.
+                        // Outside strict mode a link that can't be continued ends the stream
+                        // instead of discarding what came before it.
+                        Error::ResetRequired => match self.reset_decoder() {
+                            Ok(()) => continue,
+                            Err(err) if self.options.strict => return Err(err),
+                            Err(err) => {
+                                self.truncation_error = Some(err.to_string());
+                                break;
+                            }
+                        },
+                        Error::IoError(ref e) if e.kind() == std::io::ErrorKind::UnexpectedEof => {
+                            break
+                        }
+                        Error::IoError(_) | Error::DecodeError(_) if self.options.strict => {
+                            return Err(err.into())
+                        }
+                        Error::IoError(_) | Error::DecodeError(_) => {
+                            if self.drop_packet(err) {
+                                continue;
+                            }
+                            break;
+                        }
+                        err => {
+                            self.truncation_error = Some(err.to_string());
+                            break;
+                        }
+                    }
+                }
+            };
+
+            if packet.track_id() != self.track_id {
+                continue;
+            }
+
+            self.block.clear();
+            let position = self.position;
+            let frames = match self.decoder.decode(&packet) {
+                Ok(audio_buf) => {
+                    let frames = audio_buf.frames() as u64;
+                    let first = start_frame.clamp(position, position + frames);
+                    let last = end_frame.clamp(first, position + frames);
+                    let spec = *audio_buf.spec();
+                    let stride = spec.channels.count();
+                    if self.channels == 0 {
+                        self.channels = stride;
+                    }
+                    // Mono packets in a multichannel stream and the reverse, as MP3 allows, are
+                    // converted to the stream's layout; other changes can't be lined up.
+                    if stride != self.channels && stride != 1 && self.channels != 1 {
+                        let err = format!(
+                            "a packet changes the channel count from {} to {stride}",
+                            self.channels
+                        );
+                        if self.options.strict {
+                            return Err(err.into());
+                        }
+                        self.truncation_error = Some(err);
+                        break;
+                    }
+                    if first < last {
+                        let mut sample_buf =
+                            SampleBuffer::<f32>::new(audio_buf.capacity() as u64, spec);
+                        sample_buf.copy_interleaved_ref(audio_buf);
+                        let samples = &sample_buf.samples()[(first - position) as usize * stride
+                            ..(last - position) as usize * stride];
+                        if stride == self.channels {
+                            self.block.extend_from_slice(samples);
+                        } else if stride == 1 {
+                            for &sample in samples {
+                                self.block
+                                    .extend(std::iter::repeat_n(sample, self.channels));
+                            }
+                        } else {
+                            self.block.extend(downmix_to_mono(samples, stride));
+                        }
+                    }
+                    self.consecutive_errors = 0;
+                    frames
+                }
+                Err(err) if self.options.strict => return Err(err.into()),
+                // The packet's own duration keeps the timeline intact across the gap.
+                Err(err) => {
+                    if !self.drop_packet(err) {
+                        break;
+                    }
+                    ts_to_frame(packet.dur(), self.time_base, self.sample_rate)
+                }
+            };
+            let first = start_frame.clamp(position, position + frames);
+            self.covered_frames += end_frame.clamp(first, position + frames) - first;
+            self.position += frames;
+            if let Some(max_duration_seconds) = self.options.max_duration_seconds {
+                if self.duration_seconds() > max_duration_seconds {
+                    return Err(Box::new(LimitExceeded {
+                        max_duration_seconds,
+                    }));
+                }
+            }
+
+            self.packets += 1;
+            if let (Some(progress), Some(total_frames)) =
+                (&self.options.progress, self.total_frames)
+            {
+                if self.packets.is_multiple_of(PROGRESS_INTERVAL) {
+                    let fraction = self.position.saturating_sub(start_frame) as f64
+                        / (total_frames - start_frame) as f64;
+                    report_progress(progress, fraction.min(1.0))?;
+                }
+            }
+
+            if !self.block.is_empty() {
+                return Ok(Some(&self.block));
+            }
+        }
+
+        self.finished = true;
+        if let Some(total_frames) = self.total_frames {
+            if self.position < total_frames && self.truncation_error.is_none() {
+                self.truncation_error = Some(format!(
+                    "the stream ended after {} of the {total_frames} frames its container reports",
+                    self.position
+                ));
+            }
+        }
+        if let Some(progress) = &self.options.progress {
+            report_progress(progress, 1.0)?;
+        }
+        Ok(None)
+    }
+
+    /// Counts a skipped corrupt packet and returns whether to carry on. Once
+    /// `max_consecutive_errors` of them follow one another the stream ends there, keeping what
+    /// was decoded before with the reason in `truncation_error`.
+    fn drop_packet(&mut self, err: symphonia::core::errors::Error) -> bool {
+        self.dropped_packets += 1;
+        self.consecutive_errors += 1;
+        match self.options.max_consecutive_errors {
+            Some(max) if self.consecutive_errors >= max => {
+                self.truncation_error = Some(format!(
+                    "giving up after {max} consecutive corrupt packets: {err}"
+                ));
+                false
+            }
+            _ => true,
+        }
+    }
+
+    /// Rebuilds the decoder from the format's current tracks after `ResetRequired`, so decoding
+    /// carries on into the next chained stream. The interleaved output can't change shape
+    /// midway, so a stream with a different sample rate or channel count is an error.
+    fn reset_decoder(&mut self) -> Result<(), BoxError> {
+        let track = select_track(&*self.format, self.options)?;
+        let params = &track.codec_params;
+        if params
+            .sample_rate
+            .is_some_and(|rate| rate != self.sample_rate)
+        {
+            return Err(format!(
+                "chained stream changes the sample rate from {} to {}",
+                self.sample_rate,
+                params.sample_rate.unwrap_or_default()
+            )
+            .into());
+        }
+        let channels = params.channels.map(|channels| channels.count());
+        if self.channels != 0 && channels.is_some_and(|channels| channels != self.channels) {
+            return Err(format!(
+                "chained stream changes the channel count from {} to {}",
+                self.channels,
+                channels.unwrap_or_default()
+            )
+            .into());
+        }
+        self.decoder = default::get_codecs().make(params, &Default::default())?;
+        self.track_id = track.id;
+        self.time_base = params.time_base;
+        Ok(())
+    }
+
+    /// Frames the requested range should yield according to the container, or 0 when it
+    /// doesn't state a length.
+    fn expected_frames(&self) -> usize {
+        self.total_frames.map_or(0, |total_frames| {
+            (total_frames - self.start_frame).min(MAX_PREALLOCATION as u64) as usize
+        })
+    }
+
+    /// Length of the range decoded so far according to the stream's own frame counts.
+    fn duration_seconds(&self) -> f64 {
+        self.covered_frames as f64 / f64::from(self.sample_rate)
+    }
+
+    fn reported_duration_seconds(&self) -> Option<f64> {
+        self.total_frames.map(|total_frames| {
+            (total_frames - self.start_frame) as f64 / f64::from(self.sample_rate)
+        })
+    }
+
+    fn into_decoded(self, pcm: Vec<f32>) -> DecodedAudio {
+        DecodedAudio {
+            duration_seconds: self.duration_seconds(),
+            reported_duration_seconds: self.reported_duration_seconds(),
+            truncation_error: self.truncation_error,
+            pcm,
+            channels: self.channels,
+            sample_rate: self.sample_rate,
+            dropped_packets: self.dropped_packets,
+            codec: self.codec,
+            bits_per_sample: self.bits_per_sample,
+        }
+    }
+}
+
+/// Number of decoded packets between two calls of the progress callback.
+const PROGRESS_INTERVAL: u64 = 64;
+
+/// Calls the Python progress callback. Decoding runs with the GIL released, so it is
+/// reacquired just for the call; an exception raised by the callback aborts decoding.
+fn report_progress(progress: &PyObject, fraction: f64) -> Result<(), BoxError> {
+    Python::with_gil(|py| progress.call1(py, (fraction,)))?;
+    Ok(())
+}
+
+/// Converts a timestamp in `time_base` units into a frame index at `sample_rate`.
+fn ts_to_frame(ts: u64, time_base: Option<TimeBase>, sample_rate: u32) -> u64 {
+    match time_base {
+        Some(time_base) => {
+            let time = time_base.calc_time(ts);
+            ((time.seconds as f64 + time.frac) * f64::from(sample_rate)).round() as u64
+        }
+        None => ts,
+    }
+}
+
+/// Reads the duration of the first audio track from the container's frame count. Streams that
+/// don't report one have their packet durations summed instead, which walks the container
+/// without decoding any audio.
+fn probe_stream_duration(
+    source: Box<dyn MediaSource>,
+    options: &DecodeOptions,
+) -> Result<f64, BoxError> {
+    let mut format = probe(source, options)?.format;
+    let track = select_track(&*format, options)?;
+    let track_id = track.id;
+    let time_base = track.codec_params.time_base;
+    let sample_rate = track.codec_params.sample_rate;
+    let seconds = |ts: u64| match (time_base, sample_rate) {
+        (Some(time_base), _) => {
+            let time = time_base.calc_time(ts);
+            Ok(time.seconds as f64 + time.frac)
+        }
+        (None, Some(sample_rate)) if sample_rate > 0 => Ok(ts as f64 / f64::from(sample_rate)),
+        _ => Err("Unknown sample rate"),
+    };
+
+    if let Some(n_frames) = track.codec_params.n_frames {
+        return Ok(seconds(n_frames)?);
+    }
+
+    let mut total = 0;
+    loop {
+        match format.next_packet() {
+            Ok(packet) if packet.track_id() == track_id => total += packet.dur(),
+            Ok(_) => {}
+            Err(symphonia::core::errors::Error::IoError(ref e))
+                if e.kind() == std::io::ErrorKind::UnexpectedEof =>
+            {
+                break
+            }
+            Err(err) => return Err(err.into()),
+        }
+    }
+    Ok(seconds(total)?)
+}
+
+/// Tags and chapter markers of a file, as returned by `read_metadata`.
+struct Metadata {
+    tags: Vec<(String, String)>,
+    chapters: Vec<Chapter>,
+}
+
+/// A chapter marker found in the container.
+#[derive(Debug, PartialEq)]
+struct Chapter {
+    start_seconds: f64,
+    title: Option<String>,
+}
+
+/// Collects the tags found while probing (e.g. ID3) and in the container itself, in that order.
+/// Well-known tags use a normalized key such as `title` or `artist`; the rest keep the key
+/// stored in the file. Binary tags are skipped, and embedded cover art is reported as a
+/// `cover_art` entry holding its media type.
+///
+/// Chapters come from the container's cue points (a FLAC cue sheet) or, failing those, from
+/// `CHAPTERnnn` / `CHAPTERnnnNAME` Vorbis comments, sorted by start time.
+fn read_tags(source: Box<dyn MediaSource>, options: &DecodeOptions) -> Result<Metadata, BoxError> {
+    let mut probed = probe(source, options)?;
+    let mut tags = Vec::new();
+    let mut comment_chapters = HashMap::<String, (Option<f64>, Option<String>)>::new();
+    let mut collect = |revision: &MetadataRevision| {
+        for tag in revision.tags() {
+            if matches!(tag.value, Value::Binary(_)) {
+                continue;
+            }
+            if let Some(marker) = tag.key.to_ascii_uppercase().strip_prefix("CHAPTER") {
+                let value = tag.value.to_string();
+                match marker.strip_suffix("NAME") {
+                    Some(number) if is_chapter_number(number) => {
+                        comment_chapters.entry(number.to_string()).or_default().1 = Some(value);
+                    }
+                    None if is_chapter_number(marker) => {
+                        comment_chapters.entry(marker.to_string()).or_default().0 =
+                            parse_chapter_time(&value);
+                    }
+                    _ => {}
+                }
+            }
+            let key = match tag.std_key {
+                Some(StandardTagKey::TrackTitle) => "title",
+                Some(StandardTagKey::Artist) => "artist",
+                Some(StandardTagKey::Album) => "album",
+                Some(StandardTagKey::AlbumArtist) => "album_artist",
+                Some(StandardTagKey::Composer) => "composer",
+                Some(StandardTagKey::Genre) => "genre",
+                Some(StandardTagKey::Date) => "date",
+                Some(StandardTagKey::TrackNumber) => "track_number",
+                Some(StandardTagKey::DiscNumber) => "disc_number",
+                Some(StandardTagKey::Comment) => "comment",
+                _ => &tag.key,
+            };
+            tags.push((key.to_string(), tag.value.to_string()));
+        }
+        if let Some(visual) = revision.visuals().first() {
+            tags.push(("cover_art".to_string(), visual.media_type.clone()));
+        }
+    };
+
+    if let Some(revision) = probed.metadata.get().as_ref().and_then(|m| m.current()) {
+        collect(revision);
+    }
+    if let Some(revision) = probed.format.metadata().current() {
+        collect(revision);
+    }
+
+    let format = &*probed.format;
+    let sample_rate = select_track(format, options)
+        .ok()
+        .and_then(|track| track.codec_params.sample_rate);
+    let mut chapters: Vec<Chapter> = match sample_rate {
+        Some(sample_rate) => format
+            .cues()
+            .iter()
+            // A cue sheet's lead-out track marks the end of the audio rather than a chapter.
+            .filter(|cue| !matches!(cue.index, 170 | 255))
+            .map(|cue| Chapter {
+                start_seconds: cue.start_ts as f64 / f64::from(sample_rate),
+                title: cue
+                    .tags
+                    .iter()
+                    .find(|tag| {
+                        tag.std_key == Some(StandardTagKey::TrackTitle)
+                            || tag.key.eq_ignore_ascii_case("TITLE")
+                    })
+                    .map(|tag| tag.value.to_string()),
+            })
+            .collect(),
+        None => Vec::new(),
+    };
+    if chapters.is_empty() {
+        chapters = comment_chapters
+            .into_values()
+            .filter_map(|(start, title)| {
+                Some(Chapter {
+                    start_seconds: start?,
+                    title,
+                })
+            })
+            .collect();
+    }
+    chapters.sort_by(|a, b| a.start_seconds.total_cmp(&b.start_seconds));
+    Ok(Metadata { tags, chapters })
+}
+
+/// Whether `number` is the digits of a `CHAPTERnnn` Vorbis comment key.
+fn is_chapter_number(number: &str) -> bool {
+    !number.is_empty() && number.bytes().all(|b| b.is_ascii_digit())
+}
+
+/// Parses a chapter start such as `00:01:30.500` (hours and minutes optional) into seconds.
+fn parse_chapter_time(time: &str) -> Option<f64> {
+    let parts: Vec<&str> = time.trim().split(':').collect();
+    if parts.len() > 3 {
+        return None;
+    }
+    parts.iter().try_fold(0.0, |seconds, part| {
+        let value: f64 = part.parse().ok()?;
+        (value.is_finite() && value >= 0.0).then_some(seconds * 60.0 + value)
+    })
+}
+
+/// Splits interleaved samples into one buffer per channel. A trailing partial frame is dropped
+/// so that every channel has the same length, which the encoders rely on.
+fn interleaved_to_planar(pcm: &[f32], channels: usize) -> Vec<Vec<f32>> {
+    let frame_count = pcm.len() / channels;
+    let mut planar = vec![Vec::with_capacity(frame_count); channels];
+    for frame in pcm.chunks_exact(channels) {
+        for (channel, &sample) in planar.iter_mut().zip(frame) {
+            channel.push(sample);
+        }
+    }
+    planar
+}
+
+/// Interleaves one buffer per channel back into frames, stopping at the end of the shortest
+/// channel.
+fn planar_to_interleaved(planar: &[Vec<f32>]) -> Vec<f32> {
+    let frame_count = planar.iter().map(Vec::len).min().unwrap_or(0);
+    let mut pcm = Vec::with_capacity(frame_count * planar.len());
+    for i in 0..frame_count {
+        pcm.extend(planar.iter().map(|channel| channel[i]));
+    }
+    pcm
+}
+
+fn encode_to_ogg(
+    pcm: &[f32],
+    channels: usize,
+    sample_rate: u32,
+    options: &EncodeOptions,
+) -> Result<Vec<u8>, BoxError> {
+    let output = Vec::with_capacity(options.output_capacity(
+        pcm.len() / channels.max(1),
+        channels,
+        sample_rate,
+    ));
+    write_ogg(pcm, channels, sample_rate, options, output)
+}
+
+/// Encodes `pcm` as an OGG Vorbis stream into `output`, which is handed back once the last
+/// page has been written.
+fn write_ogg<W: Write>(
+    pcm: &[f32],
+    channels: usize,
+    sample_rate: u32,
+    options: &EncodeOptions,
+    output: W,
+) -> Result<W, BoxError> {
+    let mut encoder = vorbis_encoder(output, channels, sample_rate, options)?;
+
+    // Empty input skips straight to `finish`, which still writes a valid header-only stream.
+    if !pcm.is_empty() {
+        let planar = interleaved_to_planar(pcm, channels);
+        let planar_refs: Vec<&[f32]> = planar.iter().map(Vec::as_slice).collect();
+        encoder.encode_audio_block(&planar_refs)?;
+    }
+    Ok(encoder.finish()?)
+}
+
+const VORBIS_MAX_CHANNELS: usize = 8;
+
+/// Sets up a Vorbis encoder writing an OGG stream to `output`, which is fed blocks of planar
+/// samples and must be finished to flush the last page.
+fn vorbis_encoder<W: Write>(
+    output: W,
+    channels: usize,
+    sample_rate: u32,
+    options: &EncodeOptions,
+) -> Result<VorbisEncoder<W>, BoxError> {
+    let rate_nz =
+        NonZeroU32::new(sample_rate).ok_or_else(|| format!("invalid sample rate {sample_rate}"))?;
+
+    // Vorbis only defines channel layouts up to 7.1; libvorbis fails opaquely beyond that.
+    if channels > VORBIS_MAX_CHANNELS {
+        return Err(format!(
+            "{channels} channels not supported by vorbis (max {VORBIS_MAX_CHANNELS}), \
+             set mono to downmix"
+        )
+        .into());
+    }
+    let ch_nz = u8::try_from(channels)
+        .ok()
+        .and_then(NonZeroU8::new)
+        .ok_or_else(|| format!("invalid channel count {channels}"))?;
+
+    let mut builder = VorbisEncoderBuilder::new(rate_nz, ch_nz, output)?;
+    let bitrate = options.bitrate_bps.and_then(NonZeroU32::new);
+    let strategy = match (options.bitrate_mode, options.quality, bitrate) {
+        (BitrateMode::Vbr, Some(target_quality), _) => {
+            Some(VorbisBitrateManagementStrategy::QualityVbr { target_quality })
+        }
+        (BitrateMode::Vbr, None, Some(target_bitrate)) => {
+            Some(VorbisBitrateManagementStrategy::Vbr { target_bitrate })
+        }
+        (BitrateMode::Abr, _, Some(average_bitrate)) => {
+            Some(VorbisBitrateManagementStrategy::Abr { average_bitrate })
+        }
+        (BitrateMode::Cbr, _, Some(maximum_bitrate)) => {
+            Some(VorbisBitrateManagementStrategy::ConstrainedAbr { maximum_bitrate })
+        }
+        _ => None,
+    };
+    if let Some(strategy) = strategy {
+        builder.bitrate_management_strategy(strategy);
+    }
+    if let Some(serial) = options.ogg_serial {
+        builder.stream_serial(serial);
+    }
+    Ok(builder.build()?)
+}
+
+/// Sample rates accepted by the Opus encoder.
+const OPUS_SAMPLE_RATES: [u32; 5] = [8000, 12000, 16000, 24000, 48000];
+
+/// Owns an `OpusEncoder` allocated by `opus_encoder_create`.
+struct OpusEncoderHandle(*mut OpusEncoder);
+
+impl Drop for OpusEncoderHandle {
+    fn drop(&mut self) {
+        // SAFETY: the pointer came from `opus_encoder_create` and is only freed here.
+        unsafe { opus_encoder_destroy(self.0) }
+    }
+}
+
+fn opus_error(code: i32) -> BoxError {
+    format!("opus encoder error: {}", opus_strerror(code)).into()
+}
+
+/// Encodes `pcm` as Ogg Opus, reserving `capacity` bytes for the output.
+fn encode_to_opus(
+    pcm: &[f32],
+    channels: usize,
+    sample_rate: u32,
+    application: OpusApplication,
+    capacity: usize,
+) -> Result<Vec<u8>, BoxError> {
+    if !OPUS_SAMPLE_RATES.contains(&sample_rate) {
+        return Err(format!(
+            "opus does not support sample rate {sample_rate}, expected one of {OPUS_SAMPLE_RATES:?}"
+        )
+        .into());
+    }
+    if !(1..=2).contains(&channels) {
+        return Err(format!("{channels} channels not supported by opus (max 2)").into());
+    }
+
+    let mut error = OPUS_OK;
+    // SAFETY: the sample rate and channel count were validated above and `error` outlives the call.
+    let encoder = unsafe {
+        opus_encoder_create(
+            sample_rate as i32,
+            channels as i32,
+            application.to_libopus(),
+            &mut error,
+        )
+    };
+    if error != OPUS_OK || encoder.is_null() {
+        return Err(opus_error(error));
+    }
+    let encoder = OpusEncoderHandle(encoder);
+
+    let mut lookahead = 0i32;
+    // SAFETY: the encoder is valid and OPUS_GET_LOOKAHEAD writes a single i32.
+    let ret = unsafe { opus_encoder_ctl!(encoder.0, OPUS_GET_LOOKAHEAD_REQUEST, &mut lookahead) };
+    if ret != OPUS_OK {
+        return Err(opus_error(ret));
+    }
+
+    // Ogg Opus granule positions and pre-skip are always counted at 48 kHz.
+    let granule_scale = u64::from(48000 / sample_rate);
+    let pre_skip = lookahead as u64 * granule_scale;
+
+    let mut output = Vec::with_capacity(capacity);
+    let mut writer = PacketWriter::new(&mut output);
+    let serial = 1;
+
+    let mut head = Vec::with_capacity(19);
+    head.extend_from_slice(b"OpusHead");
+    head.push(1);
+    head.push(channels as u8);
+    head.extend_from_slice(&(pre_skip as u16).to_le_bytes());
+    head.extend_from_slice(&sample_rate.to_le_bytes());
+    head.extend_from_slice(&0i16.to_le_bytes());
+    head.push(0);
+    writer.write_packet(head, serial, PacketWriteEndInfo::EndPage, 0)?;
+
+    let vendor = b"waveform";
+    let mut tags = Vec::with_capacity(16 + vendor.len());
+    tags.extend_from_slice(b"OpusTags");
+    tags.extend_from_slice(&(vendor.len() as u32).to_le_bytes());
+    tags.extend_from_slice(vendor);
+    tags.extend_from_slice(&0u32.to_le_bytes());
+    writer.write_packet(tags, serial, PacketWriteEndInfo::EndPage, 0)?;
+
+    // Encode 20 ms frames, padding the tail with enough silence to flush the encoder's lookahead.
+    let frame_size = sample_rate as usize / 50;
+    let frame_count = pcm.len() / channels;
+    let padded_frames = frame_count + lookahead as usize;
+    let packet_count = padded_frames.div_ceil(frame_size).max(1);
+
+    let mut frame = vec![0.0f32; frame_size * channels];
+    let mut packet = vec![0u8; 4000];
+    for i in 0..packet_count {
+        let start = (i * frame_size * channels).min(pcm.len());
+        let end = ((i + 1) * frame_size * channels).min(pcm.len());
+        let samples = &pcm[start..end];
+        frame[..samples.len()].copy_from_slice(samples);
+        frame[samples.len()..].fill(0.0);
+
+        // SAFETY: `frame` holds exactly `frame_size` samples per channel and `packet` is
+        // writable for the length passed in.
+        let len = unsafe {
+            opus_encode_float(
+                encoder.0,
+                frame.as_ptr(),
+                frame_size as i32,
+                packet.as_mut_ptr(),
+                packet.len() as i32,
+            )
+        };
+        if len < 0 {
+            return Err(opus_error(len));
+        }
+
+        let (end_info, granule) = if i + 1 == packet_count {
+            (
+                PacketWriteEndInfo::EndStream,
+                pre_skip + frame_count as u64 * granule_scale,
+            )
+        } else {
+            (
+                PacketWriteEndInfo::NormalPacket,
+                ((i + 1) * frame_size) as u64 * granule_scale,
+            )
+        };
+        writer.write_packet(packet[..len as usize].to_vec(), serial, end_info, granule)?;
+    }
+
+    Ok(output)
+}
+
+const WAVE_FORMAT_PCM: u16 = 1;
+const WAVE_FORMAT_IEEE_FLOAT: u16 = 3;
+const WAVE_FORMAT_ALAW: u16 = 6;
+const WAVE_FORMAT_MULAW: u16 = 7;
+const WAVE_FORMAT_EXTENSIBLE: u16 = 0xFFFE;
+
+fn encode_to_wav(
+    pcm: &[f32],
+    channels: usize,
+    sample_rate: u32,
+    sample_format: SampleFormat,
+    dither: Option<Dither>,
+) -> Result<Vec<u8>, BoxError> {
+    if sample_rate == 0 {
+        return Err(format!("invalid sample rate {sample_rate}").into());
+    }
+    let channel_count = u16::try_from(channels)
+        .ok()
+        .filter(|&c| c > 0)
+        .ok_or_else(|| format!("invalid channel count {channels}"))?;
+
+    let bits_per_sample = sample_format.bits_per_sample();
+    let block_align = channel_count as u32 * bits_per_sample as u32 / 8;
+    let byte_rate = sample_rate
+        .checked_mul(block_align)
+        .ok_or("byte rate is too large for WAV")?;
+
+    // Only whole frames are written, so a trailing partial frame is dropped.
+    let frame_count = pcm.len() / channels;
+    let data_size = u32::try_from(frame_count as u64 * block_align as u64)
+        .map_err(|_| "audio is too large for WAV")?;
+
+    let format_tag = match sample_format {
+        SampleFormat::F32 => WAVE_FORMAT_IEEE_FLOAT,
+        SampleFormat::I16 | SampleFormat::I24 => WAVE_FORMAT_PCM,
+        SampleFormat::MuLaw => WAVE_FORMAT_MULAW,
+        SampleFormat::ALaw => WAVE_FORMAT_ALAW,
+    };
+    // More than two channels needs WAVE_FORMAT_EXTENSIBLE for players to accept the layout.
+    let extensible = channels > 2;
+    // The companded formats require the `cbSize` field, even though it is always zero.
+    let companded = matches!(sample_format, SampleFormat::MuLaw | SampleFormat::ALaw);
+    let fmt_size: u32 = match (extensible, companded) {
+        (true, _) => 40,
+        (false, true) => 18,
+        (false, false) => 16,
+    };
+    // Non-PCM formats carry a `fact` chunk holding the frame count.
+    let fact_size: u32 = if format_tag == WAVE_FORMAT_PCM { 0 } else { 12 };
+    let riff_size = (4 + 8 + fmt_size + fact_size) as u64 + 8 + data_size as u64;
+    let riff_size = u32::try_from(riff_size).map_err(|_| "audio is too large for WAV")?;
+
+    let mut output = Vec::with_capacity(riff_size as usize + 8);
+    output.extend_from_slice(b"RIFF");
+    output.extend_from_slice(&riff_size.to_le_bytes());
+    output.extend_from_slice(b"WAVE");
+
+    output.extend_from_slice(b"fmt ");
+    output.extend_from_slice(&fmt_size.to_le_bytes());
+    let header_tag = if extensible {
+        WAVE_FORMAT_EXTENSIBLE
+    } else {
+        format_tag
+    };
+    output.extend_from_slice(&header_tag.to_le_bytes());
+    output.extend_from_slice(&channel_count.to_le_bytes());
+    output.extend_from_slice(&sample_rate.to_le_bytes());
+    output.extend_from_slice(&byte_rate.to_le_bytes());
+    output.extend_from_slice(&(block_align as u16).to_le_bytes());
+    output.extend_from_slice(&bits_per_sample.to_le_bytes());
+    if extensible {
+        output.extend_from_slice(&22u16.to_le_bytes());
+        output.extend_from_slice(&bits_per_sample.to_le_bytes());
+        output.extend_from_slice(&0u32.to_le_bytes());
+        output.extend_from_slice(&format_tag.to_le_bytes());
+        output.extend_from_slice(&[
+            0x00, 0x00, 0x00, 0x00, 0x10, 0x00, 0x80, 0x00, 0x00, 0xAA, 0x00, 0x38, 0x9B, 0x71,
+        ]);
+    } else if companded {
+        output.extend_from_slice(&0u16.to_le_bytes());
+    }
+
+    if fact_size > 0 {
+        output.extend_from_slice(b"fact");
+        output.extend_from_slice(&4u32.to_le_bytes());
+        output.extend_from_slice(&(frame_count as u32).to_le_bytes());
+    }
+
+    output.extend_from_slice(b"data");
+    output.extend_from_slice(&data_size.to_le_bytes());
+    let samples = &pcm[..frame_count * channels];
+    match sample_format {
+        SampleFormat::F32 => {
+            for sample in samples {
+                output.extend_from_slice(&sample.to_le_bytes());
+            }
+        }
+        SampleFormat::I16 => {
+            for value in float_to_int(samples, 16, dither) {
+                output.extend_from_slice(&(value as i16).to_le_bytes());
+            }
+        }
+        SampleFormat::I24 => {
+            for value in float_to_int(samples, 24, dither) {
+                output.extend_from_slice(&value.to_le_bytes()[..3]);
+            }
+        }
+        SampleFormat::MuLaw => output.extend(
+            float_to_int(samples, 16, dither)
+                .into_iter()
+                .map(|value| linear_to_mulaw(value as i16)),
+        ),
+        SampleFormat::ALaw => output.extend(
+            float_to_int(samples, 16, dither)
+                .into_iter()
+                .map(|value| linear_to_alaw(value as i16)),
+        ),
+    }
+
+    Ok(output)
+}
+
+/// Sample rate of G.711 telephony audio.
+const G711_SAMPLE_RATE: u32 = 8000;
+
+/// Writes `pcm` as 8 kHz G.711 WAV in the companded `sample_format`, resampling it first when
+/// needed.
+fn encode_to_g711(
+    pcm: &[f32],
+    channels: usize,
+    sample_rate: u32,
+    sample_format: SampleFormat,
+) -> Result<Vec<u8>, BoxError> {
+    let pcm = if sample_rate == G711_SAMPLE_RATE {
+        Cow::Borrowed(pcm)
+    } else {
+        Cow::Owned(resample(pcm, channels, sample_rate, G711_SAMPLE_RATE)?)
+    };
+    encode_to_wav(&pcm, channels, G711_SAMPLE_RATE, sample_format, None)
+}
+
+/// Compands a 16-bit sample to G.711 mu-law: the biased magnitude is split into a 3-bit
+/// segment (its highest set bit) and the 4 bits below it, and the whole byte is inverted.
+fn linear_to_mulaw(sample: i16) -> u8 {
+    const BIAS: i32 = 0x84;
+    const CLIP: i32 = 32635;
+    let sign = if sample < 0 { 0x80 } else { 0 };
+    let magnitude = i32::from(sample).abs().min(CLIP) + BIAS;
+    let segment = (31 - magnitude.leading_zeros() - 7) as u8;
+    let mantissa = ((magnitude >> (segment + 3)) & 0x0f) as u8;
+    !(sign | (segment << 4) | mantissa)
+}
+
+/// Compands a 16-bit sample to G.711 A-law from its 13-bit magnitude: segment 0 is linear,
+/// each further one halves the resolution, and the even bits are inverted.
+fn linear_to_alaw(sample: i16) -> u8 {
+    let (mask, magnitude) = if sample >= 0 {
+        (0xd5, i32::from(sample) >> 3)
+    } else {
+        (0x55, -(i32::from(sample) >> 3) - 1)
+    };
+    let segment = (0..8).find(|&segment| magnitude < 0x20 << segment);
+    let value = match segment {
+        None => 0x7f,
+        Some(segment @ 0..=1) => (segment << 4) | ((magnitude >> 1) & 0x0f) as u8,
+        Some(segment) => (segment << 4) | ((magnitude >> segment) & 0x0f) as u8,
+    };
+    value ^ mask
+}
+
+/// MP3 bitrate used when `bitrate_kbps` isn't given.
+const DEFAULT_MP3_BITRATE_KBPS: u32 = 192;
+
+fn mp3_bitrate(kbps: u32) -> Option<Bitrate> {
+    Some(match kbps {
+        8 => Bitrate::Kbps8,
+        16 => Bitrate::Kbps16,
+        24 => Bitrate::Kbps24,
+        32 => Bitrate::Kbps32,
+        40 => Bitrate::Kbps40,
+        48 => Bitrate::Kbps48,
+        64 => Bitrate::Kbps64,
+        80 => Bitrate::Kbps80,
+        96 => Bitrate::Kbps96,
+        112 => Bitrate::Kbps112,
+        128 => Bitrate::Kbps128,
+        160 => Bitrate::Kbps160,
+        192 => Bitrate::Kbps192,
+        224 => Bitrate::Kbps224,
+        256 => Bitrate::Kbps256,
+        320 => Bitrate::Kbps320,
+        _ => return None,
+    })
+}
+
+/// Encodes mono or stereo `pcm` as a constant bitrate MP3 stream. LAME takes the interleaved
+/// stereo samples as-is, so no planar conversion is needed.
+fn encode_to_mp3(
+    pcm: &[f32],
+    channels: usize,
+    sample_rate: u32,
+    bitrate_kbps: u32,
+) -> Result<Vec<u8>, BoxError> {
+    let bitrate = mp3_bitrate(bitrate_kbps)
+        .ok_or_else(|| format!("bitrate_kbps {bitrate_kbps} is not a valid mp3 bitrate"))?;
+    if !(1..=2).contains(&channels) {
+        return Err(format!("{channels} channels not supported by mp3 (max 2)").into());
+    }
+
+    let mut builder = Mp3Builder::new().ok_or("unable to allocate the mp3 encoder")?;
+    builder.set_num_channels(channels as u8)?;
+    builder.set_sample_rate(sample_rate)?;
+    builder.set_brate(bitrate)?;
+    builder.set_quality(Mp3Quality::Best)?;
+    let mut encoder = builder.build()?;
+
+    let mut output = Vec::with_capacity(max_required_buffer_size(pcm.len() / channels));
+    if channels == 1 {
+        encoder.encode_to_vec(MonoPcm(pcm), &mut output)?;
+    } else {
+        encoder.encode_to_vec(InterleavedPcm(pcm), &mut output)?;
+    }
+    // The final frames need at most 7200 bytes.
+    output.reserve(7200);
+    encoder.flush_to_vec::<FlushNoGap>(&mut output)?;
+
+    Ok(output)
+}
+
+/// FLAC compression level used when `compression_level` isn't given, as in the reference
+/// encoder.
+const DEFAULT_FLAC_COMPRESSION_LEVEL: u8 = 5;
+
+/// Builds an encoder configuration loosely following the reference encoder's presets: levels
+/// 0-2 use small blocks and fixed predictors only, higher levels use larger blocks and
+/// increasingly long LPC filters.
+fn flac_config(compression_level: u8) -> flacenc::config::Encoder {
+    let mut config = flacenc::config::Encoder::default();
+    let stereo = compression_level > 0;
+    config.stereo_coding.use_leftside = stereo;
+    config.stereo_coding.use_rightside = stereo;
+    config.stereo_coding.use_midside = stereo;
+    if compression_level <= 2 {
+        config.block_size = 1152;
+        config.subframe_coding.use_lpc = false;
+        config.subframe_coding.fixed.max_order = if compression_level == 0 { 2 } else { 4 };
+    } else {
+        config.block_size = 4096;
+        config.subframe_coding.qlpc.lpc_order = match compression_level {
+            3 => 6,
+            4 | 5 => 8,
+            6 => 10,
+            _ => 12,
+        };
+    }
+    config
+}
+
+/// Encodes `pcm` as lossless 16-bit FLAC, optionally dithering the conversion to integers.
+fn encode_to_flac(
+    pcm: &[f32],
+    channels: usize,
+    sample_rate: u32,
+    compression_level: u8,
+    dither: Option<Dither>,
+) -> Result<Vec<u8>, BoxError> {
+    let config = flac_config(compression_level)
+        .into_verified()
+        .map_err(|(_, err)| err)?;
+    let samples = float_to_int(pcm, 16, dither);
+    let source = MemSource::from_samples(&samples, channels, 16, sample_rate as usize);
+    // The encoder's error holds an `Rc`, so only its message can cross threads.
+    let stream = flacenc::encode_with_fixed_block_size(&config, source, config.block_size)
+        .map_err(|err| err.to_string())?;
+
+    let mut sink = ByteSink::with_capacity(stream.count_bits());
+    stream.write(&mut sink).map_err(|err| err.to_string())?;
+    let mut output = sink.into_inner();
+    // STREAMINFO's minimum block size must exclude the final, shorter block. The encoder counts
+    // it, which makes decoders treat the fixed-size stream as variable-size and reject every
+    // frame, so copy the maximum block size over it.
+    output.copy_within(10..12, 8);
+    Ok(output)
+}
+
+/// A small xorshift generator producing triangular (TPDF) dither noise of ±1 LSB. Dithering
+/// turns the quantization error into noise rather than distortion correlated with the
+/// signal, which is most audible on quiet material.
+struct Dither {
+    state: u32,
+}
+
+impl Default for Dither {
+    fn default() -> Self {
+        Dither { state: 0x9E37_79B9 }
+    }
+}
+
+impl Dither {
+    /// `seed` must be non-zero, or the generator only ever yields zero.
+    fn new(seed: u32) -> Self {
+        Dither { state: seed }
+    }
+
+    fn next_uniform(&mut self) -> f32 {
+        self.state ^= self.state << 13;
+        self.state ^= self.state >> 17;
+        self.state ^= self.state << 5;
+        self.state as f32 / u32::MAX as f32
+    }
+
+    /// Difference of two uniform values, in `-1.0..1.0` with a triangular distribution.
+    fn next_tpdf(&mut self) -> f32 {
+        self.next_uniform() - self.next_uniform()
+    }
+}
+
+/// Converts samples to signed integers of `bits` bits, rounding to the nearest step after
+/// adding `dither` noise, if any.
+fn float_to_int(pcm: &[f32], bits: u32, mut dither: Option<Dither>) -> Vec<i32> {
+    let max = ((1i32 << (bits - 1)) - 1) as f32;
+    pcm.iter()
+        .map(|sample| {
+            let noise = dither.as_mut().map_or(0.0, Dither::next_tpdf);
+            (sample.clamp(-1.0, 1.0) * max + noise)
+                .round()
+                .clamp(-max - 1.0, max) as i32
+        })
+        .collect()
+}
+
+/// Builds frames from the source channels `map` lists, in that order. A trailing partial frame
+/// is dropped.
+fn remap_channels(pcm: &[f32], channels: usize, map: &[usize]) -> Vec<f32> {
+    pcm.chunks_exact(channels)
+        .flat_map(|frame| map.iter().map(|&index| frame[index]))
+        .collect()
+}
+
+/// Averages each interleaved frame into a single sample. A trailing partial frame is dropped.
+fn downmix_to_mono(pcm: &[f32], channels: usize) -> Vec<f32> {
+    pcm.chunks_exact(channels)
+        .map(|frame| frame.iter().sum::<f32>() / channels as f32)
+        .collect()
+}
+
+/// Resamples interleaved audio with a synchronous FFT resampler. The output is trimmed to
+/// `round(frames * to / from)` frames so the duration is unchanged.
+fn resample(pcm: &[f32], channels: usize, from: u32, to: u32) -> Result<Vec<f32>, BoxError> {
+    if from == to {
+        return Ok(pcm.to_vec());
+    }
+
+    let planar = interleaved_to_planar(pcm, channels);
+    let frame_count = planar[0].len();
+    let expected = ((frame_count as u64 * to as u64 + from as u64 / 2) / from as u64) as usize;
+
+    let mut resampler = FftFixedIn::<f32>::new(from as usize, to as usize, 1024, 2, channels)?;
+    let delay = resampler.output_delay();
+    let mut output = vec![Vec::with_capacity(expected + delay); channels];
+    fn append(output: &mut [Vec<f32>], chunk: Vec<Vec<f32>>) {
+        for (channel, samples) in output.iter_mut().zip(chunk) {
+            channel.extend_from_slice(&samples);
+        }
+    }
+
+    let mut position = 0;
+    while frame_count - position >= resampler.input_frames_next() {
+        let end = position + resampler.input_frames_next();
+        let chunk: Vec<&[f32]> = planar.iter().map(|c| &c[position..end]).collect();
+        append(&mut output, resampler.process(&chunk, None)?);
+        position = end;
+    }
+    if position < frame_count {
+        let chunk: Vec<&[f32]> = planar.iter().map(|c| &c[position..]).collect();
+        append(&mut output, resampler.process_partial(Some(&chunk), None)?);
+    }
+    // Flush the frames still held back by the resampler's delay.
+    while output[0].len() < expected + delay {
+        let before = output[0].len();
+        append(
+            &mut output,
+            resampler.process_partial::<&[f32]>(None, None)?,
+        );
+        if output[0].len() == before {
+            break;
+        }
+    }
+
+    for channel in &mut output {
+        channel.drain(..delay.min(channel.len()));
+        channel.truncate(expected);
+    }
+    Ok(planar_to_interleaved(&output))
+}
+
+fn db_to_amplitude(db: f32) -> f32 {
+    10f32.powf(db / 20.0)
+}
+
+/// Multiplies every sample by `gain`, clamping to `-1.0..=1.0` so loud results saturate
+/// instead of exceeding full scale.
+fn apply_gain(pcm: &mut [f32], gain: f32) {
+    pcm.iter_mut()
+        .for_each(|sample| *sample = (*sample * gain).clamp(-1.0, 1.0));
+}
+
+/// Removes leading and trailing runs of at least `min_frames` frames in which every channel is
+/// below `threshold`, and returns how many frames were removed from the start and the end.
+/// Shorter runs are kept. Audio that is silent throughout counts as one leading run.
+fn trim_silence(
+    pcm: &mut Vec<f32>,
+    channels: usize,
+    threshold: f32,
+    min_frames: usize,
+) -> (usize, usize) {
+    let is_loud = |frame: &[f32]| frame.iter().any(|sample| sample.abs() >= threshold);
+    let frames: Vec<&[f32]> = pcm.chunks_exact(channels).collect();
+    let total_frames = pcm.len() / channels;
+    let Some(first_loud) = frames.iter().position(|frame| is_loud(frame)) else {
+        if total_frames < min_frames {
+            return (0, 0);
+        }
+        pcm.clear();
+        return (total_frames, 0);
+    };
+    let last_loud = frames
+        .iter()
+        .rposition(|frame| is_loud(frame))
+        .unwrap_or(first_loud);
+    let leading = if first_loud >= min_frames {
+        first_loud
+    } else {
+        0
+    };
+    let trailing = match total_frames - last_loud - 1 {
+        run if run >= min_frames => run,
+        _ => 0,
+    };
+
+    pcm.truncate((total_frames - trailing) * channels);
+    pcm.drain(..leading * channels);
+    (leading, trailing)
+}
+
+/// Reverses the order of the frames in `pcm`, keeping the samples within each frame in channel
+/// order.
+fn reverse_frames(pcm: &mut [f32], channels: usize) {
+    pcm.reverse();
+    pcm.chunks_exact_mut(channels)
+        .for_each(|frame| frame.reverse());
+}
+
+/// Ramps the first `fade_in` and last `fade_out` frames from and to silence along `curve`. Both
+/// are clamped to the length of the audio, so on short clips the two fades overlap.
+fn apply_fades(
+    pcm: &mut [f32],
+    channels: usize,
+    fade_in: usize,
+    fade_out: usize,
+    curve: FadeCurve,
+) {
+    let total_frames = pcm.len() / channels;
+    let (fade_in, fade_out) = (fade_in.min(total_frames), fade_out.min(total_frames));
+    for (index, frame) in pcm.chunks_exact_mut(channels).enumerate() {
+        let mut gain = 1.0;
+        if index < fade_in {
+            gain *= curve.gain(index as f32 / fade_in as f32);
+        }
+        let from_end = total_frames - 1 - index;
+        if from_end < fade_out {
+            gain *= curve.gain(from_end as f32 / fade_out as f32);
+        }
+        if gain < 1.0 {
+            frame.iter_mut().for_each(|sample| *sample *= gain);
+        }
+    }
+}
+
+/// A second-order IIR section in transposed direct form II. Coefficients and state are kept
+/// in `f64`, since the A-weighting poles near 20 Hz sit very close to the unit circle.
+#[derive(Clone, Copy)]
+struct Biquad {
+    b: [f64; 3],
+    a: [f64; 2],
+    state: [f64; 2],
+}
+
+impl Biquad {
+    /// Maps the analog section `(b2 s² + b1 s + b0) / (s² + a1 s + a0)` to `sample_rate` with
+    /// the bilinear transform.
+    fn bilinear(b: [f64; 3], a: [f64; 2], sample_rate: u32) -> Self {
+        let k = 2.0 * f64::from(sample_rate);
+        let [b2, b1, b0] = b;
+        let [a1, a0] = a;
+        let norm = k * k + a1 * k + a0;
+        Biquad {
+            b: [
+                (b2 * k * k + b1 * k + b0) / norm,
+                (2.0 * b0 - 2.0 * b2 * k * k) / norm,
+                (b2 * k * k - b1 * k + b0) / norm,
+            ],
+            a: [
+                (2.0 * a0 - 2.0 * k * k) / norm,
+                (k * k - a1 * k + a0) / norm,
+            ],
+            state: [0.0; 2],
+        }
+    }
+
+    /// Magnitude of the response at `frequency` Hz.
+    fn gain_at(&self, frequency: f64, sample_rate: u32) -> f64 {
+        let omega = std::f64::consts::TAU * frequency / f64::from(sample_rate);
+        let (z1, z2) = ((-omega).sin_cos(), (-2.0 * omega).sin_cos());
+        let eval = |c: [f64; 3]| {
+            let re = c[0] + c[1] * z1.1 + c[2] * z2.1;
+            let im = c[1] * z1.0 + c[2] * z2.0;
+            re.hypot(im)
+        };
+        eval(self.b) / eval([1.0, self.a[0], self.a[1]])
+    }
+
+    fn process(&mut self, x: f64) -> f64 {
+        let y = self.b[0] * x + self.state[0];
+        self.state[0] = self.b[1] * x - self.a[0] * y + self.state[1];
+        self.state[1] = self.b[2] * x - self.a[1] * y;
+        y
+    }
+}
+
+/// The A-weighting curve at `sample_rate` as three biquads, normalized to 0 dB at 1 kHz. The
+/// analog prototype has four zeros at DC and poles at 20.6 Hz (twice), 107.7 Hz, 737.9 Hz and
+/// 12194 Hz (twice).
+fn a_weighting_filter(sample_rate: u32) -> [Biquad; 3] {
+    let pole = |hz: f64| std::f64::consts::TAU * hz;
+    let (p1, p2, p3, p4) = (
+        pole(20.598_997),
+        pole(107.652_65),
+        pole(737.862_23),
+        pole(12_194.217),
+    );
+    let mut sections = [
+        Biquad::bilinear([1.0, 0.0, 0.0], [2.0 * p1, p1 * p1], sample_rate),
+        Biquad::bilinear([1.0, 0.0, 0.0], [p2 + p3, p2 * p3], sample_rate),
+        Biquad::bilinear([0.0, 0.0, p4 * p4], [2.0 * p4, p4 * p4], sample_rate),
+    ];
+    let gain: f64 = sections
+        .iter()
+        .map(|section| section.gain_at(1000.0, sample_rate))
+        .product();
+    for coefficient in &mut sections[0].b {
+        *coefficient /= gain;
+    }
+    sections
+}
+
+/// A second-order Butterworth high-pass at `cutoff` Hz. The analog cut-off is prewarped so
+/// the bilinear transform puts the -3 dB point exactly at `cutoff`.
+fn highpass_filter(cutoff: f64, sample_rate: u32) -> Biquad {
+    let rate = f64::from(sample_rate);
+    let omega = 2.0 * rate * (std::f64::consts::PI * cutoff / rate).tan();
+    Biquad::bilinear(
+        [1.0, 0.0, 0.0],
+        [std::f64::consts::SQRT_2 * omega, omega * omega],
+        sample_rate,
+    )
+}
+
+/// Filters each interleaved channel of `pcm` in place with its own entry of `filters`, whose
+/// state carries over between calls.
+fn filter_interleaved(pcm: &mut [f32], filters: &mut [Biquad]) {
+    for frame in pcm.chunks_exact_mut(filters.len()) {
+        for (sample, filter) in frame.iter_mut().zip(filters.iter_mut()) {
+            *sample = filter.process(f64::from(sanitize(*sample))) as f32;
+        }
+    }
+}
+
+/// Runs each interleaved channel of `pcm` through its own A-weighting filter.
+fn a_weight(pcm: &[f32], channels: usize, sample_rate: u32) -> Vec<f32> {
+    let mut filters = vec![a_weighting_filter(sample_rate); channels];
+    pcm.iter()
+        .zip((0..channels).cycle())
+        .map(|(&sample, channel)| {
+            filters[channel]
+                .iter_mut()
+                .fold(f64::from(sanitize(sample)), |x, section| section.process(x))
+                as f32
+        })
+        .collect()
+}
+
+/// Hashes interleaved `f32` samples as little-endian bytes, after a header of the channel count
+/// and sample rate, so the digest doesn't depend on the host or on how the samples arrive.
+struct PcmHasher(Xxh3);
+
+impl PcmHasher {
+    fn new(channels: usize, sample_rate: u32) -> Self {
+        let mut hasher = Xxh3::new();
+        hasher.update(&(channels as u64).to_le_bytes());
+        hasher.update(&sample_rate.to_le_bytes());
+        PcmHasher(hasher)
+    }
+
+    fn update(&mut self, samples: &[f32]) {
+        for chunk in samples.chunks(1024) {
+            let bytes: Vec<u8> = chunk
+                .iter()
+                .flat_map(|sample| sample.to_le_bytes())
+                .collect();
+            self.0.update(&bytes);
+        }
+    }
+
+    fn finish(&self) -> String {
+        format!("{:032x}", self.0.digest128())
+    }
+}
+
+fn hash_pcm(pcm: &[f32], channels: usize, sample_rate: u32) -> String {
+    let mut hasher = PcmHasher::new(channels, sample_rate);
+    hasher.update(pcm);
+    hasher.finish()
+}
+
+/// Level above which [`soft_limit`] starts compressing samples.
+const LIMITER_THRESHOLD: f32 = 0.9;
+
+/// Passes samples up to `threshold` through untouched and bends anything louder along a tanh
+/// curve towards full scale, so overs round off instead of clipping flat.
+fn soft_limit(pcm: &mut [f32], threshold: f32) {
+    let headroom = 1.0 - threshold;
+    pcm.iter_mut().for_each(|sample| {
+        let magnitude = sample.abs();
+        if magnitude > threshold {
+            let limited = threshold + headroom * ((magnitude - threshold) / headroom).tanh();
+            *sample = limited.copysign(*sample);
+        }
+    });
+}
+
+/// Scales `pcm` by a single gain so that its largest absolute sample becomes `target`,
+/// preserving relative dynamics. Silent audio is left untouched.
+fn normalize_peak(pcm: &mut [f32], target: f32) {
+    let global_peak = peak(pcm);
+    if global_peak > 0.0 {
+        let gain = target / global_peak;
+        pcm.iter_mut().for_each(|sample| *sample *= gain);
+    }
+}
+
+/// Measures the EBU R128 integrated loudness of `pcm` in LUFS, or `None` when the audio is too
+/// short or too quiet to pass the gates.
+fn integrated_loudness(
+    pcm: &[f32],
+    channels: usize,
+    sample_rate: u32,
+) -> Result<Option<f64>, BoxError> {
+    let mut meter = EbuR128::new(channels.try_into()?, sample_rate, Mode::I)?;
+    meter.add_frames_f32(pcm)?;
+    let loudness = meter.loudness_global()?;
+    Ok(loudness.is_finite().then_some(loudness))
+}
+
+/// Magnitude at or above which a sample is counted as clipped.
+const CLIP_THRESHOLD: f32 = 0.999;
+
+/// Returns the peak magnitude of `pcm` and how many samples reach [`CLIP_THRESHOLD`].
+fn clip_stats(pcm: &[f32]) -> (f32, usize) {
+    pcm.iter().fold((0.0, 0), |(peak, clipped), sample| {
+        let magnitude = sample.abs();
+        (
+            peak.max(magnitude),
+            clipped + usize::from(magnitude >= CLIP_THRESHOLD),
+        )
+    })
+}
+
+/// Running sums for the Pearson correlation between the two channels of stereo audio, so the
+/// streaming path can measure it block by block.
+#[derive(Default)]
+struct StereoCorrelation {
+    frames: f64,
+    sum_left: f64,
+    sum_right: f64,
+    sum_left_sq: f64,
+    sum_right_sq: f64,
+    sum_product: f64,
+}
+
+impl StereoCorrelation {
+    /// Starts a measurement, or returns `None` when the audio isn't stereo.
+    fn new(channels: usize) -> Option<Self> {
+        (channels == 2).then(Self::default)
+    }
+
+    fn update(&mut self, pcm: &[f32]) {
+        for frame in pcm.chunks_exact(2) {
+            let (left, right) = (f64::from(sanitize(frame[0])), f64::from(sanitize(frame[1])));
+            self.frames += 1.0;
+            self.sum_left += left;
+            self.sum_right += right;
+            self.sum_left_sq += left * left;
+            self.sum_right_sq += right * right;
+            self.sum_product += left * right;
+        }
+    }
+
+    /// The correlation coefficient in `-1.0..=1.0`, or `None` when either channel is constant
+    /// (e.g. silent), which leaves it undefined.
+    fn finish(&self) -> Option<f64> {
+        let n = self.frames;
+        let covariance = n * self.sum_product - self.sum_left * self.sum_right;
+        let variance_left = n * self.sum_left_sq - self.sum_left * self.sum_left;
+        let variance_right = n * self.sum_right_sq - self.sum_right * self.sum_right;
+        let denominator = (variance_left * variance_right).sqrt();
+        (denominator > 0.0).then(|| (covariance / denominator).clamp(-1.0, 1.0))
+    }
+}
+
+fn stereo_correlation(pcm: &[f32], channels: usize) -> Option<f64> {
+    let mut correlation = StereoCorrelation::new(channels)?;
+    correlation.update(pcm);
+    correlation.finish()
+}
+
+/// Returns the smallest and largest of `values`, or `(0.0, 0.0)` when there are none.
+fn value_range(values: &[f32]) -> (f32, f32) {
+    if values.is_empty() {
+        return (0.0, 0.0);
+    }
+    values
+        .iter()
+        .fold((f32::INFINITY, f32::NEG_INFINITY), |(min, max), &value| {
+            (min.min(value), max.max(value))
+        })
+}
+
+/// Splits `pcm` into exactly `num_points` buckets, spreading the remainder samples evenly
+/// so that no bucket is dropped or more than one sample larger than any other.
+fn fixed_buckets(pcm: &[f32], num_points: usize) -> impl Iterator<Item = &[f32]> {
+    (0..num_points).map(move |i| fixed_bucket(pcm, num_points, i))
+}
+
+fn fixed_bucket(pcm: &[f32], num_points: usize, index: usize) -> &[f32] {
+    let len = pcm.len();
+    &pcm[index * len / num_points..(index + 1) * len / num_points]
+}
+
+/// Replaces NaN and infinite samples, which some corrupt streams decode to, with silence so a
+/// single bad sample can't poison the point of its whole chunk.
+fn sanitize(sample: f32) -> f32 {
+    if sample.is_finite() {
+        sample
+    } else {
+        0.0
+    }
+}
+
+fn peak(chunk: &[f32]) -> f32 {
+    chunk
+        .iter()
+        .map(|&v| sanitize(v).abs())
+        .fold(0.0f32, f32::max)
+}
+
+/// Buckets existing waveform `points` into `target_points` and keeps the value of largest
+/// magnitude in each, with its sign. Waveforms already at or below the target are returned as is.
+fn downsample_points(points: &[f32], target_points: usize) -> Vec<f32> {
+    if points.len() <= target_points {
+        return points.to_vec();
+    }
+    fixed_buckets(points, target_points)
+        .map(|bucket| {
+            bucket
+                .iter()
+                .copied()
+                .fold(0.0f32, |acc, v| if v.abs() > acc.abs() { v } else { acc })
+        })
+        .collect()
+}
+
+fn rms(chunk: &[f32]) -> f32 {
+    if chunk.is_empty() {
+        return 0.0;
+    }
+    let sum_squares: f32 = chunk.iter().map(|&v| sanitize(v).powi(2)).sum();
+    (sum_squares / chunk.len() as f32).sqrt()
+}
+
+fn min_max(chunk: &[f32]) -> [f32; 2] {
+    if chunk.is_empty() {
+        return [0.0, 0.0];
+    }
+    chunk
+        .iter()
+        .map(|&v| sanitize(v))
+        .fold([f32::INFINITY, f32::NEG_INFINITY], |[min, max], v| {
+            [min.min(v), max.max(v)]
+        })
+}
+
+thread_local! {
+    /// Plans are cached per FFT length, so each worker thread only plans `chunk_size` once.
+    static FFT_PLANNER: RefCell<FftPlanner<f32>> = RefCell::new(FftPlanner::new());
+}
+
+/// Magnitude of `chunk` within `band` (fractions of the Nyquist frequency), from an FFT of the
+/// Hann-windowed chunk at its own length. The energy of the bins in the band is divided by that
+/// of the window, so the whole band gives the chunk's windowed RMS: full-scale audio stays in
+/// `0.0..=1.0`, a full-scale sine measures about `0.707`, and a band gives its share of that.
+fn spectral(chunk: &[f32], band: (f32, f32)) -> f32 {
+    let len = chunk.len();
+    let window = |i: usize| (std::f32::consts::PI * i as f32 / len as f32).sin().powi(2);
+    let window_energy: f32 = (0..len).map(|i| window(i).powi(2)).sum();
+    if window_energy == 0.0 {
+        return 0.0;
+    }
+    let mut buffer: Vec<Complex<f32>> = chunk
+        .iter()
+        .enumerate()
+        .map(|(i, &v)| Complex::new(sanitize(v) * window(i), 0.0))
+        .collect();
+    let fft = FFT_PLANNER.with(|planner| planner.borrow_mut().plan_fft_forward(len));
+    fft.process(&mut buffer);
+
+    let nyquist = len / 2;
+    let low = (band.0 * nyquist as f32).round() as usize;
+    let high = ((band.1 * nyquist as f32).round() as usize).min(nyquist);
+    let energy: f32 = (low..=high)
+        .map(|bin| {
+            // Every bin but DC and Nyquist stands for its mirror image too.
+            let sides = if bin == 0 || (len.is_multiple_of(2) && bin == nyquist) {
+                1.0
+            } else {
+                2.0
+            };
+            buffer[bin].norm_sqr() * sides
+        })
+        .sum();
+    (energy / (len as f32 * window_energy)).sqrt()
+}
+
+fn quantize_u8(peak: f32) -> u8 {
+    (peak.clamp(0.0, 1.0) * 255.0).round() as u8
+}
+
+/// Inputs with fewer samples than this are reduced serially, where spawning rayon tasks would
+/// cost more than it saves.
+const PARALLEL_THRESHOLD: usize = 1 << 18;
+
+fn compute_points(pcm: &[f32], options: &WaveformOptions, parallel: bool) -> Vec<f32> {
+    let points = match options.mode {
+        WaveformMode::Peak => reduce_buckets(pcm, options, parallel, peak),
+        WaveformMode::Rms => reduce_buckets(pcm, options, parallel, rms),
+        WaveformMode::MinMax => reduce_buckets(pcm, options, parallel, min_max)
+            .into_iter()
+            .flatten()
+            .collect(),
+        WaveformMode::Spectral => {
+            let band = options.spectral_band.unwrap_or((0.0, 1.0));
+            reduce_buckets(pcm, options, parallel, |chunk| spectral(chunk, band))
+        }
+    };
+    match options.scale {
+        WaveformScale::Linear => points,
+        WaveformScale::Db => points
+            .into_iter()
+            .map(|point| scale_db(point, options.db_floor))
+            .collect(),
+    }
+}
+
+/// Maps `amplitude` onto `0.0..=1.0` over `floor..=0` dBFS, keeping its sign. Silence and
+/// anything quieter than `floor` map to `0.0` rather than `-inf`.
+fn scale_db(amplitude: f32, floor: f32) -> f32 {
+    let magnitude = amplitude.abs();
+    if magnitude == 0.0 {
+        return 0.0;
+    }
+    let db = 20.0 * magnitude.log10();
+    ((db - floor) / -floor).clamp(0.0, 1.0).copysign(amplitude)
+}
+
+fn reduce_buckets<T: Send>(
+    pcm: &[f32],
+    options: &WaveformOptions,
+    parallel: bool,
+    reduce: impl Fn(&[f32]) -> T + Send + Sync,
+) -> Vec<T> {
+    match (options.num_points, parallel) {
+        (Some(num_points), false) => fixed_buckets(pcm, num_points).map(reduce).collect(),
+        (Some(num_points), true) => (0..num_points)
+            .into_par_iter()
+            .map(|i| reduce(fixed_bucket(pcm, num_points, i)))
+            .collect(),
+        (None, parallel) if options.overlap > 0.0 => {
+            let (window, step) = (options.chunk_size, options.step_size());
+            let count = pcm.len().div_ceil(step);
+            if parallel {
+                (0..count)
+                    .into_par_iter()
+                    .map(|i| reduce(sliding_window(pcm, window, step, i)))
+                    .collect()
+            } else {
+                (0..count)
+                    .map(|i| reduce(sliding_window(pcm, window, step, i)))
+                    .collect()
+            }
+        }
+        (None, false) => pcm.chunks(options.chunk_size).map(reduce).collect(),
+        (None, true) => pcm.par_chunks(options.chunk_size).map(reduce).collect(),
+    }
+}
+
+/// The `index`th window of `window` samples starting every `step` samples, cut short at the
+/// end of `pcm`.
+fn sliding_window(pcm: &[f32], window: usize, step: usize, index: usize) -> &[f32] {
+    let start = index * step;
+    &pcm[start..(start + window).min(pcm.len())]
+}
+
+/// Computes the waveform points as they will be packed: `u8` points are already quantized
+/// to whole values in `0.0..=255.0`, and the result is padded or cut to `pad_to`.
+fn compute_waveform_values(pcm: &[f32], options: &WaveformOptions) -> Vec<f32> {
+    options.fit_to_length(quantized_points(pcm, options))
+}
+
+fn quantized_points(pcm: &[f32], options: &WaveformOptions) -> Vec<f32> {
+    quantize(options.hold_peaks(all_points(pcm, options)), options.format)
+}
+
+fn all_points(pcm: &[f32], options: &WaveformOptions) -> Vec<f32> {
+    compute_points(pcm, options, pcm.len() >= PARALLEL_THRESHOLD)
+}
+
+fn quantize(points: Vec<f32>, format: WaveformFormat) -> Vec<f32> {
+    match format {
+        WaveformFormat::F32 => points,
+        WaveformFormat::U8 => points
+            .into_iter()
+            .map(|point| f32::from(quantize_u8(point)))
+            .collect(),
+    }
+}
+
+/// Builds a waveform from samples that arrive in blocks. Samples are held back until a whole
+/// `chunk_size` window is available, so the result matches [`compute_waveform_values`] over the
+/// complete clip.
+struct WaveformAccumulator<'a> {
+    options: &'a WaveformOptions,
+    pending: Vec<f32>,
+    /// Points before the peak hold and quantization, which need the whole sequence.
+    points: Vec<f32>,
+}
+
+impl<'a> WaveformAccumulator<'a> {
+    fn new(options: &'a WaveformOptions) -> Self {
+        WaveformAccumulator {
+            options,
+            pending: Vec::with_capacity(options.chunk_size),
+            points: Vec::new(),
+        }
+    }
+
+    fn push(&mut self, samples: &[f32]) {
+        self.pending.extend_from_slice(samples);
+        let whole = self.pending.len() - self.pending.len() % self.options.chunk_size;
+        if whole > 0 {
+            self.points
+                .extend(all_points(&self.pending[..whole], self.options));
+            self.pending.drain(..whole);
+        }
+    }
+
+    /// Adds the point for the final, possibly shorter, window.
+    fn finish(mut self) -> Vec<f32> {
+        if !self.pending.is_empty() {
+            self.points.extend(all_points(&self.pending, self.options));
+        }
+        let points = self.options.hold_peaks(self.points);
+        self.options
+            .fit_to_length(quantize(points, self.options.format))
+    }
+}
+
+/// Packs values from [`compute_waveform_values`] into bytes and base64 encodes them, with the
+/// URL-safe unpadded alphabet when `url_safe` is set. `f32` values are always written
+/// little-endian, which is what `Float32Array` reads on every browser, whatever the host byte
+/// order.
+fn encode_waveform_base64(values: &[f32], format: WaveformFormat, url_safe: bool) -> String {
+    let bytes: Vec<u8> = match format {
+        WaveformFormat::F32 => values
+            .iter()
+            .flat_map(|value| value.to_le_bytes())
+            .collect(),
+        WaveformFormat::U8 => values.iter().map(|&value| value as u8).collect(),
+    };
+    if url_safe {
+        general_purpose::URL_SAFE_NO_PAD.encode(bytes)
+    } else {
+        general_purpose::STANDARD.encode(bytes)
+    }
+}
+
+fn compute_waveform_base64(pcm: &[f32], options: &WaveformOptions) -> String {
+    encode_waveform_base64(
+        &compute_waveform_values(pcm, options),
+        options.format,
+        options.url_safe,
+    )
+}
+
+/// Validates `options`, then decodes `source` and runs the DSP steps over the samples.
+fn decode_and_process(
+    source: Box<dyn MediaSource>,
+    options: &ProcessOptions,
+) -> Result<(DecodedAudio, DspReport), ProcessError> {
+    options.validate()?;
+    let audio = decode_to_pcm(source, &options.decode).map_err(ProcessError::Decode)?;
+    options.dsp.output_channels(audio.channels)?;
+    options.dsp.apply(audio).map_err(ProcessError::Decode)
+}
+
+/// Encodes `pcm` with whichever encoder `options` selects.
+fn encode_audio(
+    pcm: &[f32],
+    channels: usize,
+    sample_rate: u32,
+    options: &EncodeOptions,
+) -> Result<Vec<u8>, ProcessError> {
+    match (options.format, options.codec) {
+        (OutputFormat::Ogg, OutputCodec::Vorbis) => {
+            encode_to_ogg(pcm, channels, sample_rate, options)
+        }
+        (OutputFormat::Ogg, OutputCodec::Opus) => encode_to_opus(
+            pcm,
+            channels,
+            sample_rate,
+            options.opus_application,
+            options.output_capacity(pcm.len() / channels.max(1), channels, sample_rate),
+        ),
+        (OutputFormat::Wav, _) => encode_to_wav(
+            pcm,
+            channels,
+            sample_rate,
+            options.sample_format,
+            options.make_dither(),
+        ),
+        (OutputFormat::Mp3, _) => encode_to_mp3(
+            pcm,
+            channels,
+            sample_rate,
+            options.bitrate_kbps.unwrap_or(DEFAULT_MP3_BITRATE_KBPS),
+        ),
+        (OutputFormat::MuLaw, _) => encode_to_g711(pcm, channels, sample_rate, SampleFormat::MuLaw),
+        (OutputFormat::ALaw, _) => encode_to_g711(pcm, channels, sample_rate, SampleFormat::ALaw),
+        (OutputFormat::Flac, _) => encode_to_flac(
+            pcm,
+            channels,
+            sample_rate,
+            options
+                .compression_level
+                .unwrap_or(DEFAULT_FLAC_COMPRESSION_LEVEL),
+            options.make_dither(),
+        ),
+    }
+    .map_err(ProcessError::Encode)
+}
+
+/// Like [`encode_audio`], but writes the encoded audio to `file`. Only Vorbis is encoded
+/// straight into the file; the other encoders build their whole output in memory anyway.
+fn encode_audio_to_file(
+    pcm: &[f32],
+    channels: usize,
+    sample_rate: u32,
+    options: &EncodeOptions,
+    file: File,
+) -> Result<(), ProcessError> {
+    let mut output = BufWriter::new(file);
+    if (options.format, options.codec) == (OutputFormat::Ogg, OutputCodec::Vorbis) {
+        write_ogg(pcm, channels, sample_rate, options, &mut output)
+            .map_err(ProcessError::Encode)?;
+    } else {
+        let encoded = encode_audio(pcm, channels, sample_rate, options)?;
+        output
+            .write_all(&encoded)
+            .map_err(|err| ProcessError::Encode(err.into()))?;
+    }
+    output
+        .flush()
+        .map_err(|err| ProcessError::Encode(err.into()))
+}
+
+/// Where encoded Vorbis pages go while streaming: `ogg_data`, or `file` when one is given.
+fn encoded_sink<'a>(ogg_data: &'a mut Vec<u8>, file: Option<File>) -> Box<dyn Write + 'a> {
+    match file {
+        Some(file) => Box::new(BufWriter::new(file)),
+        None => Box::new(ogg_data),
+    }
+}
+
+fn process_audio(
+    source: Box<dyn MediaSource>,
+    options: &ProcessOptions,
+) -> Result<AudioResult, ProcessError> {
+    process_audio_to(source, options, None)
+}
+
+/// Like [`process_audio`], but writes the encoded audio to `output` when given, leaving
+/// `ogg_data` empty.
+fn process_audio_to(
+    source: Box<dyn MediaSource>,
+    options: &ProcessOptions,
+    output: Option<File>,
+) -> Result<AudioResult, ProcessError> {
+    if options.streaming {
+        return process_streaming(source, options, output);
+    }
+    let mut timings = StageTimings::default();
+    let (source, input) = if options.encode.passthrough_if_ogg {
+        let input = read_input(source).map_err(ProcessError::Decode)?;
+        (bytes_source(input.clone()), Some(input))
+    } else {
+        (source, None)
+    };
+    let (audio, report) = timed(&mut timings.decode, || decode_and_process(source, options))?;
+    let passthrough = input.filter(|input| is_passthrough(input, &audio, options));
+    build_result(audio, report, timings, options, output, passthrough)
+}
+
+/// Like [`process_audio_to`], writing to `path`. The output goes to a temporary file in the
+/// same directory that replaces `path` only once processing succeeds, so a failure leaves an
+/// existing file untouched rather than truncated.
+fn process_audio_to_path(
+    source: Box<dyn MediaSource>,
+    options: &ProcessOptions,
+    path: &Path,
+) -> Result<AudioResult, ProcessError> {
+    let io_error = |err: std::io::Error| ProcessError::Encode(err.into());
+    let dir = path
+        .parent()
+        .filter(|dir| !dir.as_os_str().is_empty())
+        .unwrap_or(Path::new("."));
+    let mut builder = tempfile::Builder::new();
+    // The same mode `File::create` uses, rather than the owner-only default for temporary files.
+    #[cfg(unix)]
+    builder.permissions(std::os::unix::fs::PermissionsExt::from_mode(0o666));
+    let output = builder.tempfile_in(dir).map_err(io_error)?;
+    let file = output.as_file().try_clone().map_err(io_error)?;
+    let result = process_audio_to(source, options, Some(file))?;
+    output.persist(path).map_err(|err| io_error(err.error))?;
+    Ok(result)
+}
+
+/// Reads all of `source` into memory, so it can be both decoded and handed back unchanged.
+fn read_input(mut source: Box<dyn MediaSource>) -> Result<Arc<[u8]>, BoxError> {
+    let mut input = Vec::new();
+    source.read_to_end(&mut input)?;
+    Ok(input.into())
+}
+
+/// Whether `input` can stand in for re-encoding `audio` under `passthrough_if_ogg`: it is an
+/// Ogg Vorbis stream with a single audio track, decoded in full without skipping a packet, and
+/// no DSP step changed its samples.
+fn is_passthrough(input: &Arc<[u8]>, audio: &DecodedAudio, options: &ProcessOptions) -> bool {
+    input.starts_with(b"OggS")
+        && audio.codec == "vorbis"
+        && audio.truncation_error.is_none()
+        && audio.dropped_packets == 0
+        && options.decode.start_seconds == 0.0
+        && options.decode.duration_seconds.is_none()
+        && !options.dsp.alters_audio()
+        // Only the headers are read again, to tell a multiplexed file from a plain one.
+        && list_audio_tracks(bytes_source(input.clone()), &options.decode)
+            .is_ok_and(|tracks| tracks.len() == 1)
+}
+
+/// Time spent in each stage of the pipeline.
+#[derive(Default)]
+struct StageTimings {
+    decode: Duration,
+    encode: Duration,
+    waveform: Duration,
+}
+
+impl StageTimings {
+    /// The `(decode_ms, encode_ms, waveform_ms)` reported on the result.
+    fn report(&self, options: &ProcessOptions) -> (Option<f64>, Option<f64>, Option<f64>) {
+        let ms = |duration: Duration| {
+            options
+                .collect_timings
+                .then_some(duration.as_secs_f64() * 1000.0)
+        };
+        (ms(self.decode), ms(self.encode), ms(self.waveform))
+    }
+}
+
+/// Runs `stage`, adding the time it took to `total`.
+fn timed<T>(total: &mut Duration, stage: impl FnOnce() -> T) -> T {
+    let start = Instant::now();
+    let result = stage();
+    *total += start.elapsed();
+    result
+}
+
+/// Runs the pipeline one decoded packet at a time: each block goes through the per-sample DSP
+/// steps, into the waveform and straight into the Vorbis encoder, so peak memory stays at a
+/// few packets plus the encoded output. The options must pass
+/// [`ProcessOptions::check_streamable`].
+fn process_streaming(
+    source: Box<dyn MediaSource>,
+    options: &ProcessOptions,
+    output: Option<File>,
+) -> Result<AudioResult, ProcessError> {
+    options.validate()?;
+    let dsp = &options.dsp;
+    let mut timings = StageTimings::default();
+    let mut stream = timed(&mut timings.decode, || {
+        PcmStream::open(source, &options.decode)
+    })
+    .map_err(ProcessError::Decode)?;
+    let source_channels = stream.channels;
+    let channels = dsp.output_channels(source_channels)?;
+    let mapped_channels = dsp.channel_map.as_ref().map_or(source_channels, Vec::len);
+    let sample_rate = stream.sample_rate;
+
+    let mut ogg_data = Vec::new();
+    if output.is_none() {
+        ogg_data.reserve(options.encode.output_capacity(
+            stream.expected_frames(),
+            channels,
+            sample_rate,
+        ));
+    }
+    let sink = encoded_sink(&mut ogg_data, output);
+    let mut encoder = vorbis_encoder(sink, channels, sample_rate, &options.encode)
+        .map_err(ProcessError::Encode)?;
+    let waveform_options = options.waveform.aligned(channels);
+    let mut waveform = WaveformAccumulator::new(&waveform_options);
+    let (mut peak, mut clip_count) = (0.0f32, 0);
+    let mut correlation = StereoCorrelation::new(channels);
+    let mut hasher = options
+        .pcm_hash
+        .then(|| PcmHasher::new(channels, sample_rate));
+    let mut highpass = dsp
+        .highpass_filters(mapped_channels, sample_rate)
+        .map_err(ProcessError::Decode)?;
+
+    loop {
+        let block = timed(&mut timings.decode, || {
+            let Some(samples) = stream.next_block()? else {
+                return Ok::<_, BoxError>(None);
+            };
+            let mut block = match &dsp.channel_map {
+                Some(map) => remap_channels(samples, source_channels, map),
+                None => samples.to_vec(),
+            };
+            if let Some(filters) = &mut highpass {
+                filter_interleaved(&mut block, filters);
+            }
+            if dsp.gain_db != 0.0 {
+                dsp.apply_gain(&mut block, db_to_amplitude(dsp.gain_db));
+            }
+            if dsp.mono && mapped_channels > 1 {
+                block = downmix_to_mono(&block, mapped_channels);
+            }
+            if dsp.limiter {
+                soft_limit(&mut block, LIMITER_THRESHOLD);
+            }
+            Ok(Some(block))
+        })
+        .map_err(ProcessError::Decode)?;
+        let Some(block) = block else {
+            break;
+        };
+
+        let (block_peak, block_clips) = clip_stats(&block);
+        peak = peak.max(block_peak);
+        clip_count += block_clips;
+        if let Some(correlation) = &mut correlation {
+            correlation.update(&block);
+        }
+        if let Some(hasher) = &mut hasher {
+            hasher.update(&block);
+        }
+        timed(&mut timings.waveform, || {
+            let samples = options
+                .waveform
+                .analyzed_samples(&block, channels, sample_rate)?;
+            waveform.push(&samples);
+            Ok::<_, ProcessError>(())
+        })?;
+
+        timed(&mut timings.encode, || {
+            let planar = interleaved_to_planar(&block, channels);
+            let planar_refs: Vec<&[f32]> = planar.iter().map(Vec::as_slice).collect();
+            encoder.encode_audio_block(&planar_refs)
+        })
+        .map_err(|err| ProcessError::Encode(err.into()))?;
+    }
+    timed(&mut timings.encode, || {
+        encoder.finish()?.flush()?;
+        Ok::<_, BoxError>(())
+    })
+    .map_err(ProcessError::Encode)?;
+
+    let waveform = timed(&mut timings.waveform, || waveform.finish());
+    let (waveform_min, waveform_max) = value_range(&waveform);
+    let (decode_ms, encode_ms, waveform_ms) = timings.report(options);
+    Ok(AudioResult {
+        ogg_data,
+        waveform_base64: encode_waveform_base64(
+            &waveform,
+            options.waveform.format,
+            options.waveform.url_safe,
+        ),
+        waveform_len: waveform.len(),
+        waveform_min,
+        waveform_max,
+        waveform,
+        duration_seconds: stream.duration_seconds(),
+        reported_duration_seconds: stream.reported_duration_seconds(),
+        truncation_error: stream.truncation_error,
+        sample_rate,
+        channels,
+        channel_waveforms: None,
+        timestamps: None,
+        waveforms: None,
+        preview_waveform_base64: None,
+        input_lufs: None,
+        peak,
+        clip_count,
+        stereo_correlation: correlation.and_then(|correlation| correlation.finish()),
+        trimmed_start_ms: 0.0,
+        trimmed_end_ms: 0.0,
+        dropped_packets: stream.dropped_packets,
+        codec: stream.codec,
+        bits_per_sample: stream.bits_per_sample,
+        pcm_hash: hasher.map(|hasher| hasher.finish()),
+        decode_ms,
+        encode_ms,
+        waveform_ms,
+    })
+}
+
+/// Runs the DSP steps, encoding and waveform over interleaved samples the caller already has,
+/// with no decoding involved.
+fn process_pcm(
+    pcm: Vec<f32>,
+    channels: usize,
+    sample_rate: u32,
+    options: &ProcessOptions,
+) -> Result<AudioResult, ProcessError> {
+    options.validate()?;
+    options.check_no_passthrough("from_pcm")?;
+    if options.streaming {
+        return Err(ProcessError::InvalidParameter(
+            "streaming only applies to decoded input".to_string(),
+        ));
+    }
+    if channels == 0 || sample_rate == 0 {
+        return Err(ProcessError::InvalidParameter(format!(
+            "channels and sample_rate must be greater than zero, got {channels} and {sample_rate}"
+        )));
+    }
+    if !pcm.len().is_multiple_of(channels) {
+        return Err(ProcessError::InvalidParameter(format!(
+            "the number of samples must be a multiple of channels, got {} samples for {channels} \
+             channels",
+            pcm.len()
+        )));
+    }
+    options.dsp.output_channels(channels)?;
+
+    let mut timings = StageTimings::default();
+    let start = Instant::now();
+    let audio = DecodedAudio {
+        duration_seconds: (pcm.len() / channels) as f64 / f64::from(sample_rate),
+        pcm,
+        channels,
+        sample_rate,
+        reported_duration_seconds: None,
+        truncation_error: None,
+        dropped_packets: 0,
+        codec: "pcm_f32le".to_string(),
+        bits_per_sample: Some(32),
+    };
+    let (audio, report) = options.dsp.apply(audio).map_err(ProcessError::Decode)?;
+    timings.decode = start.elapsed();
+    build_result(audio, report, timings, options, None, None)
+}
+
+/// Decodes every source, brings them to a common layout and joins them into one clip before
+/// running the DSP steps. Inputs are converted to mono or to `target_sample_rate` when those
+/// are set; any other mismatch in sample rate or channel count is an error.
+fn concat_audio(
+    sources: Vec<Box<dyn MediaSource>>,
+    options: &ProcessOptions,
+) -> Result<AudioResult, ProcessError> {
+    options.validate()?;
+    options.check_no_passthrough("concat")?;
+    if sources.is_empty() {
+        return Err(ProcessError::InvalidParameter(
+            "concat needs at least one input".to_string(),
+        ));
+    }
+
+    let mut timings = StageTimings::default();
+    let start = Instant::now();
+    let decoded = sources
+        .into_par_iter()
+        .map(|source| {
+            let mut audio = decode_to_pcm(source, &options.decode).map_err(ProcessError::Decode)?;
+            // Inputs are mapped and downmixed before joining, in the order `apply` runs them,
+            // so they can differ in layout as long as they agree after conversion.
+            options.dsp.output_channels(audio.channels)?;
+            if let Some(map) = &options.dsp.channel_map {
+                audio.pcm = remap_channels(&audio.pcm, audio.channels, map);
+                audio.channels = map.len();
+            }
+            if options.dsp.mono && audio.channels > 1 {
+                audio.pcm = downmix_to_mono(&audio.pcm, audio.channels);
+                audio.channels = 1;
+            }
+            if let Some(target_sample_rate) = options.dsp.target_sample_rate {
+                audio.pcm = resample(
+                    &audio.pcm,
+                    audio.channels,
+                    audio.sample_rate,
+                    target_sample_rate,
+                )
+                .map_err(ProcessError::Decode)?;
+                audio.sample_rate = target_sample_rate;
+            }
+            Ok(audio)
+        })
+        .collect::<Result<Vec<_>, ProcessError>>()?;
+
+    let mut parts = decoded.into_iter();
+    let mut joined = parts.next().expect("sources is not empty");
+    for (index, part) in parts.enumerate() {
+        if (part.sample_rate, part.channels) != (joined.sample_rate, joined.channels) {
+            return Err(ProcessError::InvalidParameter(format!(
+                "input {} is {} Hz with {} channels but input 0 is {} Hz with {} channels; \
+                 set target_sample_rate or mono to convert them",
+                index + 1,
+                part.sample_rate,
+                part.channels,
+                joined.sample_rate,
+                joined.channels
+            )));
+        }
+        joined.pcm.extend_from_slice(&part.pcm);
+        joined.duration_seconds += part.duration_seconds;
+        joined.reported_duration_seconds = joined
+            .reported_duration_seconds
+            .zip(part.reported_duration_seconds)
+            .map(|(joined, part)| joined + part);
+        joined.truncation_error = joined.truncation_error.or(part.truncation_error);
+        joined.dropped_packets += part.dropped_packets;
+        if part.codec != joined.codec {
+            joined.codec = "mixed".to_string();
+        }
+        if part.bits_per_sample != joined.bits_per_sample {
+            joined.bits_per_sample = None;
+        }
+    }
+
+    let dsp = DspOptions {
+        channel_map: None,
+        ..options.dsp.clone()
+    };
+    let (audio, report) = dsp.apply(joined).map_err(ProcessError::Decode)?;
+    timings.decode = start.elapsed();
+    build_result(audio, report, timings, options, None, None)
+}
+
+/// Limits an upload has to meet to pass `validate`. The maximum duration is enforced through
+/// [`DecodeOptions::max_duration_seconds`], so over-long inputs stop decoding early.
+#[derive(Default)]
+struct Constraints {
+    min_duration_seconds: Option<f64>,
+    allowed_sample_rates: Option<Vec<u32>>,
+    allowed_channels: Option<Vec<usize>>,
+}
+
+impl Constraints {
+    fn validate(&self) -> Result<(), ProcessError> {
+        if let Some(min_duration_seconds) = self.min_duration_seconds {
+            if !min_duration_seconds.is_finite() || min_duration_seconds < 0.0 {
+                return Err(ProcessError::InvalidParameter(format!(
+                    "min_duration_seconds must be a finite non-negative value, got \
+                     {min_duration_seconds}"
+                )));
+            }
+        }
+        if self
+            .allowed_sample_rates
+            .as_ref()
+            .is_some_and(|rates| rates.is_empty())
+        {
+            return Err(ProcessError::InvalidParameter(
+                "allowed_sample_rates must not be empty".to_string(),
+            ));
+        }
+        if self
+            .allowed_channels
+            .as_ref()
+            .is_some_and(|channels| channels.is_empty())
+        {
+            return Err(ProcessError::InvalidParameter(
+                "allowed_channels must not be empty".to_string(),
+            ));
+        }
+        Ok(())
+    }
+}
+
+/// Outcome of `validate`: the stream properties that could be read, and every reason the input
+/// was rejected. It passes when there are no reasons.
+struct Validation {
+    reasons: Vec<String>,
+    duration_seconds: Option<f64>,
+    sample_rate: Option<u32>,
+    channels: Option<usize>,
+    codec: Option<String>,
+}
+
+/// Decodes `source` without keeping the samples and checks it against `constraints`. The
+/// input fails when it can't be decoded, skips corrupt packets, ends early or breaks one of
+/// the limits.
+fn validate_audio(
+    source: Box<dyn MediaSource>,
+    options: &DecodeOptions,
+    constraints: &Constraints,
+) -> Result<Validation, ProcessError> {
+    options.validate()?;
+    constraints.validate()?;
+
+    let mut validation = Validation {
+        reasons: Vec::new(),
+        duration_seconds: None,
+        sample_rate: None,
+        channels: None,
+        codec: None,
+    };
+    let mut stream = match PcmStream::open(source, options) {
+        Ok(stream) => stream,
+        Err(err) => {
+            validation.reasons.push(format!("unable to decode: {err}"));
+            return Ok(validation);
+        }
+    };
+    loop {
+        match stream.next_block() {
+            Ok(Some(_)) => {}
+            Ok(None) => break,
+            Err(err) if err.is::<LimitExceeded>() => {
+                validation.reasons.push(err.to_string());
+                break;
+            }
+            Err(err) => {
+                validation.reasons.push(format!("unable to decode: {err}"));
+                break;
+            }
+        }
+    }
+
+    if stream.dropped_packets > 0 {
+        validation.reasons.push(format!(
+            "{} corrupt packets were skipped",
+            stream.dropped_packets
+        ));
+    }
+    if let Some(truncation_error) = &stream.truncation_error {
+        validation
+            .reasons
+            .push(format!("decoding stopped early: {truncation_error}"));
+    }
+    let duration_seconds = stream.duration_seconds();
+    if let Some(min_duration_seconds) = constraints.min_duration_seconds {
+        if duration_seconds < min_duration_seconds {
+            validation.reasons.push(format!(
+                "audio is shorter than min_duration_seconds ({min_duration_seconds}s)"
+            ));
+        }
+    }
+    if let Some(rates) = &constraints.allowed_sample_rates {
+        if !rates.contains(&stream.sample_rate) {
+            validation.reasons.push(format!(
+                "sample rate {} is not one of {rates:?}",
+                stream.sample_rate
+            ));
+        }
+    }
+    if let Some(allowed) = &constraints.allowed_channels {
+        if !allowed.contains(&stream.channels) {
+            validation.reasons.push(format!(
+                "channel count {} is not one of {allowed:?}",
+                stream.channels
+            ));
+        }
+    }
+
+    validation.duration_seconds = Some(duration_seconds);
+    validation.sample_rate = Some(stream.sample_rate);
+    validation.channels = Some(stream.channels);
+    validation.codec = Some(stream.codec);
+    Ok(validation)
+}
+
+/// Decodes and processes `source`, then summarizes it as a JSON object holding everything an
+/// [`AudioResult`] would except the encoded audio, which is never produced.
+fn analyze_audio(
+    source: Box<dyn MediaSource>,
+    options: &ProcessOptions,
+) -> Result<String, ProcessError> {
+    let (audio, report) = decode_and_process(source, options)?;
+    let samples =
+        options
+            .waveform
+            .analyzed_samples(&audio.pcm, audio.channels, audio.sample_rate)?;
+    let waveform_options = options.waveform.aligned(audio.channels);
+    let waveform = compute_waveform_values(&samples, &waveform_options);
+    let preview_waveform = options
+        .waveform
+        .preview()
+        .map(|preview| compute_waveform_values(&samples, &preview));
+    let timestamps = waveform_options.timestamps_for(
+        waveform.len(),
+        samples.len(),
+        audio.channels,
+        audio.sample_rate,
+    );
+    let (waveform_min, waveform_max) = value_range(&waveform);
+    let (peak, clip_count) = clip_stats(&audio.pcm);
+
+    let summary = serde_json::json!({
+        "duration_seconds": audio.duration_seconds,
+        "reported_duration_seconds": audio.reported_duration_seconds,
+        "truncated": audio.truncation_error.is_some(),
+        "truncation_error": audio.truncation_error,
+        "sample_rate": audio.sample_rate,
+        "channels": audio.channels,
+        "codec": audio.codec,
+        "bits_per_sample": audio.bits_per_sample,
+        "pcm_hash": options
+            .pcm_hash
+            .then(|| hash_pcm(&audio.pcm, audio.channels, audio.sample_rate)),
+        "waveform": waveform,
+        "waveform_min": waveform_min,
+        "waveform_max": waveform_max,
+        "timestamps": timestamps,
+        "preview_waveform": preview_waveform,
+        "peak": peak,
+        "clip_count": clip_count,
+        "stereo_correlation": stereo_correlation(&audio.pcm, audio.channels),
+        "dropped_packets": audio.dropped_packets,
+        "input_lufs": report.input_lufs,
+        "trimmed_start_ms": report.trimmed_start_ms,
+        "trimmed_end_ms": report.trimmed_end_ms,
+    });
+    Ok(summary.to_string())
+}
+
+/// Encodes the processed audio and computes its waveform and statistics.
+fn build_result(
+    audio: DecodedAudio,
+    report: DspReport,
+    mut timings: StageTimings,
+    options: &ProcessOptions,
+    output: Option<File>,
+    passthrough: Option<Arc<[u8]>>,
+) -> Result<AudioResult, ProcessError> {
+    let (pcm, channels, sample_rate) = (&audio.pcm, audio.channels, audio.sample_rate);
+
+    let ogg_data = timed(&mut timings.encode, || match (passthrough, output) {
+        (Some(input), None) => Ok(input.to_vec()),
+        (Some(input), Some(mut file)) => file
+            .write_all(&input)
+            .map(|()| Vec::new())
+            .map_err(|err| ProcessError::Encode(err.into())),
+        (None, Some(file)) => {
+            encode_audio_to_file(pcm, channels, sample_rate, &options.encode, file)
+                .map(|()| Vec::new())
+        }
+        (None, None) => encode_audio(pcm, channels, sample_rate, &options.encode),
+    })?;
+    let (
+        waveform,
+        waveform_base64,
+        timestamps,
+        waveforms,
+        preview_waveform_base64,
+        channel_waveforms,
+    ) = timed(&mut timings.waveform, || {
+        let samples = options
+            .waveform
+            .analyzed_samples(pcm, channels, sample_rate)?;
+        let waveform_options = options.waveform.aligned(channels);
+        let points = waveform_options.hold_peaks(all_points(&samples, &waveform_options));
+        let values = |format| waveform_options.fit_to_length(quantize(points.clone(), format));
+        let waveform = values(options.waveform.format);
+        let waveform_base64 = encode_waveform_base64(
+            &waveform,
+            options.waveform.format,
+            options.waveform.url_safe,
+        );
+        let timestamps =
+            waveform_options.timestamps_for(waveform.len(), samples.len(), channels, sample_rate);
+        let waveforms = options.waveform.formats.as_ref().map(|formats| {
+            formats
+                .iter()
+                .map(|&format| {
+                    let encoded =
+                        encode_waveform_base64(&values(format), format, options.waveform.url_safe);
+                    (format.name().to_string(), encoded)
+                })
+                .collect()
+        });
+        let preview_waveform_base64 = options
+            .waveform
+            .preview()
+            .map(|preview| compute_waveform_base64(&samples, &preview));
+        let channel_waveforms = options.waveform.per_channel.then(|| {
+            interleaved_to_planar(pcm, channels)
+                .iter()
+                .map(|channel| {
+                    let channel = options.waveform.weighted(channel, 1, sample_rate);
+                    compute_waveform_base64(&channel, &options.waveform)
+                })
+                .collect()
+        });
+        Ok::<_, ProcessError>((
+            waveform,
+            waveform_base64,
+            timestamps,
+            waveforms,
+            preview_waveform_base64,
+            channel_waveforms,
+        ))
+    })?;
+
+    let (peak, clip_count) = clip_stats(pcm);
+    let (waveform_min, waveform_max) = value_range(&waveform);
+    let pcm_hash = options
+        .pcm_hash
+        .then(|| hash_pcm(pcm, channels, sample_rate));
+    let (decode_ms, encode_ms, waveform_ms) = timings.report(options);
+
+    Ok(AudioResult {
+        ogg_data,
+        waveform_base64,
+        waveform_len: waveform.len(),
+        waveform_min,
+        waveform_max,
+        waveform,
+        duration_seconds: audio.duration_seconds,
+        reported_duration_seconds: audio.reported_duration_seconds,
+        truncation_error: audio.truncation_error,
+        sample_rate,
+        channels,
+        channel_waveforms,
+        timestamps,
+        waveforms,
+        preview_waveform_base64,
+        input_lufs: report.input_lufs,
+        peak,
+        clip_count,
+        stereo_correlation: stereo_correlation(pcm, channels),
+        trimmed_start_ms: report.trimmed_start_ms,
+        trimmed_end_ms: report.trimmed_end_ms,
+        dropped_packets: audio.dropped_packets,
+        codec: audio.codec,
+        bits_per_sample: audio.bits_per_sample,
+        pcm_hash,
+        decode_ms,
+        encode_ms,
+        waveform_ms,
+    })
+}
+
+#[pyfunction]
+#[pyo3(name = "generate", signature = (audio, **options))]
+fn generate_waveform_from_audio(
+    py: Python<'_>,
+    audio: AudioInput,
+    options: Option<&Bound<'_, PyDict>>,
+) -> PyResult<AudioResult> {
+    let options = ProcessOptions::from_kwargs(options)?;
+    // Everything below only touches Rust-owned data, so other Python threads can run meanwhile.
+    Ok(py.allow_threads(|| process_audio(audio.into_source(), &options))?)
+}
+
+/// Like `generate`, but streams the input from `path` instead of requiring it in memory.
+#[pyfunction]
+#[pyo3(signature = (path, **options))]
+fn generate_from_path(
+    py: Python<'_>,
+    path: PathBuf,
+    options: Option<&Bound<'_, PyDict>>,
+) -> PyResult<AudioResult> {
+    let mut options = ProcessOptions::from_kwargs(options)?;
+    if options.decode.hint.is_none() {
+        options.decode.hint = path
+            .extension()
+            .and_then(|extension| extension.to_str())
+            .map(str::to_string);
+    }
+    let file = File::open(path)?;
+    Ok(py.allow_threads(|| process_audio(Box::new(file), &options))?)
+}
+
+/// Like `generate`, but writes the encoded audio to `output_path` instead of returning it, so
+/// the result's `ogg_data` is empty. An existing file is only replaced once the output is
+/// complete.
+#[pyfunction]
+#[pyo3(signature = (audio, output_path, **options))]
+fn generate_to_path(
+    py: Python<'_>,
+    audio: AudioInput,
+    output_path: PathBuf,
+    options: Option<&Bound<'_, PyDict>>,
+) -> PyResult<AudioResult> {
+    let options = ProcessOptions::from_kwargs(options)?;
+    options.validate()?;
+    Ok(py.allow_threads(|| process_audio_to_path(audio.into_source(), &options, &output_path))?)
+}
+
+/// Like `generate`, but downloads the input from `url` first. The hint is taken from the URL's
+/// file extension, or else the response's `Content-Type`. `timeout` bounds the whole request in
+/// seconds, `None` waits indefinitely. The body is held in memory, so a response longer than
+/// `max_bytes` is abandoned, or never with `None`. Any failure to fetch the audio raises
+/// `NetworkError` rather than a decode error.
+#[cfg(feature = "reqwest")]
+#[pyfunction]
+#[pyo3(signature = (url, timeout = Some(30.0), max_bytes = Some(DEFAULT_MAX_DOWNLOAD_BYTES), **options))]
+fn generate_from_url(
+    py: Python<'_>,
+    url: &str,
+    timeout: Option<f64>,
+    max_bytes: Option<u64>,
+    options: Option<&Bound<'_, PyDict>>,
+) -> PyResult<AudioResult> {
+    let mut options = ProcessOptions::from_kwargs(options)?;
+    options.validate()?;
+    let timeout = timeout
+        .map(|seconds| {
+            // `try_from_secs_f64` takes zero, which would time every request out at once.
+            Duration::try_from_secs_f64(seconds)
+                .ok()
+                .filter(|timeout| !timeout.is_zero())
+                .ok_or_else(|| {
+                    ProcessError::InvalidParameter(format!(
+                        "timeout must be a positive duration, got {seconds}"
+                    ))
+                })
+        })
+        .transpose()?;
+    let (input, hint) = py
+        .allow_threads(|| download(url, timeout, max_bytes))
+        .map_err(|err| NetworkError::new_err(format!("Unable to download {url}: {err}")))?;
+    if options.decode.hint.is_none() {
+        options.decode.hint = hint;
+    }
+    Ok(py.allow_threads(|| process_audio(bytes_source(input), &options))?)
+}
+
+/// Default `max_bytes` of `generate_from_url`.
+#[cfg(feature = "reqwest")]
+const DEFAULT_MAX_DOWNLOAD_BYTES: u64 = 256 << 20;
+
+/// The HTTP client shared by every download, so its connection pool and background runtime are
+/// set up once. Timeouts are set per request instead.
+#[cfg(feature = "reqwest")]
+fn http_client() -> Result<&'static reqwest::blocking::Client, BoxError> {
+    static CLIENT: std::sync::OnceLock<reqwest::blocking::Client> = std::sync::OnceLock::new();
+    if let Some(client) = CLIENT.get() {
+        return Ok(client);
+    }
+    let client = reqwest::blocking::Client::builder().timeout(None).build()?;
+    Ok(CLIENT.get_or_init(|| client))
+}
+
+/// Fetches `url` into memory, returning the body and a probe hint for it. Fails as soon as the
+/// body turns out to be longer than `max_bytes`.
+#[cfg(feature = "reqwest")]
+fn download(
+    url: &str,
+    timeout: Option<Duration>,
+    max_bytes: Option<u64>,
+) -> Result<(Vec<u8>, Option<String>), BoxError> {
+    let mut request = http_client()?.get(url);
+    if let Some(timeout) = timeout {
+        request = request.timeout(timeout);
+    }
+    let response = request.send()?.error_for_status()?;
+    let too_large = |max_bytes| format!("the response is larger than max_bytes ({max_bytes})");
+    if let Some(max_bytes) = max_bytes {
+        if response.content_length().is_some_and(|len| len > max_bytes) {
+            return Err(too_large(max_bytes).into());
+        }
+    }
+    let extension = std::path::Path::new(response.url().path())
+        .extension()
+        .and_then(|extension| extension.to_str())
+        .map(str::to_string);
+    let content_type = response
+        .headers()
+        .get(reqwest::header::CONTENT_TYPE)
+        .and_then(|value| value.to_str().ok())
+        .map(|value| value.split(';').next().unwrap_or(value).trim().to_string());
+    let mut body = Vec::new();
+    match max_bytes {
+        // One byte over the limit is enough to tell the body doesn't fit.
+        Some(max_bytes) => {
+            response.take(max_bytes + 1).read_to_end(&mut body)?;
+            if body.len() as u64 > max_bytes {
+                return Err(too_large(max_bytes).into());
+            }
+        }
+        None => {
+            let mut response = response;
+            response.read_to_end(&mut body)?;
+        }
+    }
+    Ok((body, extension.or(content_type)))
+}
+
+/// Processes every input concurrently. Each entry of the returned list is either an
+/// `AudioResult` or the `WaveformError` that input failed with, so one bad file doesn't abort
+/// the rest of the batch.
+#[pyfunction]
+#[pyo3(signature = (inputs, **options))]
+fn generate_batch(
+    py: Python<'_>,
+    inputs: Vec<AudioInput>,
+    options: Option<&Bound<'_, PyDict>>,
+) -> PyResult<Vec<PyObject>> {
+    let options = ProcessOptions::from_kwargs(options)?;
+    options.validate()?;
+
+    let results: Vec<_> = py.allow_threads(|| {
+        inputs
+            .into_par_iter()
+            .map(|audio| process_audio(audio.into_source(), &options))
+            .collect()
+    });
+
+    results
+        .into_iter()
+        .map(|result| match result {
+            Ok(result) => Ok(Py::new(py, result)?.into_any()),
+            Err(err) => Ok(PyErr::from(err).into_value(py).into_any()),
+        })
+        .collect()
+}
+
+/// Like `generate`, but skips encoding and only returns the base64 waveform and the duration in
+/// seconds.
+#[pyfunction]
+#[pyo3(signature = (audio, **options))]
+fn compute_waveform(
+    py: Python<'_>,
+    audio: AudioInput,
+    options: Option<&Bound<'_, PyDict>>,
+) -> PyResult<(String, f64)> {
+    let options = ProcessOptions::from_kwargs(options)?;
+    let result = py.allow_threads(|| {
+        let (audio, _) = decode_and_process(audio.into_source(), &options)?;
+        let samples =
+            options
+                .waveform
+                .analyzed_samples(&audio.pcm, audio.channels, audio.sample_rate)?;
+        Ok::<_, ProcessError>((
+            compute_waveform_base64(&samples, &options.waveform.aligned(audio.channels)),
+            audio.duration_seconds,
+        ))
+    })?;
+    Ok(result)
+}
+
+/// Like `generate`, but skips the waveform and only returns the encoded audio.
+#[pyfunction]
+#[pyo3(signature = (audio, **options))]
+fn transcode<'py>(
+    py: Python<'py>,
+    audio: AudioInput,
+    options: Option<&Bound<'_, PyDict>>,
+) -> PyResult<Bound<'py, PyBytes>> {
+    let options = ProcessOptions::from_kwargs(options)?;
+    options.check_no_passthrough("transcode")?;
+    let encoded = py.allow_threads(|| {
+        let (audio, _) = decode_and_process(audio.into_source(), &options)?;
+        encode_audio(
+            &audio.pcm,
+            audio.channels,
+            audio.sample_rate,
+            &options.encode,
+        )
+    })?;
+    Ok(PyBytes::new(py, &encoded))
+}
+
+/// Returns a JSON summary of `audio`: its stream properties, waveform and level statistics.
+#[pyfunction]
+#[pyo3(signature = (audio, **options))]
+fn analyze(
+    py: Python<'_>,
+    audio: AudioInput,
+    options: Option<&Bound<'_, PyDict>>,
+) -> PyResult<String> {
+    let options = ProcessOptions::from_kwargs(options)?;
+    Ok(py.allow_threads(|| analyze_audio(audio.into_source(), &options))?)
+}
+
+/// Checks that `audio` decodes cleanly and meets the given limits, without encoding it or
+/// computing a waveform. Returns a dict with `valid`, the `reasons` it was rejected for, and
+/// the `duration_seconds`, `sample_rate`, `channels` and `codec` that could be read (`None`
+/// when the input couldn't be opened). The limits are named like the matching `generate`
+/// options, so the maximum duration is `max_duration_seconds` rather than `max_duration` and
+/// the rates are `allowed_sample_rates` rather than `allowed_rates`.
+#[pyfunction]
+#[pyo3(signature = (
+    audio,
+    hint = None,
+    max_duration_seconds = None,
+    min_duration_seconds = None,
+    allowed_sample_rates = None,
+    allowed_channels = None,
+))]
+fn validate<'py>(
+    py: Python<'py>,
+    audio: AudioInput,
+    hint: Option<String>,
+    max_duration_seconds: Option<f64>,
+    min_duration_seconds: Option<f64>,
+    allowed_sample_rates: Option<Vec<u32>>,
+    allowed_channels: Option<Vec<usize>>,
+) -> PyResult<Bound<'py, PyDict>> {
+    let options = DecodeOptions {
+        hint,
+        max_duration_seconds,
+        ..DecodeOptions::default()
+    };
+    let constraints = Constraints {
+        min_duration_seconds,
+        allowed_sample_rates,
+        allowed_channels,
+    };
+    let validation =
+        py.allow_threads(|| validate_audio(audio.into_source(), &options, &constraints))?;
+    let dict = PyDict::new(py);
+    dict.set_item("valid", validation.reasons.is_empty())?;
+    dict.set_item("reasons", validation.reasons)?;
+    dict.set_item("duration_seconds", validation.duration_seconds)?;
+    dict.set_item("sample_rate", validation.sample_rate)?;
+    dict.set_item("channels", validation.channels)?;
+    dict.set_item("codec", validation.codec)?;
+    Ok(dict)
+}
+
+/// Like `generate`, but takes interleaved `float32` samples (a numpy array or little-endian
+/// bytes) instead of a file to decode.
+#[pyfunction]
+#[pyo3(signature = (samples, channels, sample_rate, **options))]
+fn from_pcm(
+    py: Python<'_>,
+    samples: PcmChunk<'_>,
+    channels: usize,
+    sample_rate: u32,
+    options: Option<&Bound<'_, PyDict>>,
+) -> PyResult<AudioResult> {
+    let options = ProcessOptions::from_kwargs(options)?;
+    let pcm = samples.to_samples()?;
+    Ok(py.allow_threads(|| process_pcm(pcm, channels, sample_rate, &options))?)
+}
+
+/// Joins `inputs` end to end into a single clip, then encodes it and computes one waveform
+/// over the whole thing.
+#[pyfunction]
+#[pyo3(signature = (inputs, **options))]
+fn concat(
+    py: Python<'_>,
+    inputs: Vec<AudioInput>,
+    options: Option<&Bound<'_, PyDict>>,
+) -> PyResult<AudioResult> {
+    let options = ProcessOptions::from_kwargs(options)?;
+    let sources = inputs.into_iter().map(AudioInput::into_source).collect();
+    Ok(py.allow_threads(|| concat_audio(sources, &options))?)
+}
+
+/// Returns the tags of `audio` as a dict; files without metadata give an empty dict. The
+/// `chapters` entry lists the chapter markers as dicts with their `start_seconds` and `title`,
+/// and is empty when the container has none.
+#[pyfunction]
+#[pyo3(signature = (audio, hint = None))]
+fn read_metadata<'py>(
+    py: Python<'py>,
+    audio: AudioInput,
+    hint: Option<String>,
+) -> PyResult<Bound<'py, PyDict>> {
+    let options = DecodeOptions {
+        hint,
+        ..DecodeOptions::default()
+    };
+    let metadata = py
+        .allow_threads(|| read_tags(audio.into_source(), &options))
+        .map_err(ProcessError::Decode)?;
+    let dict = PyDict::new(py);
+    for (key, value) in metadata.tags {
+        dict.set_item(key, value)?;
+    }
+    let chapters = metadata
+        .chapters
+        .into_iter()
+        .map(|chapter| {
+            let entry = PyDict::new(py);
+            entry.set_item("start_seconds", chapter.start_seconds)?;
+            entry.set_item("title", chapter.title)?;
+            Ok(entry)
+        })
+        .collect::<PyResult<Vec<_>>>()?;
+    dict.set_item("chapters", chapters)?;
+    Ok(dict)
+}
+
+/// Returns the duration of `audio` in seconds from its container headers, without decoding it.
+#[pyfunction]
+#[pyo3(signature = (audio, hint = None))]
+fn probe_duration(py: Python<'_>, audio: AudioInput, hint: Option<String>) -> PyResult<f64> {
+    let options = DecodeOptions {
+        hint,
+        ..DecodeOptions::default()
+    };
+    let duration = py
+        .allow_threads(|| probe_stream_duration(audio.into_source(), &options))
+        .map_err(ProcessError::Decode)?;
+    Ok(duration)
+}
+
+/// Reduces an already computed waveform to `target_points` by taking the peak of each bucket.
+#[pyfunction]
+fn resample_waveform(points: Vec<f32>, target_points: usize) -> PyResult<Vec<f32>> {
+    if target_points == 0 {
+        return Err(ProcessError::InvalidParameter(
+            "target_points must be greater than 0".to_string(),
+        )
+        .into());
+    }
+    Ok(downsample_points(&points, target_points))
+}
+
+/// Decodes `audio` and returns the interleaved samples without copying them a second time.
+#[pyfunction]
+#[pyo3(signature = (audio, hint = None))]
+fn decode_pcm<'py>(
+    py: Python<'py>,
+    audio: AudioInput,
+    hint: Option<String>,
+) -> PyResult<(Bound<'py, PyArray1<f32>>, usize, u32)> {
+    let options = DecodeOptions {
+        hint,
+        ..DecodeOptions::default()
+    };
+    let audio = py
+        .allow_threads(|| decode_to_pcm(audio.into_source(), &options))
+        .map_err(ProcessError::Decode)?;
+    Ok((
+        audio.pcm.into_pyarray(py),
+        audio.channels,
+        audio.sample_rate,
+    ))
+}
+
+/// A chunk of interleaved `f32` samples passed to `StreamingEncoder.push`: either a numpy array
+/// or raw little-endian bytes.
+enum PcmChunk<'py> {
+    Array(PyReadonlyArray1<'py, f32>),
+    Bytes(PyBackedBytes),
+}
+
+impl<'py> FromPyObject<'py> for PcmChunk<'py> {
+    fn extract_bound(ob: &Bound<'py, PyAny>) -> PyResult<Self> {
+        // Bytes are checked first so they keep working when numpy isn't installed.
+        if let Ok(bytes) = ob.extract() {
+            return Ok(PcmChunk::Bytes(bytes));
+        }
+        if let Ok(array) = ob.extract() {
+            return Ok(PcmChunk::Array(array));
+        }
+        Err(PyTypeError::new_err(format!(
+            "expected a float32 numpy array or bytes, got {}",
+            ob.get_type().name()?
+        )))
+    }
+}
+
+impl PcmChunk<'_> {
+    fn to_samples(&self) -> Result<Vec<f32>, ProcessError> {
+        match self {
+            PcmChunk::Array(array) => Ok(array.as_array().iter().copied().collect()),
+            PcmChunk::Bytes(bytes) => {
+                if !bytes.len().is_multiple_of(4) {
+                    return Err(ProcessError::InvalidParameter(format!(
+                        "PCM bytes must hold whole f32 samples, got {} bytes",
+                        bytes.len()
+                    )));
+                }
+                Ok(bytes
+                    .chunks_exact(4)
+                    .map(|sample| f32::from_le_bytes(sample.try_into().unwrap()))
+                    .collect())
+            }
+        }
+    }
+}
+
+/// Output buffer shared between a `StreamingEncoder` and the Vorbis encoder writing into it, so
+/// the bytes written so far can be drained between calls.
+#[derive(Clone, Default)]
+struct SharedBuffer(Rc<RefCell<Vec<u8>>>);
+
+impl SharedBuffer {
+    fn take(&self) -> Vec<u8> {
+        std::mem::take(&mut self.0.borrow_mut())
+    }
+}
+
+impl Write for SharedBuffer {
+    fn write(&mut self, buf: &[u8]) -> std::io::Result<usize> {
+        self.0.borrow_mut().extend_from_slice(buf);
+        Ok(buf.len())
+    }
+
+    fn flush(&mut self) -> std::io::Result<()> {
+        Ok(())
+    }
+}
+
+/// Encodes interleaved `f32` PCM to Ogg Vorbis as it arrives. Every `push` returns the Ogg bytes
+/// completed so far and `finish` returns the rest, so concatenating the results gives the whole
+/// stream. Leaving a `with` block normally finishes a stream that is still open, and `finish`
+/// then returns its last bytes; an exception leaving the block discards the stream instead.
+#[pyclass(unsendable)]
+struct StreamingEncoder {
+    encoder: Option<VorbisEncoder<SharedBuffer>>,
+    output: SharedBuffer,
+    channels: usize,
+    /// Samples of a trailing partial frame, held back until the next `push` completes it.
+    pending: Vec<f32>,
+    /// The end of a stream finished by leaving a `with` block, until `finish` collects it.
+    unread_tail: Option<Vec<u8>>,
+}
+
+impl StreamingEncoder {
+    fn open(
+        sample_rate: u32,
+        channels: usize,
+        options: &EncodeOptions,
+    ) -> Result<Self, ProcessError> {
+        options.validate()?;
+        if options.format != OutputFormat::Ogg || options.codec != OutputCodec::Vorbis {
+            return Err(ProcessError::InvalidParameter(
+                "StreamingEncoder only supports ogg/vorbis output".to_string(),
+            ));
+        }
+        if options.passthrough_if_ogg {
+            return Err(ProcessError::InvalidParameter(
+                "passthrough_if_ogg cannot be used with StreamingEncoder".to_string(),
+            ));
+        }
+        let output = SharedBuffer::default();
+        let encoder = vorbis_encoder(output.clone(), channels, sample_rate, options)
+            .map_err(ProcessError::Encode)?;
+        Ok(StreamingEncoder {
+            encoder: Some(encoder),
+            output,
+            channels,
+            pending: Vec::new(),
+            unread_tail: None,
+        })
+    }
+
+    /// Ends the stream when leaving a `with` block: a stream still open is finished and its
+    /// tail kept for `finish` when the block completed, and dropped when it raised.
+    fn close(&mut self, completed: bool) -> Result<(), ProcessError> {
+        if completed && self.encoder.is_some() {
+            self.unread_tail = Some(self.finish_stream()?);
+        } else {
+            self.encoder = None;
+            self.pending.clear();
+        }
+        Ok(())
+    }
+
+    fn encoder(&mut self) -> Result<&mut VorbisEncoder<SharedBuffer>, ProcessError> {
+        self.encoder
+            .as_mut()
+            .ok_or_else(|| ProcessError::Encode("the stream is already finished".into()))
+    }
+
+    fn push_samples(&mut self, samples: &[f32]) -> Result<Vec<u8>, ProcessError> {
+        self.encoder()?;
+        self.pending.extend_from_slice(samples);
+        let whole = self.pending.len() - self.pending.len() % self.channels;
+        if whole > 0 {
+            let planar = interleaved_to_planar(&self.pending[..whole], self.channels);
+            let planar_refs: Vec<&[f32]> = planar.iter().map(Vec::as_slice).collect();
+            self.encoder()?
+                .encode_audio_block(&planar_refs)
+                .map_err(|err| ProcessError::Encode(err.into()))?;
+            self.pending.drain(..whole);
+        }
+        Ok(self.output.take())
+    }
+
+    fn finish_stream(&mut self) -> Result<Vec<u8>, ProcessError> {
+        if self.encoder.is_none() {
+            if let Some(tail) = self.unread_tail.take() {
+                return Ok(tail);
+            }
+        }
+        self.encoder()?;
+        if !self.pending.is_empty() {
+            return Err(ProcessError::InvalidParameter(format!(
+                "{} samples are left over, which is not a whole frame of {} channels",
+                self.pending.len(),
+                self.channels
+            )));
+        }
+        let encoder = self.encoder.take().unwrap();
+        encoder
+            .finish()
+            .map_err(|err| ProcessError::Encode(err.into()))?;
+        Ok(self.output.take())
+    }
+}
+
+#[pymethods]
+impl StreamingEncoder {
+    /// Accepts the encoder keyword arguments of `generate`, e.g. `quality` or `bitrate_mode`,
+    /// and rejects the rest, which have nothing to act on here.
+    #[new]
+    #[pyo3(signature = (sample_rate, channels, **options))]
+    fn new(
+        sample_rate: u32,
+        channels: usize,
+        options: Option<&Bound<'_, PyDict>>,
+    ) -> PyResult<Self> {
+        let mut encode = EncodeOptions::default();
+        for (key, value) in options.into_iter().flatten() {
+            let key: String = key.extract()?;
+            if !encode.set_kwarg(&key, &value)? {
+                return Err(ProcessError::InvalidParameter(format!(
+                    "StreamingEncoder only takes encoder options, got {key:?}"
+                ))
+                .into());
+            }
+        }
+        Ok(StreamingEncoder::open(sample_rate, channels, &encode)?)
+    }
+
+    fn push<'py>(
+        &mut self,
+        py: Python<'py>,
+        chunk: PcmChunk<'py>,
+    ) -> PyResult<Bound<'py, PyBytes>> {
+        let samples = chunk.to_samples()?;
+        Ok(PyBytes::new(py, &self.push_samples(&samples)?))
+    }
+
+    fn finish<'py>(&mut self, py: Python<'py>) -> PyResult<Bound<'py, PyBytes>> {
+        Ok(PyBytes::new(py, &self.finish_stream()?))
+    }
+
+    fn __enter__(slf: PyRef<'_, Self>) -> PyRef<'_, Self> {
+        slf
+    }
+
+    fn __exit__(
+        &mut self,
+        exc_type: &Bound<'_, PyAny>,
+        _exc_value: &Bound<'_, PyAny>,
+        _traceback: &Bound<'_, PyAny>,
+    ) -> PyResult<bool> {
+        self.close(exc_type.is_none())?;
+        Ok(false)
+    }
+}
+
+/// Holds a set of `generate` options, parsed and validated once, for decoding one input after
+/// another. Symphonia's probe and codec registries are process-wide statics, so every call
+/// shares them rather than building its own.
+#[pyclass(name = "Decoder")]
+struct AudioDecoder {
+    options: ProcessOptions,
+}
+
+#[pymethods]
+impl AudioDecoder {
+    #[new]
+    #[pyo3(signature = (**options))]
+    fn new(options: Option<&Bound<'_, PyDict>>) -> PyResult<Self> {
+        let options = ProcessOptions::from_kwargs(options)?;
+        options.validate()?;
+        Ok(AudioDecoder { options })
+    }
+
+    /// Decodes `audio`, applies the DSP options and returns the interleaved samples with the
+    /// channel count and sample rate, like `decode_pcm`.
+    fn decode<'py>(
+        &self,
+        py: Python<'py>,
+        audio: AudioInput,
+    ) -> PyResult<(Bound<'py, PyArray1<f32>>, usize, u32)> {
+        let (audio, _) =
+            py.allow_threads(|| decode_and_process(audio.into_source(), &self.options))?;
+        Ok((
+            audio.pcm.into_pyarray(py),
+            audio.channels,
+            audio.sample_rate,
+        ))
+    }
+
+    /// Same as calling `generate` with this decoder's options.
+    fn generate(&self, py: Python<'_>, audio: AudioInput) -> PyResult<AudioResult> {
+        Ok(py.allow_threads(|| process_audio(audio.into_source(), &self.options))?)
+    }
+}
+
+/// Lists the audio tracks of `audio` as dicts with their `index` (as taken by `track_index`),
+/// `codec`, `language`, `channels` and `sample_rate`; properties the container doesn't state are
+/// `None`.
+#[pyfunction]
+#[pyo3(signature = (audio, hint = None))]
+fn list_tracks<'py>(
+    py: Python<'py>,
+    audio: AudioInput,
+    hint: Option<String>,
+) -> PyResult<Vec<Bound<'py, PyDict>>> {
+    let options = DecodeOptions {
+        hint,
+        ..DecodeOptions::default()
+    };
+    let tracks = py
+        .allow_threads(|| list_audio_tracks(audio.into_source(), &options))
+        .map_err(ProcessError::Decode)?;
+    tracks
+        .into_iter()
+        .map(|track| {
+            let dict = PyDict::new(py);
+            dict.set_item("index", track.index)?;
+            dict.set_item("codec", track.codec)?;
+            dict.set_item("language", track.language)?;
+            dict.set_item("channels", track.channels)?;
+            dict.set_item("sample_rate", track.sample_rate)?;
+            Ok(dict)
+        })
+        .collect()
+}
+
+#[pymodule]
+pub fn waveform(m: &Bound<'_, PyModule>) -> PyResult<()> {
+    m.add("WaveformError", m.py().get_type::<WaveformError>())?;
+    m.add("DecodeError", m.py().get_type::<DecodeError>())?;
+    m.add(
+        "UnsupportedFormatError",
+        m.py().get_type::<UnsupportedFormatError>(),
+    )?;
+    m.add("EncodeError", m.py().get_type::<EncodeError>())?;
+    m.add(
+        "LimitExceededError",
+        m.py().get_type::<LimitExceededError>(),
+    )?;
+    m.add(
+        "InvalidParameterError",
+        m.py().get_type::<InvalidParameterError>(),
+    )?;
+    #[cfg(feature = "reqwest")]
+    m.add("NetworkError", m.py().get_type::<NetworkError>())?;
+    // Errors that don't come from Symphonia inherit `None`.
+    m.py()
+        .get_type::<WaveformError>()
+        .setattr("symphonia_error", m.py().None())?;
+    m.add_class::<StreamingEncoder>()?;
+    m.add_class::<AudioDecoder>()?;
+    m.add_function(wrap_pyfunction!(generate_waveform_from_audio, m)?)?;
+    m.add_function(wrap_pyfunction!(generate_from_path, m)?)?;
+    m.add_function(wrap_pyfunction!(generate_to_path, m)?)?;
+    #[cfg(feature = "reqwest")]
+    m.add_function(wrap_pyfunction!(generate_from_url, m)?)?;
+    m.add_function(wrap_pyfunction!(generate_batch, m)?)?;
+    m.add_function(wrap_pyfunction!(compute_waveform, m)?)?;
+    m.add_function(wrap_pyfunction!(transcode, m)?)?;
+    m.add_function(wrap_pyfunction!(concat, m)?)?;
+    m.add_function(wrap_pyfunction!(analyze, m)?)?;
+    m.add_function(wrap_pyfunction!(validate, m)?)?;
+    m.add_function(wrap_pyfunction!(from_pcm, m)?)?;
+    m.add_function(wrap_pyfunction!(read_metadata, m)?)?;
+    m.add_function(wrap_pyfunction!(list_tracks, m)?)?;
+    m.add_function(wrap_pyfunction!(decode_pcm, m)?)?;
+    m.add_function(wrap_pyfunction!(resample_waveform, m)?)?;
+    m.add_function(wrap_pyfunction!(probe_duration, m)?)?;
+
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use std::io::Read as _;
+    use symphonia::core::io::ReadOnlySource;
+
+    use super::*;
+
+    #[test]
+    fn test_lib() {
+        let mut file = std::fs::File::open("test.mp3").expect("Unable to open test mp3.");
+
+        let mut buf = Vec::new();
+        file.read_to_end(&mut buf)
+            .expect("Unable to read audio file into buffer");
+
+        let result = process_audio(bytes_source(buf), &ProcessOptions::default())
+            .expect("Unable to process test mp3.");
+        println!(
+            "{:#?}\n\n{:#?}",
+            result.duration_seconds, result.waveform_base64
+        );
+        // The Xing header of test.mp3 reports 3129 frames of 1152 samples at 48kHz.
+        let expected = 3129.0 * 1152.0 / 48000.0;
+        assert!((result.duration_seconds - expected).abs() < 0.05);
+    }
+
+    /// Small inputs under `fixtures/`, one per decoder, checked against `fixtures/golden.json`.
+    const GOLDEN_FIXTURES: [&str; 4] = [
+        "tone_mono.wav",
+        "tone_stereo.flac",
+        "tone_stereo.ogg",
+        "tone_mono.mp3",
+    ];
+
+    /// Run with `UPDATE_GOLDEN=1` to rewrite the goldens after an intended change in output.
+    #[test]
+    fn test_golden_fixtures() {
+        let options = ProcessOptions {
+            encode: EncodeOptions {
+                ogg_serial: Some(1),
+                ..EncodeOptions::default()
+            },
+            ..ProcessOptions::default()
+        };
+        let process = |name: &str| {
+            let input = std::fs::read(format!("fixtures/{name}")).unwrap();
+            process_audio(bytes_source(input), &options).unwrap()
+        };
+        let results: serde_json::Map<String, serde_json::Value> = GOLDEN_FIXTURES
+            .iter()
+            .map(|&name| {
+                let result = process(name);
+                // With a fixed stream serial nothing in the output is left to chance.
+                assert_eq!(process(name).ogg_data, result.ogg_data, "{name}");
+                let summary = serde_json::json!({
+                    "duration_seconds": result.duration_seconds,
+                    "waveform_base64": result.waveform_base64,
+                    "ogg_size": result.ogg_data.len(),
+                });
+                (name.to_string(), summary)
+            })
+            .collect();
+
+        let path = "fixtures/golden.json";
+        if std::env::var_os("UPDATE_GOLDEN").is_some() {
+            let json = serde_json::to_string_pretty(&results).unwrap();
+            std::fs::write(path, json + "\n").unwrap();
+            return;
+        }
+        let golden: serde_json::Value =
+            serde_json::from_str(&std::fs::read_to_string(path).unwrap()).unwrap();
+        let points = |value: &serde_json::Value| -> Vec<f32> {
+            general_purpose::STANDARD
+                .decode(value.as_str().unwrap())
+                .unwrap()
+                .chunks_exact(4)
+                .map(|bytes| f32::from_le_bytes(bytes.try_into().unwrap()))
+                .collect()
+        };
+        for (name, actual) in &results {
+            let expected = &golden[name];
+            let duration = actual["duration_seconds"].as_f64().unwrap();
+            assert!(
+                (duration - expected["duration_seconds"].as_f64().unwrap()).abs() < 1e-9,
+                "{name}: duration {duration}"
+            );
+            // Decoders may round differently between platforms, so points only need to be close.
+            let (actual_points, expected_points) = (
+                points(&actual["waveform_base64"]),
+                points(&expected["waveform_base64"]),
+            );
+            assert_eq!(actual_points.len(), expected_points.len(), "{name}");
+            for (actual, expected) in actual_points.iter().zip(&expected_points) {
+                assert!(
+                    (actual - expected).abs() < 1e-4,
+                    "{name}: {actual} != {expected}"
+                );
+            }
+            // Likewise libvorbis builds may pick slightly different bitrates.
+            let size = actual["ogg_size"].as_f64().unwrap();
+            let expected_size = expected["ogg_size"].as_f64().unwrap();
+            assert!(
+                (size - expected_size).abs() <= expected_size * 0.05,
+                "{name}: ogg size {size}, expected about {expected_size}"
+            );
+        }
+    }
+
+    #[test]
+    fn test_probe_duration() {
+        let buf = std::fs::read("test.mp3").unwrap();
+        let duration = probe_stream_duration(bytes_source(buf), &DecodeOptions::default()).unwrap();
+        assert!((duration - 3129.0 * 1152.0 / 48000.0).abs() < 0.05);
+
+        let wav = encode_to_wav(&[0.0; 8000], 2, 8000, SampleFormat::I16, None).unwrap();
+        let duration = probe_stream_duration(bytes_source(wav), &DecodeOptions::default()).unwrap();
+        assert!((duration - 0.5).abs() < 1e-9);
+    }
+
+    #[test]
+    fn test_invalid_input() {
+        let result = process_audio(
+            bytes_source(b"definitely not audio"),
+            &ProcessOptions::default(),
+        );
+        assert!(matches!(result, Err(ProcessError::Decode(_))));
+    }
+
+    #[test]
+    fn test_unsupported_format() {
+        let err = decode_to_pcm(
+            bytes_source(b"definitely not audio"),
+            &DecodeOptions::default(),
+        )
+        .err()
+        .unwrap();
+        assert!(is_unsupported(&*err));
+        assert_eq!(symphonia_error_kind(&*err), Some("Unsupported"));
+
+        let err: BoxError = "corrupt frame".into();
+        assert!(!is_unsupported(&*err));
+        assert_eq!(symphonia_error_kind(&*err), None);
+    }
+
+    #[test]
+    fn test_invalid_options() {
+        let options = ProcessOptions {
+            waveform: WaveformOptions {
+                chunk_size: 0,
+                ..Default::default()
+            },
+            ..Default::default()
+        };
+        let result = process_audio(bytes_source(b"definitely not audio"), &options);
+        assert!(matches!(result, Err(ProcessError::InvalidParameter(_))));
+
+        let options = ProcessOptions {
+            encode: EncodeOptions {
+                quality: Some(1.5),
+                ..Default::default()
+            },
+            ..Default::default()
+        };
+        let result = process_audio(bytes_source(b"definitely not audio"), &options);
+        assert!(matches!(result, Err(ProcessError::InvalidParameter(_))));
+
+        let options = ProcessOptions {
+            encode: EncodeOptions {
+                quality: Some(0.5),
+                bitrate_mode: BitrateMode::Cbr,
+                bitrate_bps: Some(128_000),
+                ..Default::default()
+            },
+            ..Default::default()
+        };
+        let result = process_audio(bytes_source(b"definitely not audio"), &options);
+        assert!(matches!(result, Err(ProcessError::InvalidParameter(_))));
+    }
+
+    #[test]
+    fn test_streaming_encoder() {
+        let pcm: Vec<f32> = (0..8000).map(|i| (i as f32 * 0.05).sin() * 0.5).collect();
+        let mut encoder = StreamingEncoder::open(8000, 2, &EncodeOptions::default()).unwrap();
+        let mut ogg = Vec::new();
+        // Odd chunk sizes split frames, which must be carried over to the next push.
+        for chunk in pcm.chunks(1001) {
+            ogg.extend(encoder.push_samples(chunk).unwrap());
+        }
+        ogg.extend(encoder.finish_stream().unwrap());
+        assert_eq!(&ogg[..4], b"OggS");
+        assert!(encoder.pending.is_empty());
+
+        let audio = decode_to_pcm(bytes_source(ogg), &DecodeOptions::default()).unwrap();
+        assert_eq!(audio.channels, 2);
+        // Vorbis pads the final block, so the decoded stream can run slightly long.
+        assert!(audio.pcm.len() >= pcm.len());
+
+        assert!(matches!(
+            encoder.push_samples(&pcm),
+            Err(ProcessError::Encode(_))
+        ));
+        assert!(matches!(
+            encoder.finish_stream(),
+            Err(ProcessError::Encode(_))
+        ));
+
+        // A partial frame at the end is an error rather than silently dropped.
+        let mut encoder = StreamingEncoder::open(8000, 2, &EncodeOptions::default()).unwrap();
+        let mut ogg = encoder.push_samples(&pcm[..1001]).unwrap();
+        assert!(matches!(
+            encoder.finish_stream(),
+            Err(ProcessError::InvalidParameter(_))
+        ));
+
+        // Closing after a completed `with` block finishes the stream for `finish` to return.
+        ogg.extend(encoder.push_samples(&pcm[1001..]).unwrap());
+        encoder.close(true).unwrap();
+        ogg.extend(encoder.finish_stream().unwrap());
+        let audio = decode_to_pcm(bytes_source(ogg), &DecodeOptions::default()).unwrap();
+        assert!(audio.pcm.len() >= pcm.len());
+        assert!(encoder.finish_stream().is_err());
+
+        let mut encoder = StreamingEncoder::open(8000, 2, &EncodeOptions::default()).unwrap();
+        encoder.push_samples(&pcm[..1001]).unwrap();
+        encoder.close(false).unwrap();
+        assert!(encoder.finish_stream().is_err());
+    }
+
+    #[test]
+    fn test_bitrate_modes() {
+        let pcm: Vec<f32> = (0..44100)
+            .map(|i| (i as f32 * 440.0 * std::f32::consts::TAU / 44100.0).sin() * 0.5)
+            .collect();
+        for (bitrate_mode, bitrate_bps) in [
+            (BitrateMode::Vbr, Some(96_000)),
+            (BitrateMode::Abr, Some(96_000)),
+            (BitrateMode::Cbr, Some(64_000)),
+        ] {
+            let options = EncodeOptions {
+                bitrate_mode,
+                bitrate_bps,
+                ..Default::default()
+            };
+            options.validate().unwrap();
+            let ogg = encode_to_ogg(&pcm, 1, 44100, &options).unwrap();
+            assert_eq!(&ogg[..4], b"OggS");
+        }
+
+        let missing = EncodeOptions {
+            bitrate_mode: BitrateMode::Abr,
+            ..Default::default()
+        };
+        assert!(matches!(
+            missing.validate(),
+            Err(ProcessError::InvalidParameter(_))
+        ));
+        assert!("constant".parse::<BitrateMode>().is_err());
+    }
+
+    #[test]
+    fn test_fixed_buckets() {
+        let pcm: Vec<f32> = (0..1003).map(|i| i as f32).collect();
+        let sizes: Vec<usize> = fixed_buckets(&pcm, 10).map(<[f32]>::len).collect();
+        assert_eq!(sizes.len(), 10);
+        assert_eq!(sizes.iter().sum::<usize>(), pcm.len());
+        assert!(sizes.iter().all(|&size| size == 100 || size == 101));
+
+        let short = [0.5f32; 3];
+        assert_eq!(fixed_buckets(&short, 8).count(), 8);
+    }
+
+    #[test]
+    fn test_spectral() {
+        // A full-scale sine at an eighth of the sample rate, i.e. a quarter of Nyquist.
+        let pcm: Vec<f32> = (0..4096)
+            .map(|i| (i as f32 * std::f32::consts::TAU / 8.0).sin())
+            .collect();
+        let points = |spectral_band| {
+            let options = WaveformOptions {
+                mode: WaveformMode::Spectral,
+                spectral_band,
+                ..Default::default()
+            };
+            options.validate().unwrap();
+            compute_points(&pcm, &options, false)
+        };
+
+        let full = points(None);
+        assert_eq!(full.len(), 4);
+        for point in &full {
+            assert!((point - std::f32::consts::FRAC_1_SQRT_2).abs() < 1e-3);
+        }
+        for point in points(Some((0.2, 0.3))) {
+            assert!((point - std::f32::consts::FRAC_1_SQRT_2).abs() < 1e-3);
+        }
+        for point in points(Some((0.5, 1.0))) {
+            assert!(point < 1e-3);
+        }
+        assert_eq!(spectral(&[], (0.0, 1.0)), 0.0);
+        assert_eq!(spectral(&[1.0], (0.0, 1.0)), 0.0);
+
+        // Stereo audio is downmixed first, so interleaving doesn't fold the spectrum.
+        let options = WaveformOptions {
+            mode: WaveformMode::Spectral,
+            ..Default::default()
+        };
+        let stereo: Vec<f32> = pcm.iter().flat_map(|&sample| [sample, sample]).collect();
+        assert_eq!(
+            options.analyzed_samples(&stereo, 2, 8000).unwrap().as_ref(),
+            pcm
+        );
+
+        for spectral_band in [(0.5, 0.5), (-0.1, 0.5), (0.5, 1.5)] {
+            let options = WaveformOptions {
+                mode: WaveformMode::Spectral,
+                spectral_band: Some(spectral_band),
+                ..Default::default()
+            };
+            assert!(options.validate().is_err());
+        }
+        let options = WaveformOptions {
+            spectral_band: Some((0.0, 0.5)),
+            ..Default::default()
+        };
+        assert!(options.validate().is_err());
+    }
+
+    #[test]
+    fn test_a_weighting() {
+        let sample_rate = 48000;
+        let tone = |hz: f32| -> Vec<f32> {
+            (0..sample_rate)
+                .map(|i| (i as f32 * std::f32::consts::TAU * hz / sample_rate as f32).sin())
+                .collect()
+        };
+        // Level in dB of the weighted tone relative to the flat one, skipping the filter's
+        // settling time.
+        let response_db = |hz: f32| {
+            let pcm = tone(hz);
+            let weighted = a_weight(&pcm, 1, sample_rate);
+            let settled = sample_rate as usize / 2;
+            20.0 * (rms(&weighted[settled..]) / rms(&pcm[settled..])).log10()
+        };
+        // Reference values of the IEC 61672 curve.
+        for (hz, expected_db) in [(100.0, -19.1), (1000.0, 0.0), (4000.0, 1.0)] {
+            let db = response_db(hz);
+            assert!((db - expected_db).abs() < 0.2, "{hz} Hz: {db} dB");
+        }
+
+        let options = WaveformOptions {
+            mode: WaveformMode::Rms,
+            weighting: Weighting::A,
+            ..Default::default()
+        };
+        // Each interleaved channel keeps its own filter state.
+        let stereo: Vec<f32> = tone(1000.0).iter().flat_map(|&s| [s, -s]).collect();
+        let weighted = options.analyzed_samples(&stereo, 2, sample_rate).unwrap();
+        for frame in weighted.chunks_exact(2) {
+            assert!((frame[0] + frame[1]).abs() < 1e-6);
+        }
+        assert!("c".parse::<Weighting>().is_err());
+    }
+
+    #[test]
+    fn test_parallel_matches_serial() {
+        // A cheap deterministic generator is enough to give every chunk a distinct envelope.
+        let mut state = 0x2545_f491u32;
+        let pcm: Vec<f32> = (0..PARALLEL_THRESHOLD * 2 + 123)
+            .map(|_| {
+                state ^= state << 13;
+                state ^= state >> 17;
+                state ^= state << 5;
+                state as f32 / u32::MAX as f32 * 2.0 - 1.0
+            })
+            .collect();
+
+        for mode in [
+            WaveformMode::Peak,
+            WaveformMode::Rms,
+            WaveformMode::MinMax,
+            WaveformMode::Spectral,
+        ] {
+            for num_points in [None, Some(256)] {
+                let options = WaveformOptions {
+                    num_points,
+                    mode,
+                    ..Default::default()
+                };
+                // Compared bit for bit rather than with float equality.
+                let bytes = |points: Vec<f32>| -> Vec<u8> {
+                    points
+                        .iter()
+                        .flat_map(|point| point.to_le_bytes())
+                        .collect()
+                };
+                let serial = compute_points(&pcm, &options, false);
+                let parallel = compute_points(&pcm, &options, true);
+                assert_eq!(bytes(serial), bytes(parallel));
+            }
+        }
+    }
+
+    #[test]
+    fn test_overlap() {
+        let pcm = [0.1, 0.2, 0.3, 0.4, 0.5, 0.6, 0.7];
+        let options = WaveformOptions {
+            chunk_size: 4,
+            overlap: 0.5,
+            ..WaveformOptions::default()
+        };
+        assert_eq!(options.step_size(), 2);
+        let points = compute_points(&pcm, &options, false);
+        assert_eq!(points, [0.4, 0.6, 0.7, 0.7]);
+        assert_eq!(compute_points(&pcm, &options, true), points);
+
+        let no_overlap = WaveformOptions {
+            overlap: 0.0,
+            ..options
+        };
+        assert_eq!(compute_points(&pcm, &no_overlap, false), [0.4, 0.7]);
+    }
+
+    #[test]
+    fn test_downsample_points() {
+        let points = [0.1, 0.5, 0.2, -0.7, 0.3, 0.4, 0.0];
+        assert_eq!(downsample_points(&points, 3), [0.5, -0.7, 0.4]);
+        assert_eq!(downsample_points(&points, 7), points);
+        assert_eq!(downsample_points(&points, 10), points);
+        assert!(downsample_points(&[], 4).is_empty());
+    }
+
+    #[test]
+    fn test_analyzed_channel() {
+        let pcm = [0.1, -0.5, 0.2, -0.6, 0.3, -0.7];
+        let mut options = WaveformOptions::default();
+        assert_eq!(*options.analyzed_samples(&pcm, 2, 8000).unwrap(), pcm);
+        options.channel = Some(1);
+        assert_eq!(
+            *options.analyzed_samples(&pcm, 2, 8000).unwrap(),
+            [-0.5, -0.6, -0.7]
+        );
+        options.channel = Some(2);
+        assert!(matches!(
+            options.analyzed_samples(&pcm, 2, 8000),
+            Err(ProcessError::InvalidParameter(_))
+        ));
+
+        let pcm = [0.5, 0.25, -0.5, 0.5];
+        options.channel = None;
+        options.stereo_mode = StereoMode::Mid;
+        assert_eq!(
+            *options.analyzed_samples(&pcm, 2, 8000).unwrap(),
+            [0.375, 0.0]
+        );
+        options.stereo_mode = StereoMode::Side;
+        assert_eq!(
+            *options.analyzed_samples(&pcm, 2, 8000).unwrap(),
+            [0.125, -0.5]
+        );
+        assert!(matches!(
+            options.analyzed_samples(&pcm, 1, 8000),
+            Err(ProcessError::InvalidParameter(_))
+        ));
+    }
+
+    #[test]
+    fn test_pad_to() {
+        let pcm = [0.5; 10];
+        let mut options = WaveformOptions {
+            chunk_size: 2,
+            format: WaveformFormat::U8,
+            pad_to: Some(8),
+            ..WaveformOptions::default()
+        };
+        let values = compute_waveform_values(&pcm, &options);
+        assert_eq!(values.len(), 8);
+        assert_eq!(&values[5..], [0.0; 3]);
+        options.pad_to = Some(3);
+        assert_eq!(compute_waveform_values(&pcm, &options).len(), 3);
+    }
+
+    #[test]
+    fn test_decay() {
+        let pcm = [1.0, 0.0, 0.0, 0.8, 0.1];
+        let mut options = WaveformOptions {
+            chunk_size: 1,
+            decay: 0.5,
+            ..WaveformOptions::default()
+        };
+        let values = compute_waveform_values(&pcm, &options);
+        assert_eq!(values, [1.0, 0.5, 0.25, 0.8, 0.4]);
+
+        // Streamed blocks hold peaks across block boundaries too.
+        let mut accumulator = WaveformAccumulator::new(&options);
+        pcm.chunks(2).for_each(|block| accumulator.push(block));
+        assert_eq!(accumulator.finish(), values);
+
+        options.mode = WaveformMode::MinMax;
+        options.chunk_size = 2;
+        let values = compute_waveform_values(&[-1.0, 0.6, 0.0, 0.0], &options);
+        assert_eq!(values, [-1.0, 0.6, -0.5, 0.3]);
+
+        options.decay = 1.5;
+        assert!(options.validate().is_err());
+        options.decay = 0.0;
+        let values = compute_waveform_values(&[-1.0, 0.6, 0.0, 0.0], &options);
+        assert_eq!(values, [-1.0, 0.6, 0.0, 0.0]);
+    }
+
+    #[test]
+    fn test_waveform_byte_order() {
+        // 1.0 and -2.0 as little-endian f32: 00 00 80 3f 00 00 00 c0.
+        let encoded = encode_waveform_base64(&[1.0, -2.0], WaveformFormat::F32, false);
+        assert_eq!(encoded, "AACAPwAAAMA=");
+    }
+
+    #[test]
+    fn test_url_safe_waveform() {
+        let values = [251.0, 255.0];
+        assert_eq!(
+            encode_waveform_base64(&values, WaveformFormat::U8, false),
+            "+/8="
+        );
+        assert_eq!(
+            encode_waveform_base64(&values, WaveformFormat::U8, true),
+            "-_8"
+        );
+    }
+
+    #[test]
+    fn test_non_finite_samples() {
+        let pcm = [0.5, f32::NAN, -0.25, f32::INFINITY, f32::NEG_INFINITY, 0.1];
+        assert_eq!(peak(&pcm), 0.5);
+        assert_eq!(min_max(&pcm), [-0.25, 0.5]);
+        assert_eq!(min_max(&[f32::NAN]), [0.0, 0.0]);
+        assert!((rms(&pcm) - ((0.25 + 0.0625 + 0.01) / 6.0f32).sqrt()).abs() < 1e-6);
+
+        let options = WaveformOptions {
+            chunk_size: 3,
+            ..WaveformOptions::default()
+        };
+        let values = compute_waveform_values(&pcm, &options);
+        assert_eq!(values, [0.5, 0.1]);
+    }
+
+    #[test]
+    fn test_min_max_waveform() {
+        let pcm = [0.25, -0.5, 0.75, 0.1, 0.2, 0.3, -1.0];
+        let options = WaveformOptions {
+            chunk_size: 3,
+            mode: WaveformMode::MinMax,
+            ..Default::default()
+        };
+        let points = compute_points(&pcm, &options, false);
+        assert_eq!(points, [-0.5, 0.75, 0.1, 0.3, -1.0, -1.0]);
+    }
+
+    #[test]
+    fn test_planar_conversion() {
+        let pcm = [0.1, 0.2, 0.3, 0.4, 0.5, 0.6];
+        let planar = interleaved_to_planar(&pcm, 3);
+        assert_eq!(planar, [vec![0.1, 0.4], vec![0.2, 0.5], vec![0.3, 0.6]]);
+        assert_eq!(planar_to_interleaved(&planar), pcm);
+
+        // The odd trailing samples don't form a whole frame and are dropped from every channel.
+        let planar = interleaved_to_planar(&[0.1, 0.2, 0.3, 0.4, 0.5], 2);
+        assert_eq!(planar, [vec![0.1, 0.3], vec![0.2, 0.4]]);
+        assert_eq!(planar_to_interleaved(&planar), [0.1, 0.2, 0.3, 0.4]);
+
+        assert_eq!(
+            interleaved_to_planar(&[0.1], 2),
+            [Vec::<f32>::new(), Vec::new()]
+        );
+        assert_eq!(
+            planar_to_interleaved(&[vec![0.1, 0.2], vec![0.3]]),
+            [0.1, 0.3]
+        );
+        assert!(planar_to_interleaved(&[]).is_empty());
+    }
+
+    #[test]
+    fn test_downmix_to_mono() {
+        let pcm = [0.75, 0.0, 0.0, 0.5, 0.5, 0.5, -0.25, -0.5, 0.0, 1.0];
+        assert_eq!(downmix_to_mono(&pcm, 3), [0.25, 0.5, -0.25]);
+    }
+
+    #[test]
+    fn test_channel_map() {
+        let pcm = [0.1, 0.2, 0.3, 0.4, 0.5, 0.6];
+        assert_eq!(remap_channels(&pcm, 3, &[2, 0]), [0.3, 0.1, 0.6, 0.4]);
+
+        let swap = ProcessOptions {
+            dsp: DspOptions {
+                channel_map: Some(vec![1, 0]),
+                ..DspOptions::default()
+            },
+            ..ProcessOptions::default()
+        };
+        let wav =
+            encode_to_wav(&[0.5, -0.5, 0.25, -0.25], 2, 8000, SampleFormat::F32, None).unwrap();
+        let (audio, _) = decode_and_process(bytes_source(wav.clone()), &swap).unwrap();
+        assert_eq!(audio.pcm, [-0.5, 0.5, -0.25, 0.25]);
+        let left_only = DspOptions {
+            channel_map: Some(vec![0]),
+            ..DspOptions::default()
+        };
+        assert_eq!(left_only.output_channels(2).unwrap(), 1);
+        let streaming = ProcessOptions {
+            streaming: true,
+            dsp: left_only,
+            ..ProcessOptions::default()
+        };
+        let result = process_audio(bytes_source(wav.clone()), &streaming).unwrap();
+        assert_eq!((result.channels, result.peak), (1, 0.5));
+
+        let out_of_range = ProcessOptions {
+            dsp: DspOptions {
+                channel_map: Some(vec![0, 2]),
+                ..DspOptions::default()
+            },
+            ..ProcessOptions::default()
+        };
+        let err = decode_and_process(bytes_source(wav), &out_of_range)
+            .err()
+            .unwrap();
+        assert_eq!(
+            err.to_string(),
+            "Invalid parameter: channel_map index 2 is out of range for audio with 2 channels"
+        );
+        let duplicate = DspOptions {
+            channel_map: Some(vec![0, 1, 0]),
+            ..DspOptions::default()
+        };
+        assert!(matches!(
+            duplicate.validate(),
+            Err(ProcessError::InvalidParameter(_))
+        ));
+    }
+
+    #[test]
+    fn test_resample() {
+        let sine = |rate: f32, i: usize| (i as f32 * 440.0 * std::f32::consts::TAU / rate).sin();
+        let pcm: Vec<f32> = (0..44100).flat_map(|i| [sine(44100.0, i); 2]).collect();
+
+        let resampled = resample(&pcm, 2, 44100, 48000).unwrap();
+        assert_eq!(resampled.len(), 48000 * 2);
+        // Ignore the edges, where the band-limited filter rings against the hard cut-off.
+        for i in 1000..47000 {
+            assert!((resampled[i * 2] - sine(48000.0, i)).abs() < 1e-2);
+            assert_eq!(resampled[i * 2], resampled[i * 2 + 1]);
+        }
+    }
+
+    #[test]
+    fn test_highpass() {
+        let sine = |hz: f32, i: usize| (std::f32::consts::TAU * hz * i as f32 / 8000.0).sin();
+        let rms = |samples: &[f32]| {
+            (samples.iter().map(|s| s * s).sum::<f32>() / samples.len() as f32).sqrt()
+        };
+        let options = DspOptions {
+            highpass_hz: Some(50.0),
+            ..DspOptions::default()
+        };
+        // A DC offset on the left channel and a 1 kHz tone on the right.
+        let pcm = (0..8000)
+            .flat_map(|i| [0.5, 0.5 * sine(1000.0, i)])
+            .collect();
+        let (audio, _) = options.apply(decoded(pcm, 2, 8000)).unwrap();
+        let (left, right): (Vec<f32>, Vec<f32>) = audio.pcm[4000..]
+            .chunks_exact(2)
+            .map(|frame| (frame[0], frame[1]))
+            .unzip();
+        assert!(left.iter().all(|sample| sample.abs() < 1e-3));
+        assert!((rms(&right) - 0.5 / 2f32.sqrt()).abs() < 1e-3);
+
+        let filter = highpass_filter(50.0, 8000);
+        assert!((filter.gain_at(50.0, 8000) - 0.5f64.sqrt()).abs() < 1e-9);
+
+        let off = DspOptions {
+            highpass_hz: Some(0.0),
+            ..DspOptions::default()
+        };
+        let (audio, _) = off.apply(decoded(vec![0.5; 16], 1, 8000)).unwrap();
+        assert_eq!(audio.pcm, [0.5; 16]);
+        let too_high = DspOptions {
+            highpass_hz: Some(4000.0),
+            ..DspOptions::default()
+        };
+        assert!(too_high.apply(decoded(vec![0.5; 16], 1, 8000)).is_err());
+    }
+
+    #[test]
+    fn test_reverse() {
+        let mut pcm = vec![1.0, -1.0, 2.0, -2.0, 3.0, -3.0];
+        reverse_frames(&mut pcm, 2);
+        assert_eq!(pcm, [3.0, -3.0, 2.0, -2.0, 1.0, -1.0]);
+
+        let options = DspOptions {
+            reverse: true,
+            fade_in_ms: 1.0,
+            ..DspOptions::default()
+        };
+        let pcm = (0..32).flat_map(|i| [i as f32 / 32.0, -1.0]).collect();
+        let (audio, _) = options.apply(decoded(pcm, 2, 8000)).unwrap();
+        let frames: Vec<&[f32]> = audio.pcm.chunks_exact(2).collect();
+        // The fade-in lands on the new start, and the left and right samples stay paired.
+        assert_eq!(frames[0], [0.0, 0.0]);
+        assert_eq!(frames[8], [23.0 / 32.0, -1.0]);
+        assert_eq!(frames[31], [0.0, -1.0]);
+    }
+
+    #[test]
+    fn test_trim_silence() {
+        let threshold = db_to_amplitude(-40.0);
+        let mut pcm = vec![0.0, 0.001, 0.5, 0.0, 0.0, 0.0, 0.0, -0.2, 0.0, 0.0];
+        assert_eq!(trim_silence(&mut pcm, 2, threshold, 0), (1, 1));
+        assert_eq!(pcm, [0.5, 0.0, 0.0, 0.0, 0.0, -0.2]);
+
+        let mut silence = vec![0.0; 8];
+        assert_eq!(trim_silence(&mut silence, 2, threshold, 0), (4, 0));
+        assert!(silence.is_empty());
+
+        // Only runs of at least `min_frames` are removed, and never the gaps between sounds.
+        let mut pcm = vec![0.0, 0.5, 0.0, 0.0, 0.0, 0.5, 0.0, 0.0, 0.0];
+        assert_eq!(trim_silence(&mut pcm, 1, threshold, 2), (0, 3));
+        assert_eq!(pcm, [0.0, 0.5, 0.0, 0.0, 0.0, 0.5]);
+        let mut short_silence = vec![0.0; 3];
+        assert_eq!(trim_silence(&mut short_silence, 1, threshold, 4), (0, 0));
+        assert_eq!(short_silence.len(), 3);
+
+        let options = DspOptions {
+            trim_silence: true,
+            min_silence_ms: 1.0,
+            ..DspOptions::default()
+        };
+        let mut pcm = vec![0.0; 16];
+        pcm.extend([0.5; 8]);
+        pcm.extend([0.0; 4]);
+        let (audio, report) = options.apply(decoded(pcm, 1, 8000)).unwrap();
+        // 1ms is 8 frames, so the 4 trailing frames stay.
+        assert_eq!(audio.pcm.len(), 12);
+        assert_eq!((report.trimmed_start_ms, report.trimmed_end_ms), (2.0, 0.0));
+    }
+
+    #[test]
+    fn test_apply_gain() {
+        let mut pcm = [0.25, -0.25, 0.75, -0.75];
+        apply_gain(&mut pcm, db_to_amplitude(20.0 * 2f32.log10()));
+        assert_eq!(pcm, [0.5, -0.5, 1.0, -1.0]);
+    }
+
+    #[test]
+    fn test_target_lufs() {
+        let sample_rate = 48000;
+        let pcm: Vec<f32> = (0..sample_rate * 2)
+            .map(|i| 0.1 * (i as f32 * 1000.0 * std::f32::consts::TAU / sample_rate as f32).sin())
+            .collect();
+        let options = DspOptions {
+            target_lufs: Some(-16.0),
+            ..DspOptions::default()
+        };
+        let (audio, report) = options.apply(decoded(pcm, 1, sample_rate)).unwrap();
+        assert!(report.input_lufs.unwrap() < -16.0);
+        let output_lufs = integrated_loudness(&audio.pcm, 1, sample_rate)
+            .unwrap()
+            .unwrap();
+        assert!((output_lufs + 16.0).abs() < 0.1, "{output_lufs}");
+
+        // Too short to be gated: passed through untouched.
+        let (short, report) = options
+            .apply(decoded(vec![0.5; 100], 1, sample_rate))
+            .unwrap();
+        assert_eq!(report.input_lufs, None);
+        assert_eq!(short.pcm, vec![0.5; 100]);
+    }
+
+    #[test]
+    fn test_apply_fades() {
+        let mut pcm = [1.0; 12];
+        apply_fades(&mut pcm, 2, 4, 2, FadeCurve::Linear);
+        assert_eq!(
+            pcm,
+            [0.0, 0.0, 0.25, 0.25, 0.5, 0.5, 0.75, 0.75, 0.5, 0.5, 0.0, 0.0]
+        );
+
+        // Ramps longer than the clip are clamped to it.
+        let mut short = [1.0; 2];
+        apply_fades(&mut short, 1, 100, 0, FadeCurve::Sine);
+        assert_eq!(short[0], 0.0);
+        assert!((short[1] - std::f32::consts::FRAC_1_SQRT_2).abs() < 1e-6);
+
+        let mut untouched = [0.5; 4];
+        apply_fades(&mut untouched, 1, 0, 0, FadeCurve::Linear);
+        assert_eq!(untouched, [0.5; 4]);
+    }
+
+    #[test]
+    fn test_soft_limit() {
+        let mut pcm = [0.5, -0.9, 0.95, -2.0, 10.0];
+        soft_limit(&mut pcm, LIMITER_THRESHOLD);
+        assert_eq!(&pcm[..2], [0.5, -0.9]);
+        assert!(pcm[2] > 0.9 && pcm[2] < 0.95);
+        assert!(pcm[3] < -0.9 && pcm[3] >= -1.0);
+        assert!(pcm[4] > pcm[2] && pcm[4] <= 1.0);
+
+        let options = DspOptions {
+            gain_db: 20.0,
+            limiter: true,
+            ..DspOptions::default()
+        };
+        let (audio, _) = options.apply(decoded(vec![0.05, 0.5], 1, 8000)).unwrap();
+        assert!((audio.pcm[0] - 0.5).abs() < 1e-6);
+        assert!(audio.pcm[1] > 0.9 && audio.pcm[1] <= 1.0);
+    }
+
+    #[test]
+    fn test_normalize_peak() {
+        let mut pcm = [0.1, -0.25, 0.05];
+        normalize_peak(&mut pcm, 1.0);
+        assert_eq!(pcm, [0.4, -1.0, 0.2]);
+
+        let mut silence = [0.0; 4];
+        normalize_peak(&mut silence, 1.0);
+        assert_eq!(silence, [0.0; 4]);
+
+        let normalize = |normalize_target_db, limiter| {
+            let options = DspOptions {
+                normalize: true,
+                normalize_target_db,
+                limiter,
+                ..DspOptions::default()
+            };
+            let (audio, _) = options.apply(decoded(vec![0.1, -0.25], 1, 8000)).unwrap();
+            peak(&audio.pcm)
+        };
+        assert!((normalize(-6.0, false) - db_to_amplitude(-6.0)).abs() < 1e-6);
+        // Targets above full scale, or above the limiter's threshold, are capped.
+        assert_eq!(normalize(3.0, false), 1.0);
+        assert_eq!(normalize(0.0, true), LIMITER_THRESHOLD);
+
+        let without_normalize = DspOptions {
+            normalize_target_db: -3.0,
+            ..DspOptions::default()
+        };
+        assert!(without_normalize.validate().is_err());
+    }
+
+    #[test]
+    fn test_u8_waveform() {
+        let pcm = [0.0, -0.5, 1.0, 2.0];
+        let options = WaveformOptions {
+            chunk_size: 1,
+            format: WaveformFormat::U8,
+            ..Default::default()
+        };
+        let bytes = general_purpose::STANDARD
+            .decode(compute_waveform_base64(&pcm, &options))
+            .unwrap();
+        assert_eq!(bytes, [0, 128, 255, 255]);
+        assert_eq!(
+            compute_waveform_values(&pcm, &options),
+            [0.0, 128.0, 255.0, 255.0]
+        );
+    }
+
+    #[test]
+    fn test_scale_db() {
+        assert_eq!(scale_db(1.0, -60.0), 1.0);
+        assert_eq!(scale_db(0.0, -60.0), 0.0);
+        assert_eq!(scale_db(1e-6, -60.0), 0.0);
+        assert!((scale_db(0.1, -40.0) - 0.5).abs() < 1e-5);
+        assert!((scale_db(-0.1, -40.0) + 0.5).abs() < 1e-5);
+
+        let options = WaveformOptions {
+            chunk_size: 2,
+            scale: WaveformScale::Db,
+            ..WaveformOptions::default()
+        };
+        assert_eq!(
+            compute_points(&[0.0, 0.0, 1.0, -0.5], &options, false),
+            [0.0, 1.0]
+        );
+    }
+
+    #[test]
+    fn test_clip_stats() {
+        assert_eq!(clip_stats(&[]), (0.0, 0));
+        assert_eq!(clip_stats(&[0.5, -1.0, 0.9995, -0.25]), (1.0, 2));
+    }
+
+    #[test]
+    fn test_rms() {
+        assert_eq!(rms(&[]), 0.0);
+        assert_eq!(rms(&[0.5, -0.5]), 0.5);
+        assert!((rms(&[1.0, 0.0]) - std::f32::consts::FRAC_1_SQRT_2).abs() < 1e-6);
+    }
+
+    #[test]
+    fn test_opus_output() {
+        let pcm: Vec<f32> = (0..48000)
+            .map(|i| (i as f32 * 440.0 * std::f32::consts::TAU / 48000.0).sin() * 0.5)
+            .collect();
+        let ogg = encode_to_opus(&pcm, 1, 48000, OpusApplication::Audio, 0)
+            .expect("Unable to encode opus.");
+        assert!(ogg.starts_with(b"OggS"));
+        assert!(ogg.windows(8).any(|w| w == b"OpusHead"));
+
+        let err = encode_to_opus(&pcm, 1, 44100, OpusApplication::Audio, 0).unwrap_err();
+        assert!(err.to_string().contains("sample rate 44100"));
+    }
+
+    #[test]
+    fn test_opus_application() {
+        let pcm = vec![0.25; 9600];
+        let pre_skip = |application| {
+            let ogg = encode_to_opus(&pcm, 1, 48000, application, 0).unwrap();
+            let head = ogg.windows(8).position(|w| w == b"OpusHead").unwrap();
+            u16_le(&ogg, head + 10)
+        };
+        // The low-delay mode skips the speech analysis and its lookahead.
+        assert_eq!(
+            pre_skip(OpusApplication::Voip),
+            pre_skip(OpusApplication::Audio)
+        );
+        assert!(pre_skip(OpusApplication::RestrictedLowdelay) < pre_skip(OpusApplication::Audio));
+
+        let vorbis = EncodeOptions {
+            opus_application: OpusApplication::Voip,
+            ..EncodeOptions::default()
+        };
+        assert!(vorbis.validate().is_err());
+    }
+
+    fn u16_le(bytes: &[u8], i: usize) -> u16 {
+        u16::from_le_bytes([bytes[i], bytes[i + 1]])
+    }
+
+    fn u32_le(bytes: &[u8], i: usize) -> u32 {
+        u32::from_le_bytes(bytes[i..i + 4].try_into().unwrap())
+    }
+
+    /// A silent AAC-LC mono frame: one single channel element with no scale factor bands,
+    /// where every spectral coefficient is zero, followed by the END element.
+    const SILENT_AAC_FRAME: [u8; 4] = [0x01, 0x18, 0x20, 0x07];
+    /// AudioSpecificConfig for AAC-LC, 48kHz, mono.
+    const AAC_CONFIG: [u8; 2] = [0x11, 0x88];
+
+    fn adts_stream(frames: usize) -> Vec<u8> {
+        let len = 7 + SILENT_AAC_FRAME.len();
+        let header = [
+            0xFF,
+            // MPEG-4, layer 0, no CRC.
+            0xF1,
+            // AAC-LC, 48kHz, channel configuration 1 (high bit).
+            (1 << 6) | (3 << 2),
+            (1 << 6) | (len >> 11) as u8,
+            (len >> 3) as u8,
+            ((len & 7) << 5) as u8 | 0x1F,
+            0xFC,
+        ];
+        (0..frames)
+            .flat_map(|_| header.iter().chain(&SILENT_AAC_FRAME).copied())
+            .collect()
+    }
+
+    fn mp4_box(kind: &[u8; 4], body: &[u8]) -> Vec<u8> {
+        let mut out = Vec::with_capacity(8 + body.len());
+        out.extend_from_slice(&(8 + body.len() as u32).to_be_bytes());
+        out.extend_from_slice(kind);
+        out.extend_from_slice(body);
+        out
+    }
+
+    /// An `.m4a` file holding `frames` silent AAC frames in a single chunk.
+    fn m4a_file(frames: usize) -> Vec<u8> {
+        let ftyp = mp4_box(b"ftyp", b"M4A \0\0\0\0M4A mp42isom");
+        let duration = (frames * 1024) as u32;
+        let matrix: Vec<u8> = [0x10000u32, 0, 0, 0, 0x10000, 0, 0, 0, 0x4000_0000]
+            .iter()
+            .flat_map(|v| v.to_be_bytes())
+            .collect();
+        let descriptor = |tag: u8, body: &[u8]| {
+            let mut out = vec![tag, body.len() as u8];
+            out.extend_from_slice(body);
+            out
+        };
+
+        let moov = |chunk_offset: u32| {
+            let mut mvhd = vec![0; 12];
+            mvhd.extend_from_slice(&48000u32.to_be_bytes());
+            mvhd.extend_from_slice(&duration.to_be_bytes());
+            mvhd.extend_from_slice(&[0, 1, 0, 0, 1, 0]);
+            mvhd.extend_from_slice(&[0; 10]);
+            mvhd.extend_from_slice(&matrix);
+            mvhd.extend_from_slice(&[0; 24]);
+            mvhd.extend_from_slice(&2u32.to_be_bytes());
+
+            let mut tkhd = vec![0, 0, 0, 7, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 1, 0, 0, 0, 0];
+            tkhd.extend_from_slice(&duration.to_be_bytes());
+            tkhd.extend_from_slice(&[0; 12]);
+            tkhd.extend_from_slice(&[1, 0, 0, 0]);
+            tkhd.extend_from_slice(&matrix);
+            tkhd.extend_from_slice(&[0; 8]);
+
+            let mut mdhd = vec![0; 12];
+            mdhd.extend_from_slice(&48000u32.to_be_bytes());
+            mdhd.extend_from_slice(&duration.to_be_bytes());
+            mdhd.extend_from_slice(&[0x55, 0xC4, 0, 0]);
+
+            let mut hdlr = vec![0; 8];
+            hdlr.extend_from_slice(b"soun");
+            hdlr.extend_from_slice(&[0; 13]);
+
+            let url = mp4_box(b"url ", &[0, 0, 0, 1]);
+            let mut dref = vec![0, 0, 0, 0, 0, 0, 0, 1];
+            dref.extend_from_slice(&url);
+
+            let mut decoder_config = vec![0x40, 0x15, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0];
+            decoder_config.extend(descriptor(0x05, &AAC_CONFIG));
+            let mut es = vec![0, 0, 0];
+            es.extend(descriptor(0x04, &decoder_config));
+            es.extend(descriptor(0x06, &[0x02]));
+            let mut esds = vec![0; 4];
+            esds.extend(descriptor(0x03, &es));
+
+            let mut mp4a = vec![0, 0, 0, 0, 0, 0, 0, 1, 0, 0, 0, 0, 0, 0, 0, 0];
+            mp4a.extend_from_slice(&[0, 1, 0, 16, 0, 0, 0, 0]);
+            mp4a.extend_from_slice(&(48000u32 << 16).to_be_bytes());
+            mp4a.extend(mp4_box(b"esds", &esds));
+            let mut stsd = vec![0, 0, 0, 0, 0, 0, 0, 1];
+            stsd.extend(mp4_box(b"mp4a", &mp4a));
+
+            let words = |values: &[u32]| -> Vec<u8> {
+                values.iter().flat_map(|v| v.to_be_bytes()).collect()
+            };
+            let frame_len = SILENT_AAC_FRAME.len() as u32;
+            let mut stsz = words(&[0, 0, frames as u32]);
+            stsz.extend(words(&vec![frame_len; frames]));
+            let stbl = [
+                mp4_box(b"stsd", &stsd),
+                mp4_box(b"stts", &words(&[0, 1, frames as u32, 1024])),
+                mp4_box(b"stsc", &words(&[0, 1, 1, frames as u32, 1])),
+                mp4_box(b"stsz", &stsz),
+                mp4_box(b"stco", &words(&[0, 1, chunk_offset])),
+            ]
+            .concat();
+
+            let minf = [
+                mp4_box(b"smhd", &[0; 8]),
+                mp4_box(b"dinf", &mp4_box(b"dref", &dref)),
+                mp4_box(b"stbl", &stbl),
+            ]
+            .concat();
+            let mdia = [
+                mp4_box(b"mdhd", &mdhd),
+                mp4_box(b"hdlr", &hdlr),
+                mp4_box(b"minf", &minf),
+            ]
+            .concat();
+            let trak = [mp4_box(b"tkhd", &tkhd), mp4_box(b"mdia", &mdia)].concat();
+            mp4_box(
+                b"moov",
+                &[mp4_box(b"mvhd", &mvhd), mp4_box(b"trak", &trak)].concat(),
+            )
+        };
+
+        let chunk_offset = (ftyp.len() + moov(0).len() + 8) as u32;
+        let mdat: Vec<u8> = (0..frames).flat_map(|_| SILENT_AAC_FRAME).collect();
+        [ftyp, moov(chunk_offset), mp4_box(b"mdat", &mdat)].concat()
+    }
+
+    /// Wraps synthetic samples as if they had been decoded without any gaps.
+    fn decoded(pcm: Vec<f32>, channels: usize, sample_rate: u32) -> DecodedAudio {
+        let duration_seconds = pcm.len() as f64 / (channels as f64 * f64::from(sample_rate));
+        DecodedAudio {
+            pcm,
+            channels,
+            sample_rate,
+            duration_seconds,
+            reported_duration_seconds: Some(duration_seconds),
+            truncation_error: None,
+            dropped_packets: 0,
+            codec: "pcm_f32le".to_string(),
+            bits_per_sample: Some(32),
+        }
+    }
+
+    #[test]
+    fn test_decode_range() {
+        let pcm: Vec<f32> = (0..16000).map(|i| (i % 100) as f32 / 100.0).collect();
+        let wav = encode_to_wav(&pcm, 2, 8000, SampleFormat::F32, None).unwrap();
+        let options = DecodeOptions {
+            start_seconds: 0.25,
+            duration_seconds: Some(0.5),
+            ..DecodeOptions::default()
+        };
+        let audio = decode_to_pcm(bytes_source(wav), &options).unwrap();
+        assert_eq!(audio.channels, 2);
+        assert_eq!(audio.pcm, pcm[4000..12000]);
+        assert_eq!(audio.duration_seconds, 0.5);
+        assert_eq!(audio.reported_duration_seconds, Some(0.5));
+    }
+
+    /// Stands in for a codec whose packets change layout: each packet decodes to the next of
+    /// `buffers` in turn, whatever it contains.
+    struct CyclingDecoder {
+        params: symphonia::core::codecs::CodecParameters,
+        buffers: Vec<symphonia::core::audio::AudioBuffer<f32>>,
+        packets: usize,
+    }
+
+    impl CyclingDecoder {
+        /// `frames` frames per packet, each frame holding one of `layouts`' samples per channel.
+        fn boxed(layouts: &[&[f32]], frames: usize) -> Box<dyn Decoder> {
+            use symphonia::core::audio::{AudioBuffer, Channels, Signal, SignalSpec};
+            let buffers = layouts
+                .iter()
+                .map(|frame| {
+                    let channels = Channels::from_bits((1 << frame.len()) - 1).unwrap();
+                    let mut buffer =
+                        AudioBuffer::new(frames as u64, SignalSpec::new(8000, channels));
+                    buffer.render_reserved(Some(frames));
+                    for (channel, &sample) in frame.iter().enumerate() {
+                        buffer.chan_mut(channel).fill(sample);
+                    }
+                    buffer
+                })
+                .collect();
+            Box::new(CyclingDecoder {
+                params: Default::default(),
+                buffers,
+                packets: 0,
+            })
+        }
+    }
+
+    impl Decoder for CyclingDecoder {
+        fn try_new(
+            _: &symphonia::core::codecs::CodecParameters,
+            _: &symphonia::core::codecs::DecoderOptions,
+        ) -> symphonia::core::errors::Result<Self> {
+            unimplemented!()
+        }
+
+        fn supported_codecs() -> &'static [symphonia::core::codecs::CodecDescriptor] {
+            &[]
+        }
+
+        fn reset(&mut self) {}
+
+        fn codec_params(&self) -> &symphonia::core::codecs::CodecParameters {
+            &self.params
+        }
+
+        fn decode(
+            &mut self,
+            _: &symphonia::core::formats::Packet,
+        ) -> symphonia::core::errors::Result<symphonia::core::audio::AudioBufferRef<'_>> {
+            use symphonia::core::audio::AsAudioBufferRef;
+            self.packets += 1;
+            let index = (self.packets - 1) % self.buffers.len();
+            Ok(self.buffers[index].as_audio_buffer_ref())
+        }
+
+        fn finalize(&mut self) -> symphonia::core::codecs::FinalizeResult {
+            Default::default()
+        }
+
+        fn last_decoded(&self) -> symphonia::core::audio::AudioBufferRef<'_> {
+            use symphonia::core::audio::AsAudioBufferRef;
+            self.buffers[0].as_audio_buffer_ref()
+        }
+    }
+
+    #[test]
+    fn test_channel_count_change() {
+        // Takes its packets from a WAV of `channels` channels but decodes them with `layouts`.
+        let decode = |channels, layouts: &[&[f32]], strict| {
+            let wav = encode_to_wav(
+                &vec![0.0; 4000 * channels],
+                channels,
+                8000,
+                SampleFormat::F32,
+                None,
+            )
+            .unwrap();
+            let options = DecodeOptions {
+                strict,
+                ..DecodeOptions::default()
+            };
+            let mut stream = PcmStream::open(bytes_source(wav), &options)?;
+            stream.decoder = CyclingDecoder::boxed(layouts, 10);
+            // The fake packets are shorter than the WAV's, so its length no longer applies.
+            stream.total_frames = None;
+            let mut pcm = Vec::new();
+            while let Some(block) = stream.next_block()? {
+                pcm.extend_from_slice(block);
+            }
+            Ok::<_, BoxError>((pcm, stream.truncation_error))
+        };
+
+        // Mono packets in a stereo stream fill both channels instead of shifting the stride.
+        let (pcm, truncation_error) = decode(2, &[&[0.5], &[0.25, -0.25]], false).unwrap();
+        assert!(truncation_error.is_none());
+        assert_eq!(&pcm[..20], [0.5; 20]);
+        assert_eq!(&pcm[20..40], [0.25, -0.25].repeat(10));
+        // Stereo packets in a mono stream are downmixed.
+        let (pcm, _) = decode(1, &[&[0.5], &[0.25, 0.75]], false).unwrap();
+        assert_eq!(&pcm[..20], [0.5; 20]);
+
+        // Other changes end the stream, or fail it in strict mode.
+        let (pcm, truncation_error) = decode(2, &[&[0.5, 0.5], &[0.1, 0.2, 0.3]], false).unwrap();
+        assert_eq!(pcm.len(), 20);
+        assert_eq!(
+            truncation_error.unwrap(),
+            "a packet changes the channel count from 2 to 3"
+        );
+        assert!(decode(2, &[&[0.1, 0.2, 0.3]], true).is_err());
+    }
+
+    #[test]
+    fn test_mp3_duration() {
+        // The duration ffprobe reports for test.mp3, which it takes from the 3129 frames in the
+        // Xing header at 1152 samples each and 48 kHz. Neither it nor Symphonia trims the
+        // encoder delay and padding out of the duration.
+        const FFPROBE_DURATION_SECONDS: f64 = 75.096;
+        let mut mp3 = std::fs::read("test.mp3").unwrap();
+        let audio = decode_to_pcm(bytes_source(mp3.clone()), &DecodeOptions::default()).unwrap();
+        assert_eq!(audio.dropped_packets, 0);
+        let duration = audio.duration_seconds;
+        assert!(
+            (duration - FFPROBE_DURATION_SECONDS).abs() < 0.03,
+            "{duration}"
+        );
+
+        // Every 100 kB, overwrite a frame's side information but keep its header. The reader
+        // still finds the frame but the decoder has to drop the packet, which still advances
+        // the timeline by its own duration.
+        for offset in (100_000..1_200_000).step_by(100_000) {
+            let header = offset
+                + mp3[offset..]
+                    .windows(2)
+                    .position(|sync| sync == [0xff, 0xfb])
+                    .unwrap();
+            // 32 bytes for MPEG-1 stereo, right after the 4-byte header.
+            mp3[header + 4..header + 36].fill(0xff);
+        }
+        let audio = decode_to_pcm(bytes_source(mp3), &DecodeOptions::default()).unwrap();
+        assert!(audio.dropped_packets > 0);
+        let duration = audio.duration_seconds;
+        assert!(
+            (duration - FFPROBE_DURATION_SECONDS).abs() < 0.03,
+            "{duration}"
+        );
+    }
+
+    #[test]
+    fn test_reported_duration() {
+        let mut wav = encode_to_wav(&vec![0.1; 8000], 1, 8000, SampleFormat::F32, None).unwrap();
+        // Cut the data chunk short of the length its header declares.
+        wav.truncate(wav.len() - 4 * 2000);
+        let audio = decode_to_pcm(bytes_source(wav), &DecodeOptions::default()).unwrap();
+        assert_eq!(audio.duration_seconds, 0.75);
+        assert_eq!(audio.reported_duration_seconds, Some(1.0));
+        assert_eq!(
+            audio.truncation_error.unwrap(),
+            "the stream ended after 6000 of the 8000 frames its container reports"
+        );
+    }
+
+    #[test]
+    fn test_validate_audio() {
+        let wav = encode_to_wav(&vec![0.1; 16000], 2, 8000, SampleFormat::I16, None).unwrap();
+        let check = |input: Vec<u8>, max_duration_seconds, constraints: &Constraints| {
+            let options = DecodeOptions {
+                max_duration_seconds,
+                ..DecodeOptions::default()
+            };
+            validate_audio(bytes_source(input), &options, constraints).unwrap()
+        };
+
+        let valid = check(wav.clone(), Some(2.0), &Constraints::default());
+        assert!(valid.reasons.is_empty());
+        assert_eq!(
+            (valid.duration_seconds, valid.sample_rate, valid.channels),
+            (Some(1.0), Some(8000), Some(2))
+        );
+        assert_eq!(valid.codec.as_deref(), Some("pcm_s16le"));
+
+        let strict = Constraints {
+            min_duration_seconds: Some(2.0),
+            allowed_sample_rates: Some(vec![44100, 48000]),
+            allowed_channels: Some(vec![1]),
+        };
+        assert_eq!(
+            check(wav.clone(), None, &strict).reasons,
+            [
+                "audio is shorter than min_duration_seconds (2s)",
+                "sample rate 8000 is not one of [44100, 48000]",
+                "channel count 2 is not one of [1]",
+            ]
+        );
+        let too_long = check(wav.clone(), Some(0.5), &Constraints::default());
+        assert_eq!(
+            too_long.reasons,
+            ["audio is longer than max_duration_seconds (0.5s)"]
+        );
+
+        let mut truncated = wav;
+        truncated.truncate(truncated.len() - 8000);
+        let reasons = check(truncated, None, &Constraints::default()).reasons;
+        assert_eq!(reasons.len(), 1);
+        assert!(
+            reasons[0].starts_with("decoding stopped early"),
+            "{reasons:?}"
+        );
+
+        let garbage = check(vec![0x42; 256], None, &Constraints::default());
+        assert_eq!(garbage.reasons.len(), 1);
+        assert!(garbage.reasons[0].starts_with("unable to decode"));
+        assert_eq!(garbage.sample_rate, None);
+
+        let invalid = Constraints {
+            allowed_channels: Some(Vec::new()),
+            ..Constraints::default()
+        };
+        let options = DecodeOptions::default();
+        assert!(matches!(
+            validate_audio(bytes_source(Vec::new()), &options, &invalid),
+            Err(ProcessError::InvalidParameter(_))
+        ));
+    }
+
+    #[test]
+    fn test_truncated_result() {
+        let pcm = vec![0.25; 8000];
+        let complete = [
+            encode_to_wav(&pcm, 1, 8000, SampleFormat::I16, None).unwrap(),
+            encode_to_flac(&pcm, 1, 8000, 5, None).unwrap(),
+            encode_to_ogg(&pcm, 1, 8000, &EncodeOptions::default()).unwrap(),
+            m4a_file(20),
+        ];
+        for input in complete {
+            let result = process_audio(bytes_source(input), &ProcessOptions::default()).unwrap();
+            assert_eq!(result.truncation_error, None, "{}", result.codec);
+        }
+
+        let noise: Vec<f32> = (0..32000u32)
+            .map(|i| (i.wrapping_mul(2654435761) >> 16) as f32 / 65536.0 - 0.5)
+            .collect();
+        let mut flac = encode_to_flac(&noise, 1, 8000, 5, None).unwrap();
+        flac.truncate(flac.len() / 2);
+        let result = process_audio(bytes_source(flac), &ProcessOptions::default()).unwrap();
+        assert!(result.truncated());
+        assert!(result.duration_seconds > 1.0 && result.duration_seconds < 3.0);
+        assert_eq!(result.reported_duration_seconds, Some(4.0));
+        assert!(!result.waveform.is_empty());
+    }
+
+    #[test]
+    fn test_max_duration() {
+        let wav = encode_to_wav(&vec![0.1; 8000], 1, 8000, SampleFormat::F32, None).unwrap();
+        let options = DecodeOptions {
+            max_duration_seconds: Some(0.5),
+            ..DecodeOptions::default()
+        };
+        let err = decode_to_pcm(bytes_source(wav.clone()), &options)
+            .err()
+            .unwrap();
+        assert!(err.is::<LimitExceeded>());
+
+        let options = DecodeOptions {
+            max_duration_seconds: Some(1.0),
+            ..DecodeOptions::default()
+        };
+        assert!(decode_to_pcm(bytes_source(wav), &options).is_ok());
+    }
+
+    #[test]
+    fn test_aac_input() {
+        for (input, hint) in [(adts_stream(20), Some("aac")), (m4a_file(20), None)] {
+            let options = DecodeOptions {
+                hint: hint.map(str::to_string),
+                ..DecodeOptions::default()
+            };
+            let audio = decode_to_pcm(bytes_source(input), &options).unwrap();
+            assert_eq!((audio.channels, audio.sample_rate), (1, 48000));
+            assert_eq!(audio.codec, "aac");
+            assert_eq!(audio.pcm.len(), 20 * 1024);
+            assert!(audio.pcm.iter().all(|&sample| sample == 0.0));
+        }
+    }
+
+    #[test]
+    fn test_strict_decoding() {
+        let mut mp3 = std::fs::read("test.mp3").unwrap();
+        mp3.truncate(200_000);
+        // Corrupt a few spots in the middle of the stream.
+        for offset in (50_000..150_000).step_by(10_000) {
+            mp3[offset..offset + 200].fill(0xff);
+        }
+
+        let audio = decode_to_pcm(bytes_source(mp3.clone()), &DecodeOptions::default()).unwrap();
+        assert!(audio.dropped_packets > 0);
+
+        let strict = DecodeOptions {
+            strict: true,
+            ..DecodeOptions::default()
+        };
+        assert!(decode_to_pcm(bytes_source(mp3), &strict).is_err());
+    }
+
+    #[test]
+    fn test_max_consecutive_errors() {
+        let mut mp3 = std::fs::read("test.mp3").unwrap();
+        mp3.truncate(200_000);
+        // Damage most frames across the middle of the stream, leaving runs of bad packets.
+        for offset in (50_000..150_000).step_by(500) {
+            mp3[offset..offset + 200].fill(0xff);
+        }
+
+        let decode = |max_consecutive_errors| {
+            let options = DecodeOptions {
+                max_consecutive_errors,
+                ..DecodeOptions::default()
+            };
+            decode_to_pcm(bytes_source(mp3.clone()), &options)
+        };
+        let complete = decode(None).unwrap();
+        let dropped_packets = complete.dropped_packets;
+        assert!(dropped_packets > 0);
+        // Reaching the cap ends the stream but keeps what came before the run.
+        let partial = decode(Some(3)).unwrap();
+        assert!(!partial.pcm.is_empty() && partial.pcm.len() < complete.pcm.len());
+        assert!(partial
+            .truncation_error
+            .unwrap()
+            .starts_with("giving up after 3 consecutive corrupt packets: "));
+        assert_eq!(decode(Some(50)).unwrap().dropped_packets, dropped_packets);
+        let default = DecodeOptions::default().max_consecutive_errors;
+        assert_eq!(default, Some(DEFAULT_MAX_CONSECUTIVE_ERRORS));
+        assert_eq!(decode(default).unwrap().dropped_packets, dropped_packets);
+    }
+
+    #[test]
+    fn test_empty_audio() {
+        let options = EncodeOptions::default();
+        let ogg = encode_to_ogg(&[], 2, 44100, &options).unwrap();
+        assert_eq!(&ogg[..4], b"OggS");
+        let opus = encode_to_opus(&[], 2, 48000, OpusApplication::Audio, 0).unwrap();
+        assert_eq!(&opus[..4], b"OggS");
+        encode_to_mp3(&[], 2, 44100, 128).unwrap();
+        let flac = encode_to_flac(&[], 2, 44100, 5, None).unwrap();
+        assert_eq!(&flac[..4], b"fLaC");
+        let wav = encode_to_wav(&[], 2, 44100, SampleFormat::F32, None).unwrap();
+        assert_eq!(u32_le(&wav, wav.len() - 4), 0);
+
+        assert_eq!(
+            compute_waveform_base64(&[], &WaveformOptions::default()),
+            ""
+        );
+        let dsp = DspOptions {
+            gain_db: 6.0,
+            mono: true,
+            target_sample_rate: Some(48000),
+            trim_silence: true,
+            target_lufs: Some(-16.0),
+            ..DspOptions::default()
+        };
+        let (audio, report) = dsp.apply(decoded(Vec::new(), 2, 44100)).unwrap();
+        assert!(audio.pcm.is_empty());
+        assert_eq!(audio.duration_seconds, 0.0);
+        assert_eq!(
+            (audio.channels, audio.sample_rate, report.input_lufs),
+            (1, 48000, None)
+        );
+
+        // Decoding an empty WAV runs the whole pipeline on zero samples.
+        let result = process_audio(bytes_source(wav.clone()), &ProcessOptions::default()).unwrap();
+        assert_eq!(result.decode_ms, None);
+        assert_eq!(result.waveform_base64, "");
+        assert_eq!(result.waveform_len, 0);
+        assert_eq!((result.waveform_min, result.waveform_max), (0.0, 0.0));
+        assert_eq!(result.duration_seconds, 0.0);
+        assert_eq!(&result.ogg_data[..4], b"OggS");
+
+        // Silence is not empty: it keeps its length and yields zero-valued points.
+        let silence = encode_to_wav(&[0.0; 4096], 1, 8000, SampleFormat::F32, None).unwrap();
+        let options = ProcessOptions {
+            dsp: DspOptions {
+                normalize: true,
+                ..DspOptions::default()
+            },
+            ..ProcessOptions::default()
+        };
+        let result = process_audio(bytes_source(silence), &options).unwrap();
+        assert_eq!(result.duration_seconds, 4096.0 / 8000.0);
+        assert_eq!(result.peak, 0.0);
+        let points: Vec<f32> = general_purpose::STANDARD
+            .decode(&result.waveform_base64)
+            .unwrap()
+            .chunks_exact(4)
+            .map(|bytes| f32::from_le_bytes(bytes.try_into().unwrap()))
+            .collect();
+        assert_eq!(points, [0.0; 4]);
+        assert_eq!(result.waveform_len, 4);
+    }
+
+    #[test]
+    fn test_waveform_range() {
+        let wav = encode_to_wav(&[0.5, -1.0, 0.25, 0.0], 1, 8000, SampleFormat::F32, None).unwrap();
+        let range = |mode, format| {
+            let options = ProcessOptions {
+                waveform: WaveformOptions {
+                    chunk_size: 2,
+                    mode,
+                    format,
+                    ..WaveformOptions::default()
+                },
+                ..ProcessOptions::default()
+            };
+            let result = process_audio(bytes_source(wav.clone()), &options).unwrap();
+            (result.waveform_min, result.waveform_max)
+        };
+        assert_eq!(
+            range(WaveformMode::MinMax, WaveformFormat::F32),
+            (-1.0, 0.5)
+        );
+        assert_eq!(range(WaveformMode::Peak, WaveformFormat::U8), (64.0, 255.0));
+    }
+
+    #[test]
+    fn test_chunks_aligned_to_frames() {
+        let pcm = [0.1, 0.1, 0.9, 0.2, 0.2, 0.2, 0.3, 0.3, 0.3];
+        let wav = encode_to_wav(&pcm, 3, 8000, SampleFormat::F32, None).unwrap();
+        let options = ProcessOptions {
+            waveform: WaveformOptions {
+                chunk_size: 4,
+                ..WaveformOptions::default()
+            },
+            ..ProcessOptions::default()
+        };
+        // Chunks of 4 samples would mix frames; each point now covers exactly one.
+        let result = process_audio(bytes_source(wav), &options).unwrap();
+        assert_eq!(result.waveform, [0.9, 0.2, 0.3]);
+
+        let overlapping = WaveformOptions {
+            chunk_size: 10,
+            overlap: 0.5,
+            ..WaveformOptions::default()
+        };
+        let aligned = overlapping.aligned(3);
+        assert_eq!((aligned.chunk_size, aligned.step_size()), (9, 3));
+        // A derived mono signal needs no snapping.
+        let mid = WaveformOptions {
+            stereo_mode: StereoMode::Mid,
+            ..overlapping
+        };
+        assert!(matches!(mid.aligned(2), Cow::Borrowed(_)));
+    }
+
+    #[test]
+    fn test_timestamps() {
+        let wav = encode_to_wav(&[0.5; 12], 2, 8000, SampleFormat::F32, None).unwrap();
+        let timestamps = |waveform| {
+            let options = ProcessOptions {
+                waveform,
+                ..ProcessOptions::default()
+            };
+            let result = process_audio(bytes_source(wav.clone()), &options).unwrap();
+            let timestamps = result.timestamps.unwrap();
+            assert_eq!(timestamps.len(), result.waveform.len());
+            timestamps
+        };
+        let base = || WaveformOptions {
+            chunk_size: 4,
+            timestamps: true,
+            ..WaveformOptions::default()
+        };
+        // Chunks of 4 interleaved stereo samples are 2 frames, or 0.25ms at 8kHz.
+        assert_eq!(timestamps(base()), [0.0, 0.000_25, 0.000_5]);
+        // A downmixed signal has one sample per frame.
+        assert_eq!(
+            timestamps(WaveformOptions {
+                stereo_mode: StereoMode::Mid,
+                ..base()
+            }),
+            [0.0, 0.000_5]
+        );
+        assert_eq!(
+            timestamps(WaveformOptions {
+                overlap: 0.5,
+                mode: WaveformMode::MinMax,
+                pad_to: Some(4),
+                ..base()
+            }),
+            [0.0, 0.0, 0.000_125, 0.000_125]
+        );
+        assert_eq!(
+            timestamps(WaveformOptions {
+                num_points: Some(4),
+                ..base()
+            }),
+            [0.0, 0.000_187_5, 0.000_375, 0.000_562_5]
+        );
+    }
+
+    #[test]
+    fn test_waveform_formats() {
+        let wav = encode_to_wav(&[0.5, -1.0, 0.25, 0.0], 1, 8000, SampleFormat::F32, None).unwrap();
+        let options = ProcessOptions {
+            waveform: WaveformOptions {
+                chunk_size: 2,
+                formats: Some(vec![WaveformFormat::F32, WaveformFormat::U8]),
+                ..WaveformOptions::default()
+            },
+            ..ProcessOptions::default()
+        };
+        let result = process_audio(bytes_source(wav), &options).unwrap();
+        let waveforms = result.waveforms.unwrap();
+        assert_eq!(waveforms["f32"], result.waveform_base64);
+        assert_eq!(
+            waveforms["u8"],
+            encode_waveform_base64(&[255.0, 64.0], WaveformFormat::U8, false)
+        );
+
+        let minmax = WaveformOptions {
+            mode: WaveformMode::MinMax,
+            formats: Some(vec![WaveformFormat::U8]),
+            ..WaveformOptions::default()
+        };
+        assert!(minmax.validate().is_err());
+        let empty = WaveformOptions {
+            formats: Some(Vec::new()),
+            ..WaveformOptions::default()
+        };
+        assert!(empty.validate().is_err());
+    }
+
+    #[test]
+    fn test_analyze() {
+        let wav = encode_to_wav(&[0.5, -1.0, 0.25, 0.0], 2, 8000, SampleFormat::F32, None).unwrap();
+        let options = ProcessOptions {
+            waveform: WaveformOptions {
+                chunk_size: 2,
+                ..WaveformOptions::default()
+            },
+            ..ProcessOptions::default()
+        };
+        let json = analyze_audio(bytes_source(wav), &options).unwrap();
+        let summary: serde_json::Value = serde_json::from_str(&json).unwrap();
+        assert_eq!(summary["sample_rate"], 8000);
+        assert_eq!(summary["channels"], 2);
+        assert_eq!(summary["codec"], "pcm_f32le");
+        assert_eq!(summary["waveform"], serde_json::json!([1.0, 0.25]));
+        assert_eq!(summary["waveform_min"], 0.25);
+        assert_eq!(summary["waveform_max"], 1.0);
+        assert_eq!(summary["clip_count"], 1);
+        assert_eq!(summary["input_lufs"], serde_json::Value::Null);
+        assert!(summary.get("ogg_data").is_none());
+    }
+
+    #[test]
+    fn test_concat() {
+        let wav = |frames: usize, rate| {
+            bytes_source(
+                encode_to_wav(&vec![0.5; frames], 1, rate, SampleFormat::I16, None).unwrap(),
+            )
+        };
+
+        let result = concat_audio(
+            vec![wav(800, 8000), wav(400, 8000)],
+            &ProcessOptions::default(),
+        )
+        .unwrap();
+        assert_eq!((result.sample_rate, result.channels), (8000, 1));
+        assert!((result.duration_seconds - 0.15).abs() < 1e-9);
+        assert_eq!(result.codec, "pcm_s16le");
+
+        let result = concat_audio(
+            vec![wav(800, 8000), wav(800, 16000)],
+            &ProcessOptions::default(),
+        );
+        assert!(matches!(result, Err(ProcessError::InvalidParameter(_))));
+
+        let options = ProcessOptions {
+            dsp: DspOptions {
+                target_sample_rate: Some(16000),
+                ..DspOptions::default()
+            },
+            ..ProcessOptions::default()
+        };
+        let result = concat_audio(vec![wav(800, 8000), wav(800, 16000)], &options).unwrap();
+        assert_eq!(result.sample_rate, 16000);
+        assert!((result.duration_seconds - 0.15).abs() < 1e-9);
+
+        let result = concat_audio(Vec::new(), &ProcessOptions::default());
+        assert!(matches!(result, Err(ProcessError::InvalidParameter(_))));
+
+        // channel_map picks from each input before mono downmixes it, as in `generate`.
+        let stereo = || {
+            let pcm: Vec<f32> = [0.25, -0.5].repeat(400);
+            bytes_source(encode_to_wav(&pcm, 2, 8000, SampleFormat::F32, None).unwrap())
+        };
+        let options = ProcessOptions {
+            dsp: DspOptions {
+                channel_map: Some(vec![1]),
+                mono: true,
+                ..DspOptions::default()
+            },
+            ..ProcessOptions::default()
+        };
+        let result = concat_audio(vec![stereo(), stereo()], &options).unwrap();
+        let generated = process_audio(stereo(), &options).unwrap();
+        assert_eq!(result.channels, 1);
+        assert_eq!(result.peak, 0.5);
+        assert_eq!(generated.peak, 0.5);
+        assert!((result.duration_seconds - 0.1).abs() < 1e-9);
+
+        let options = ProcessOptions {
+            dsp: DspOptions {
+                channel_map: Some(vec![2]),
+                ..DspOptions::default()
+            },
+            ..ProcessOptions::default()
+        };
+        let result = concat_audio(vec![stereo()], &options);
+        assert!(matches!(result, Err(ProcessError::InvalidParameter(_))));
+    }
+
+    #[test]
+    fn test_streaming_matches_buffered() {
+        let pcm: Vec<f32> = (0..20_000)
+            .map(|i| (i as f32 * 0.01).sin() * (i as f32 / 20_000.0) * 1.5)
+            .collect();
+        let wav = encode_to_wav(&pcm, 2, 8000, SampleFormat::F32, None).unwrap();
+        let mut options = ProcessOptions {
+            dsp: DspOptions {
+                highpass_hz: Some(40.0),
+                gain_db: 3.0,
+                mono: true,
+                limiter: true,
+                ..DspOptions::default()
+            },
+            waveform: WaveformOptions {
+                chunk_size: 300,
+                ..WaveformOptions::default()
+            },
+            ..ProcessOptions::default()
+        };
+        let buffered = process_audio(bytes_source(wav.clone()), &options).unwrap();
+        options.streaming = true;
+        options.collect_timings = true;
+        let streamed = process_audio(bytes_source(wav.clone()), &options).unwrap();
+        assert!(streamed.decode_ms.is_some() && streamed.encode_ms.is_some());
+        assert!(streamed.waveform_ms.unwrap() >= 0.0);
+
+        assert_eq!(streamed.waveform, buffered.waveform);
+        assert_eq!(streamed.duration_seconds, buffered.duration_seconds);
+        assert_eq!(streamed.channels, 1);
+        assert_eq!(
+            (streamed.peak, streamed.clip_count),
+            (buffered.peak, buffered.clip_count)
+        );
+        assert_eq!(&streamed.ogg_data[..4], b"OggS");
+
+        options.dsp.normalize = true;
+        let result = process_audio(bytes_source(wav), &options);
+        assert!(matches!(result, Err(ProcessError::InvalidParameter(_))));
+    }
+
+    #[test]
+    fn test_process_to_file() {
+        let wav = encode_to_wav(&[0.25; 8000], 1, 8000, SampleFormat::F32, None).unwrap();
+        let path = std::env::temp_dir().join(format!("waveform-{}.ogg", std::process::id()));
+        let mut options = ProcessOptions::default();
+        for streaming in [false, true] {
+            options.streaming = streaming;
+            let in_memory = process_audio(bytes_source(wav.clone()), &options).unwrap();
+            let file = File::create(&path).unwrap();
+            let result = process_audio_to(bytes_source(wav.clone()), &options, Some(file)).unwrap();
+            assert!(result.ogg_data.is_empty());
+            assert_eq!(result.waveform, in_memory.waveform);
+            // Each stream gets a random serial, so compare what the file decodes to.
+            let written = std::fs::read(&path).unwrap();
+            let decoded = decode_to_pcm(bytes_source(written), &DecodeOptions::default()).unwrap();
+            let expected =
+                decode_to_pcm(bytes_source(in_memory.ogg_data), &DecodeOptions::default()).unwrap();
+            assert_eq!(decoded.pcm, expected.pcm);
+        }
+
+        options.streaming = false;
+        options.encode.format = OutputFormat::Wav;
+        let file = File::create(&path).unwrap();
+        process_audio_to(bytes_source(wav.clone()), &options, Some(file)).unwrap();
+        assert_eq!(std::fs::read(&path).unwrap(), wav);
+        std::fs::remove_file(path).unwrap();
+
+        // Writing to a path replaces an existing file only once processing succeeded.
+        let dir = tempfile::tempdir().unwrap();
+        let path = dir.path().join("output.wav");
+        std::fs::write(&path, &wav).unwrap();
+        let result = process_audio_to_path(bytes_source(b"definitely not audio"), &options, &path);
+        assert!(matches!(result, Err(ProcessError::Decode(_))));
+        assert_eq!(std::fs::read(&path).unwrap(), wav);
+        let silence = encode_to_wav(&[0.0; 800], 1, 8000, SampleFormat::F32, None).unwrap();
+        process_audio_to_path(bytes_source(silence.clone()), &options, &path).unwrap();
+        assert_eq!(std::fs::read(&path).unwrap(), silence);
+        // Neither run leaves its temporary file behind.
+        assert_eq!(std::fs::read_dir(dir.path()).unwrap().count(), 1);
+    }
+
+    #[test]
+    fn test_passthrough_if_ogg() {
+        let ogg = encode_to_ogg(&[0.25; 8000], 1, 8000, &EncodeOptions::default()).unwrap();
+        let mut options = ProcessOptions {
+            encode: EncodeOptions {
+                passthrough_if_ogg: true,
+                ..EncodeOptions::default()
+            },
+            ..ProcessOptions::default()
+        };
+        let result = process_audio(bytes_source(ogg.clone()), &options).unwrap();
+        assert_eq!(result.ogg_data, ogg);
+        assert!(result.waveform.iter().any(|&point| point > 0.2));
+
+        // Anything else is still encoded.
+        let wav = encode_to_wav(&[0.25; 8000], 1, 8000, SampleFormat::F32, None).unwrap();
+        let result = process_audio(bytes_source(wav), &options).unwrap();
+        assert_eq!(&result.ogg_data[..4], b"OggS");
+        options.dsp.gain_db = -6.0;
+        let result = process_audio(bytes_source(ogg.clone()), &options).unwrap();
+        assert_ne!(result.ogg_data, ogg);
+
+        options.dsp.gain_db = 0.0;
+
+        // A stream that ended early or skipped packets is re-encoded rather than handed back
+        // with the damage.
+        let input: Arc<[u8]> = ogg.clone().into();
+        let mut audio = decode_to_pcm(bytes_source(ogg.clone()), &options.decode).unwrap();
+        assert!(is_passthrough(&input, &audio, &options));
+        audio.dropped_packets = 1;
+        assert!(!is_passthrough(&input, &audio, &options));
+        audio.dropped_packets = 0;
+        audio.truncation_error = Some("the stream ended early".to_string());
+        assert!(!is_passthrough(&input, &audio, &options));
+
+        // And a multiplexed file, where only the selected track should come back.
+        let stereo = encode_to_ogg(&[-0.25; 16000], 2, 8000, &EncodeOptions::default()).unwrap();
+        let (mono, stereo) = (ogg_pages(&ogg), ogg_pages(&stereo));
+        let multiplexed = [
+            &mono[..1],
+            &stereo[..1],
+            &mono[1..2],
+            &stereo[1..2],
+            &mono[2..],
+            &stereo[2..],
+        ]
+        .concat()
+        .concat();
+        options.decode.track_index = Some(0);
+        let result = process_audio(bytes_source(multiplexed.clone()), &options).unwrap();
+        assert_ne!(result.ogg_data, multiplexed);
+        assert_eq!(&result.ogg_data[..4], b"OggS");
+        options.decode.track_index = None;
+
+        for function in ["transcode", "from_pcm", "concat"] {
+            let err = options.check_no_passthrough(function).err().unwrap();
+            assert!(err.to_string().contains(function));
+        }
+        let result = concat_audio(vec![bytes_source(ogg.clone())], &options);
+        assert!(matches!(result, Err(ProcessError::InvalidParameter(_))));
+        let result = process_pcm(vec![0.25; 8000], 1, 8000, &options);
+        assert!(matches!(result, Err(ProcessError::InvalidParameter(_))));
+
+        options.encode.format = OutputFormat::Wav;
+        let result = process_audio(bytes_source(ogg), &options);
+        assert!(matches!(result, Err(ProcessError::InvalidParameter(_))));
+    }
+
+    #[test]
+    fn test_pcm_hash() {
+        let pcm: Vec<f32> = (0..4000)
+            .map(|i| ((i * 37) % 2000) as f32 / 32768.0 - 0.03)
+            .collect();
+        let mut options = ProcessOptions {
+            pcm_hash: true,
+            ..ProcessOptions::default()
+        };
+        let hash = |input: Vec<u8>, options: &ProcessOptions| {
+            process_audio(bytes_source(input), options)
+                .unwrap()
+                .pcm_hash
+                .unwrap()
+        };
+        // 16-bit samples survive both containers exactly, so the hashes collide.
+        let wav = encode_to_wav(&pcm, 2, 8000, SampleFormat::I16, None).unwrap();
+        let flac = encode_to_flac(&pcm, 2, 8000, 5, None).unwrap();
+        let wav_hash = hash(wav.clone(), &options);
+        assert_eq!(wav_hash.len(), 32);
+        assert_eq!(hash(flac, &options), wav_hash);
+        // The channel layout is part of the hash, not just the samples.
+        let mono = encode_to_wav(&pcm, 1, 16000, SampleFormat::I16, None).unwrap();
+        assert_ne!(hash(mono, &options), wav_hash);
+
+        options.streaming = true;
+        assert_eq!(hash(wav.clone(), &options), wav_hash);
+        options.dsp.gain_db = -1.0;
+        assert_ne!(hash(wav, &options), wav_hash);
+    }
+
+    #[test]
+    fn test_stereo_correlation() {
+        let wave: Vec<f32> = (0..4000).map(|i| 0.5 * (i as f32 / 10.0).sin()).collect();
+        let stereo = |right: &dyn Fn(usize, f32) -> f32| -> Vec<f32> {
+            wave.iter()
+                .enumerate()
+                .flat_map(|(i, &left)| [left, right(i, left)])
+                .collect()
+        };
+        let correlation = |pcm: &[f32], channels: usize, streaming: bool| {
+            let options = ProcessOptions {
+                streaming,
+                ..ProcessOptions::default()
+            };
+            let wav = encode_to_wav(pcm, channels, 8000, SampleFormat::F32, None).unwrap();
+            process_audio(bytes_source(wav), &options)
+                .unwrap()
+                .stereo_correlation
+        };
+
+        let mono_fold = stereo(&|_, left| left);
+        assert!((correlation(&mono_fold, 2, false).unwrap() - 1.0).abs() < 1e-9);
+        let inverted = stereo(&|_, left| -left);
+        assert!((correlation(&inverted, 2, false).unwrap() + 1.0).abs() < 1e-9);
+        let quadrature = stereo(&|i, _| 0.5 * (i as f32 / 10.0).cos());
+        let buffered = correlation(&quadrature, 2, false).unwrap();
+        assert!(buffered.abs() < 0.05, "{buffered}");
+        assert_eq!(correlation(&quadrature, 2, true), Some(buffered));
+
+        assert_eq!(correlation(&stereo(&|_, _| 0.0), 2, false), None);
+        assert_eq!(correlation(&wave, 1, false), None);
+    }
+
+    #[test]
+    fn test_preview_waveform() {
+        let pcm: Vec<f32> = (0..8000).map(|i| (i % 800) as f32 / 1000.0).collect();
+        let wav = encode_to_wav(&pcm, 1, 8000, SampleFormat::F32, None).unwrap();
+        let options = ProcessOptions {
+            waveform: WaveformOptions {
+                chunk_size: 100,
+                pad_to: Some(100),
+                preview_points: Some(4),
+                ..WaveformOptions::default()
+            },
+            ..ProcessOptions::default()
+        };
+        let result = process_audio(bytes_source(wav.clone()), &options).unwrap();
+        assert_eq!(result.waveform_len, 100);
+        let bytes = general_purpose::STANDARD
+            .decode(result.preview_waveform_base64.unwrap())
+            .unwrap();
+        let preview: Vec<f32> = bytes
+            .chunks_exact(4)
+            .map(|b| f32::from_le_bytes(b.try_into().unwrap()))
+            .collect();
+        let expected = WaveformOptions {
+            num_points: Some(4),
+            ..WaveformOptions::default()
+        };
+        assert_eq!(preview, compute_waveform_values(&pcm, &expected));
+        assert_eq!(preview.len(), 4);
+
+        let streaming = ProcessOptions {
+            streaming: true,
+            ..options
+        };
+        assert!(matches!(
+            process_audio(bytes_source(wav), &streaming),
+            Err(ProcessError::InvalidParameter(_))
+        ));
+    }
+
+    #[test]
+    fn test_process_pcm() {
+        let pcm: Vec<f32> = (0..8000).map(|i| 0.5 * (i as f32 / 9.0).sin()).collect();
+        let options = ProcessOptions::default();
+        let result = process_pcm(pcm.clone(), 2, 8000, &options).unwrap();
+        let wav = encode_to_wav(&pcm, 2, 8000, SampleFormat::F32, None).unwrap();
+        let decoded = process_audio(bytes_source(wav), &options).unwrap();
+        assert_eq!(result.waveform_base64, decoded.waveform_base64);
+        assert_eq!(result.duration_seconds, 0.5);
+        assert_eq!((result.channels, result.codec.as_str()), (2, "pcm_f32le"));
+        assert_eq!(&result.ogg_data[..4], b"OggS");
+
+        let err = process_pcm(vec![0.0; 3], 2, 8000, &options).err().unwrap();
+        assert_eq!(
+            err.to_string(),
+            "Invalid parameter: the number of samples must be a multiple of channels, got 3 \
+             samples for 2 channels"
+        );
+        assert!(process_pcm(Vec::new(), 0, 8000, &options).is_err());
+    }
+
+    #[test]
+    fn test_read_tags_without_metadata() {
+        let wav = encode_to_wav(&[0.0; 64], 1, 8000, SampleFormat::I16, None).unwrap();
+        let metadata = read_tags(bytes_source(wav), &DecodeOptions::default()).unwrap();
+        assert!(metadata.tags.is_empty() && metadata.chapters.is_empty());
+    }
+
+    /// Appends extra metadata blocks to the STREAMINFO-only header `encode_to_flac` writes.
+    fn flac_with_blocks(pcm: &[f32], blocks: &[(u8, Vec<u8>)]) -> Vec<u8> {
+        let flac = encode_to_flac(pcm, 1, 8000, 5, None).unwrap();
+        let mut output = flac[..42].to_vec();
+        output[4] &= 0x7f;
+        for (i, (block_type, body)) in blocks.iter().enumerate() {
+            let last = if i + 1 == blocks.len() { 0x80 } else { 0 };
+            output.push(block_type | last);
+            output.extend_from_slice(&(body.len() as u32).to_be_bytes()[1..]);
+            output.extend_from_slice(body);
+        }
+        output.extend_from_slice(&flac[42..]);
+        output
+    }
+
+    #[test]
+    fn test_read_chapters() {
+        let pcm = vec![0.1; 8000];
+        let mut comments = Vec::new();
+        comments.extend_from_slice(&0u32.to_le_bytes());
+        let entries = [
+            "TITLE=Book",
+            "CHAPTER002=00:00:00.500",
+            "CHAPTER002NAME=Two",
+            "CHAPTER001=00:00:00.000",
+            "CHAPTER001NAME=One",
+            "CHAPTER003=0:0:0.750",
+        ];
+        comments.extend_from_slice(&(entries.len() as u32).to_le_bytes());
+        for entry in entries {
+            comments.extend_from_slice(&(entry.len() as u32).to_le_bytes());
+            comments.extend_from_slice(entry.as_bytes());
+        }
+        let flac = flac_with_blocks(&pcm, &[(4, comments)]);
+        let metadata = read_tags(bytes_source(flac), &DecodeOptions::default()).unwrap();
+        assert!(metadata
+            .tags
+            .contains(&("title".to_string(), "Book".to_string())));
+        let chapter = |start_seconds, title: Option<&str>| Chapter {
+            start_seconds,
+            title: title.map(str::to_string),
+        };
+        assert_eq!(
+            metadata.chapters,
+            [
+                chapter(0.0, Some("One")),
+                chapter(0.5, Some("Two")),
+                chapter(0.75, None)
+            ]
+        );
+
+        let mut cuesheet = vec![0; 128 + 8 + 1 + 258];
+        cuesheet.push(3);
+        for (offset, number) in [(0u64, 1u8), (2000, 2), (8000, 255)] {
+            cuesheet.extend_from_slice(&offset.to_be_bytes());
+            cuesheet.push(number);
+            cuesheet.extend_from_slice(&[0; 12 + 14]);
+            cuesheet.push(1);
+            cuesheet.extend_from_slice(&[0; 12]);
+        }
+        let flac = flac_with_blocks(&pcm, &[(5, cuesheet)]);
+        let metadata = read_tags(bytes_source(flac), &DecodeOptions::default()).unwrap();
+        assert_eq!(metadata.chapters, [chapter(0.0, None), chapter(0.25, None)]);
+    }
+
+    #[test]
+    fn test_mp3_output() {
+        let pcm: Vec<f32> = (0..44100 * 2)
+            .map(|i| 0.5 * (i as f32 / 20.0).sin())
+            .collect();
+        let mp3 = encode_to_mp3(&pcm, 2, 44100, 128).unwrap();
+        let options = DecodeOptions {
+            hint: Some("mp3".to_string()),
+            ..DecodeOptions::default()
+        };
+        let audio = decode_to_pcm(bytes_source(mp3), &options).unwrap();
+        assert_eq!((audio.channels, audio.sample_rate), (2, 44100));
+        assert_eq!((audio.codec.as_str(), audio.bits_per_sample), ("mp3", None));
+        // LAME adds encoder delay and padding to whole frames.
+        assert!((audio.duration_seconds - 1.0).abs() < 0.1);
+
+        assert!(encode_to_mp3(&pcm, 2, 44100, 100).is_err());
+        assert!(encode_to_mp3(&pcm[..6], 3, 44100, 128).is_err());
+    }
+
+    #[test]
+    fn test_flac_round_trip() {
+        let pcm: Vec<f32> = (0..800000).map(|i| 0.5 * (i as f32 / 10.0).sin()).collect();
+        for compression_level in [0, 5, 8] {
+            let flac =
+                encode_to_flac(&pcm, 2, 16000, compression_level, Some(Dither::default())).unwrap();
+            assert_eq!(&flac[..4], b"fLaC");
+            let audio = decode_to_pcm(bytes_source(flac), &DecodeOptions::default()).unwrap();
+            assert_eq!((audio.channels, audio.sample_rate), (2, 16000));
+            assert_eq!(
+                (audio.codec.as_str(), audio.bits_per_sample),
+                ("flac", Some(16))
+            );
+            assert_eq!(audio.pcm.len(), pcm.len());
+            // Rounding plus dither is within 1.5 LSB; the decoder scaling by 32768 rather than
+            // 32767 adds up to another half.
+            for (decoded, original) in audio.pcm.iter().zip(&pcm) {
+                assert!((decoded - original).abs() <= 2.0 / 32767.0);
+            }
+        }
+    }
+
+    #[test]
+    fn test_wav_header() {
+        let pcm = [0.0, 0.5, -0.5, 1.0, -1.0, 0.25, 0.75];
+        let wav = encode_to_wav(&pcm, 3, 22050, SampleFormat::I16, None).unwrap();
+        assert_eq!(&wav[..4], b"RIFF");
+        assert_eq!(u32_le(&wav, 4) as usize, wav.len() - 8);
+        assert_eq!(u16_le(&wav, 20), WAVE_FORMAT_EXTENSIBLE);
+        assert_eq!(u16_le(&wav, 22), 3);
+        assert_eq!(u32_le(&wav, 24), 22050);
+        assert_eq!(u32_le(&wav, 28), 22050 * 3 * 2);
+        assert_eq!(u16_le(&wav, 32), 6);
+        assert_eq!(&wav[60..64], b"data");
+        assert_eq!(u32_le(&wav, 64), 12);
+        assert_eq!(wav.len(), 68 + 12);
+
+        let wav = encode_to_wav(&pcm[..4], 2, 48000, SampleFormat::F32, None).unwrap();
+        assert_eq!(u16_le(&wav, 20), WAVE_FORMAT_IEEE_FLOAT);
+        assert_eq!(&wav[36..40], b"fact");
+        assert_eq!(u32_le(&wav, 44), 2);
+        assert_eq!(&wav[48..52], b"data");
+        assert_eq!(u32_le(&wav, 52), 16);
+        assert_eq!(wav.len(), 56 + 16);
+
+        let wav = encode_to_wav(&pcm[..4], 2, 48000, SampleFormat::I24, None).unwrap();
+        assert_eq!(u16_le(&wav, 20), WAVE_FORMAT_PCM);
+        assert_eq!(u16_le(&wav, 32), 6);
+        assert_eq!(u16_le(&wav, 34), 24);
+        assert_eq!(u32_le(&wav, 40), 12);
+        assert_eq!(wav.len(), 44 + 12);
+    }
+
+    #[test]
+    fn test_g711_output() {
+        // Reference values from the ITU-T G.711 tables.
+        assert_eq!(
+            [0, 1, -1, 32767, -32768, 1000].map(linear_to_mulaw),
+            [0xff, 0xff, 0x7f, 0x80, 0x00, 0xce]
+        );
+        assert_eq!(
+            [0, 16, -1, 32767, -32768, 1000].map(linear_to_alaw),
+            [0xd5, 0xd4, 0x55, 0xaa, 0x2a, 0xfa]
+        );
+
+        let pcm: Vec<f32> = (0..1600).map(|i| 0.5 * (i as f32 / 7.0).sin()).collect();
+        for (output_format, codec) in [
+            (OutputFormat::MuLaw, "pcm_mulaw"),
+            (OutputFormat::ALaw, "pcm_alaw"),
+        ] {
+            let options = EncodeOptions {
+                format: output_format,
+                ..EncodeOptions::default()
+            };
+            options.validate().unwrap();
+            let wav = encode_audio(&pcm, 1, 8000, &options).unwrap();
+            assert_eq!(u16_le(&wav, 34), 8);
+            assert_eq!(wav.len(), 58 + pcm.len());
+            let decoded = decode_to_pcm(bytes_source(wav), &DecodeOptions::default()).unwrap();
+            assert_eq!(decoded.codec, codec);
+            assert_eq!(decoded.pcm.len(), pcm.len());
+            // An 8-bit companded step near half scale is 1/64 of full scale.
+            for (original, decoded) in pcm.iter().zip(&decoded.pcm) {
+                assert!((original - decoded).abs() < 1.0 / 64.0);
+            }
+
+            let resampled = encode_audio(&[0.1; 3200], 2, 16000, &options).unwrap();
+            assert_eq!(u32_le(&resampled, 24), 8000);
+            assert_eq!(u32_le(&resampled, 54), 800 * 2);
+        }
+    }
+
+    #[test]
+    fn test_integer_wav_round_trip() {
+        let pcm: Vec<f32> = (0..4800).map(|i| 0.5 * (i as f32 / 30.0).sin()).collect();
+        for (sample_format, bits) in [(SampleFormat::I16, 16), (SampleFormat::I24, 24)] {
+            let wav =
+                encode_to_wav(&pcm, 2, 48000, sample_format, Some(Dither::default())).unwrap();
+            let decoded = decode_to_pcm(bytes_source(wav), &DecodeOptions::default()).unwrap();
+            assert_eq!(decoded.bits_per_sample, Some(bits));
+            assert_eq!(decoded.pcm.len(), pcm.len());
+            // TPDF dither adds at most one step and decoding rescales by 2^(bits - 1).
+            let tolerance = 2.0 / (1u32 << (bits - 1)) as f32;
+            for (original, decoded) in pcm.iter().zip(&decoded.pcm) {
+                assert!((original - decoded).abs() <= tolerance);
+            }
+        }
+    }
+
+    #[test]
+    fn test_dither() {
+        // A tone of a third of an LSB rounds away to digital silence unless it is dithered.
+        let pcm: Vec<f32> = (0..4096)
+            .map(|i| (i as f32 * 0.1).sin() / 3.0 / 32767.0)
+            .collect();
+        let samples = |dither| float_to_int(&pcm, 16, dither);
+        assert!(samples(None).iter().all(|&value| value == 0));
+        let dithered = samples(Some(Dither::new(7)));
+        assert!(dithered.iter().any(|&value| value != 0));
+        assert!(dithered.iter().all(|value| value.abs() <= 1));
+        assert_eq!(dithered, samples(Some(Dither::new(7))));
+        assert_ne!(dithered, samples(Some(Dither::new(8))));
+
+        let options = EncodeOptions {
+            format: OutputFormat::Wav,
+            sample_format: SampleFormat::I16,
+            dither: true,
+            dither_seed: Some(7),
+            ..Default::default()
+        };
+        options.validate().unwrap();
+        let wav = encode_audio(&pcm, 1, 8000, &options).unwrap();
+        let wav_samples: Vec<i32> = wav[wav.len() - pcm.len() * 2..]
+            .chunks_exact(2)
+            .map(|sample| i16::from_le_bytes([sample[0], sample[1]]).into())
+            .collect();
+        assert_eq!(wav_samples, dithered);
+
+        for options in [
+            EncodeOptions {
+                dither: true,
+                ..Default::default()
+            },
+            EncodeOptions {
+                format: OutputFormat::Flac,
+                dither_seed: Some(7),
+                ..Default::default()
+            },
+            EncodeOptions {
+                format: OutputFormat::Flac,
+                dither: true,
+                dither_seed: Some(0),
+                ..Default::default()
+            },
+        ] {
+            assert!(matches!(
+                options.validate(),
+                Err(ProcessError::InvalidParameter(_))
+            ));
+        }
+    }
+
+    #[test]
+    fn test_chained_ogg() {
+        let first = encode_to_ogg(&[0.25; 8000], 1, 8000, &EncodeOptions::default()).unwrap();
+        let second = encode_to_ogg(&[-0.25; 12000], 1, 8000, &EncodeOptions::default()).unwrap();
+        // Symphonia's end-of-stream probe skips the first link of a seekable chain this short, so
+        // it is read like a network stream.
+        let source = ReadOnlySource::new(Cursor::new([first.clone(), second].concat()));
+        let audio = decode_to_pcm(Box::new(source), &DecodeOptions::default()).unwrap();
+        assert!(audio.pcm.len() >= 20000);
+        // The second stream's samples follow the first one's instead of being dropped.
+        assert!(audio.pcm[..7000].iter().all(|&sample| sample > 0.0));
+        assert!(audio.pcm[audio.pcm.len() - 4000..]
+            .iter()
+            .all(|&sample| sample < 0.0));
+
+        let resampled = encode_to_ogg(&[0.25; 8000], 1, 16000, &EncodeOptions::default()).unwrap();
+        let chained = [first, resampled].concat();
+        let source = ReadOnlySource::new(Cursor::new(chained.clone()));
+        let audio = decode_to_pcm(Box::new(source), &DecodeOptions::default()).unwrap();
+        assert!(audio.pcm.len() >= 8000 && audio.pcm.len() < 16000);
+        assert_eq!(
+            audio.truncation_error.unwrap(),
+            "chained stream changes the sample rate from 8000 to 16000"
+        );
+        let strict = DecodeOptions {
+            strict: true,
+            ..DecodeOptions::default()
+        };
+        let source = ReadOnlySource::new(Cursor::new(chained));
+        let err = decode_to_pcm(Box::new(source), &strict).err().unwrap();
+        assert_eq!(
+            err.to_string(),
+            "chained stream changes the sample rate from 8000 to 16000"
+        );
+    }
+
+    /// Splits an Ogg stream into its pages.
+    fn ogg_pages(mut data: &[u8]) -> Vec<&[u8]> {
+        let mut pages = Vec::new();
+        while !data.is_empty() {
+            let segments = data[26] as usize;
+            let body: usize = data[27..27 + segments]
+                .iter()
+                .map(|&len| len as usize)
+                .sum();
+            let (page, rest) = data.split_at(27 + segments + body);
+            pages.push(page);
+            data = rest;
+        }
+        pages
+    }
+
+    #[test]
+    fn test_track_index() {
+        let mono = encode_to_ogg(&[0.25; 8000], 1, 8000, &EncodeOptions::default()).unwrap();
+        let stereo = encode_to_ogg(&[-0.25; 32000], 2, 16000, &EncodeOptions::default()).unwrap();
+        let (mono, stereo) = (ogg_pages(&mono), ogg_pages(&stereo));
+        // Multiplex the two streams: both first pages, then both sets of headers, then the audio.
+        let multiplexed = [
+            &mono[..1],
+            &stereo[..1],
+            &mono[1..2],
+            &stereo[1..2],
+            &mono[2..],
+            &stereo[2..],
+        ]
+        .concat()
+        .concat();
+
+        let tracks =
+            list_audio_tracks(bytes_source(multiplexed.clone()), &DecodeOptions::default())
+                .unwrap();
+        // The Ogg reader orders tracks by their random stream serials.
+        let mut summary: Vec<_> = tracks
+            .iter()
+            .map(|track| {
+                (
+                    track.codec.as_str(),
+                    track.language.as_deref(),
+                    track.channels,
+                    track.sample_rate,
+                )
+            })
+            .collect();
+        summary.sort();
+        assert_eq!(
+            summary,
+            [
+                ("vorbis", None, Some(1), Some(8000)),
+                ("vorbis", None, Some(2), Some(16000))
+            ]
+        );
+        let stereo_index = tracks
+            .iter()
+            .find(|track| track.channels == Some(2))
+            .unwrap()
+            .index;
+
+        let decode = |track_index| {
+            let options = DecodeOptions {
+                track_index,
+                ..DecodeOptions::default()
+            };
+            decode_to_pcm(bytes_source(multiplexed.clone()), &options)
+        };
+        let first = decode(None).unwrap();
+        assert_eq!(first.channels, tracks[0].channels.unwrap());
+        let stereo = decode(Some(stereo_index)).unwrap();
+        assert_eq!((stereo.channels, stereo.sample_rate), (2, 16000));
+        assert!(stereo.pcm.iter().all(|&sample| sample < 0.0));
+        assert_eq!(
+            decode(Some(2)).err().unwrap().to_string(),
+            "track_index 2 is out of range, the input has 2 audio tracks"
+        );
+    }
+
+    #[test]
+    fn test_output_capacity() {
+        let options = EncodeOptions::default();
+        // Ten seconds of stereo at 64 kbps per channel.
+        assert_eq!(options.output_capacity(441_000, 2, 44100), 160_000);
+        let options = EncodeOptions {
+            bitrate_bps: Some(96_000),
+            ..Default::default()
+        };
+        assert_eq!(options.output_capacity(441_000, 2, 44100), 120_000);
+        assert_eq!(
+            options.output_capacity(usize::MAX / 1024, 8, 8000),
+            MAX_PREALLOCATION
+        );
+        let options = EncodeOptions {
+            expected_size_hint: Some(12_345),
+            ..Default::default()
+        };
+        assert_eq!(options.output_capacity(441_000, 2, 44100), 12_345);
+
+        let wav = encode_to_wav(&[0.25; 16000], 2, 8000, SampleFormat::F32, None).unwrap();
+        let options = DecodeOptions {
+            start_seconds: 0.25,
+            ..DecodeOptions::default()
+        };
+        let stream = PcmStream::open(bytes_source(wav), &options).unwrap();
+        assert_eq!(stream.expected_frames(), 6000);
+    }
+
+    #[test]
+    fn test_allowed_formats() {
+        let wav = encode_to_wav(&[0.25; 800], 1, 8000, SampleFormat::F32, None).unwrap();
+        let decode = |allowed_formats| {
+            let options = DecodeOptions {
+                allowed_formats: Some(allowed_formats),
+                ..DecodeOptions::default()
+            };
+            decode_to_pcm(bytes_source(wav.clone()), &options)
+        };
+        assert_eq!(decode(vec![InputFormat::Wav]).unwrap().pcm.len(), 800);
+        let err = decode(vec![InputFormat::Mp3, InputFormat::Ogg])
+            .err()
+            .unwrap();
+        assert!(is_unsupported(&*err));
+
+        // The ID3v2 tag in front of the MP3 doesn't stop it from being found.
+        let mp3 = std::fs::read("test.mp3").unwrap();
+        let options = DecodeOptions {
+            allowed_formats: Some(vec![InputFormat::Mp3]),
+            ..DecodeOptions::default()
+        };
+        let tracks = list_audio_tracks(bytes_source(mp3), &options).unwrap();
+        assert_eq!(tracks[0].codec, "mp3");
+
+        let options = DecodeOptions {
+            allowed_formats: Some(Vec::new()),
+            ..DecodeOptions::default()
+        };
+        assert!(options.validate().is_err());
+        assert!("webm".parse::<InputFormat>().is_err());
+    }
+
+    #[test]
+    fn test_invalid_stream_parameters() {
+        let err = encode_to_ogg(&[], 2, 0, &EncodeOptions::default()).unwrap_err();
+        assert_eq!(err.to_string(), "invalid sample rate 0");
+
+        let err = encode_to_ogg(&[], 0, 44100, &EncodeOptions::default()).unwrap_err();
+        assert_eq!(err.to_string(), "invalid channel count 0");
+
+        let err = encode_to_ogg(&[], 9, 44100, &EncodeOptions::default()).unwrap_err();
+        assert_eq!(
+            err.to_string(),
+            "9 channels not supported by vorbis (max 8), set mono to downmix"
+        );
     }
 }
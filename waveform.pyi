@@ -1,8 +1,215 @@
-from typing import Tuple
+from collections.abc import Callable, Sequence
+from os import PathLike
+from types import TracebackType
+from typing import Literal, Protocol, TypedDict
+
+import numpy as np
+import numpy.typing as npt
+from typing_extensions import Self, Unpack
+
+class WaveformError(Exception):
+    symphonia_error: Literal[
+        "IoError", "DecodeError", "SeekError", "Unsupported", "LimitError", "ResetRequired"
+    ] | None
+
+class DecodeError(WaveformError): ...
+class UnsupportedFormatError(DecodeError): ...
+class EncodeError(WaveformError): ...
+class InvalidParameterError(WaveformError): ...
+class LimitExceededError(WaveformError): ...
+
+# Only present when built with the `reqwest` feature.
+class NetworkError(WaveformError): ...
+
+class SupportsRead(Protocol):
+    def read(self, size: int, /) -> bytes: ...
+
+AudioInput = bytes | bytearray | SupportsRead
 
 class AudioResult:
     ogg_data: bytes
     waveform_base64: str
+    waveform: list[float]
+    waveform_len: int
+    waveform_min: float
+    waveform_max: float
     duration_seconds: float
+    reported_duration_seconds: float | None
+    truncation_error: str | None
+    @property
+    def truncated(self) -> bool: ...
+    sample_rate: int
+    channels: int
+    channel_waveforms: list[str] | None
+    timestamps: list[float] | None
+    waveforms: dict[str, str] | None
+    preview_waveform_base64: str | None
+    input_lufs: float | None
+    peak: float
+    clip_count: int
+    stereo_correlation: float | None
+    trimmed_start_ms: float
+    trimmed_end_ms: float
+    dropped_packets: int
+    codec: str
+    bits_per_sample: int | None
+    pcm_hash: str | None
+    decode_ms: float | None
+    encode_ms: float | None
+    waveform_ms: float | None
+
+class TrackInfo(TypedDict):
+    index: int
+    codec: str
+    language: str | None
+    channels: int | None
+    sample_rate: int | None
+
+class ValidationResult(TypedDict):
+    valid: bool
+    reasons: list[str]
+    duration_seconds: float | None
+    sample_rate: int | None
+    channels: int | None
+    codec: str | None
+
+class Chapter(TypedDict):
+    start_seconds: float
+    title: str | None
+
+class EncoderOptions(TypedDict, total=False):
+    quality: float | None
+    bitrate_mode: Literal["vbr", "abr", "cbr"]
+    bitrate_bps: int | None
+    bitrate_kbps: int | None
+    compression_level: int | None
+    dither: bool
+    dither_seed: int | None
+    ogg_serial: int | None
+    expected_size_hint: int | None
+    output_codec: Literal["vorbis", "opus"]
+    opus_application: Literal["audio", "voip", "restricted_lowdelay"]
+    output_format: Literal["ogg", "wav", "mp3", "flac", "mulaw", "alaw"]
+    sample_format: Literal["f32", "i16", "i24"]
+
+class GenerateOptions(EncoderOptions, total=False):
+    hint: str | None
+    start_seconds: float
+    duration_seconds: float | None
+    progress: Callable[[float], object] | None
+    strict: bool
+    # Decoding stops after this many corrupt packets in a row, 100 by default, and the result
+    # is marked truncated; None skips any number of them.
+    max_consecutive_errors: int | None
+    max_duration_seconds: float | None
+    track_index: int | None
+    allowed_formats: Sequence[Literal["aac", "flac", "mkv", "mp3", "mp4", "ogg", "wav"]] | None
+    streaming: bool
+    collect_timings: bool
+    pcm_hash: bool
+    passthrough_if_ogg: bool
+    highpass_hz: float | None
+    gain_db: float
+    channel_map: Sequence[int] | None
+    mono: bool
+    target_sample_rate: int | None
+    trim_silence: bool
+    silence_threshold_db: float
+    min_silence_ms: float
+    reverse: bool
+    fade_in_ms: float
+    fade_out_ms: float
+    fade_curve: Literal["linear", "sine"]
+    normalize: bool
+    normalize_target_db: float
+    limiter: bool
+    target_lufs: float | None
+    chunk_size: int
+    num_points: int | None
+    preview_points: int | None
+    waveform_format: Literal["f32", "u8"]
+    waveform_formats: list[Literal["f32", "u8"]]
+    mode: Literal["peak", "rms", "minmax", "spectral"]
+    spectral_band: tuple[float, float] | None
+    weighting: Literal["none", "a"]
+    per_channel: bool
+    channel: int | None
+    stereo_mode: Literal["lr", "mid", "side"]
+    pad_to: int | None
+    timestamps: bool
+    url_safe: bool
+    scale: Literal["linear", "db"]
+    db_floor: float
+    overlap: float
+    decay: float
+
+class StreamingEncoder:
+    def __init__(
+        self, sample_rate: int, channels: int, **options: Unpack[EncoderOptions]
+    ) -> None: ...
+    def push(self, chunk: npt.NDArray[np.float32] | bytes) -> bytes: ...
+    def finish(self) -> bytes: ...
+    def __enter__(self) -> Self: ...
+    def __exit__(
+        self,
+        exc_type: type[BaseException] | None,
+        exc_value: BaseException | None,
+        traceback: TracebackType | None,
+    ) -> bool: ...
+
+class Decoder:
+    def __init__(self, **options: Unpack[GenerateOptions]) -> None: ...
+    def decode(self, audio: AudioInput) -> tuple[npt.NDArray[np.float32], int, int]: ...
+    def generate(self, audio: AudioInput) -> AudioResult: ...
 
-def generate(audio: bytes) -> Tuple[str, float]: ...
+def generate(audio: AudioInput, **options: Unpack[GenerateOptions]) -> AudioResult: ...
+def generate_from_path(
+    path: str | PathLike[str], **options: Unpack[GenerateOptions]
+) -> AudioResult: ...
+def generate_to_path(
+    audio: AudioInput,
+    output_path: str | PathLike[str],
+    **options: Unpack[GenerateOptions],
+) -> AudioResult: ...
+# Only present when built with the `reqwest` feature.
+def generate_from_url(
+    url: str,
+    timeout: float | None = 30.0,
+    max_bytes: int | None = 268435456,
+    **options: Unpack[GenerateOptions],
+) -> AudioResult: ...
+def generate_batch(
+    inputs: Sequence[AudioInput], **options: Unpack[GenerateOptions]
+) -> list[AudioResult | WaveformError]: ...
+def compute_waveform(
+    audio: AudioInput, **options: Unpack[GenerateOptions]
+) -> tuple[str, float]: ...
+def transcode(audio: AudioInput, **options: Unpack[GenerateOptions]) -> bytes: ...
+def analyze(audio: AudioInput, **options: Unpack[GenerateOptions]) -> str: ...
+# The limits use the `generate` option names, e.g. `max_duration_seconds`, not `max_duration`.
+def validate(
+    audio: AudioInput,
+    hint: str | None = None,
+    max_duration_seconds: float | None = None,
+    min_duration_seconds: float | None = None,
+    allowed_sample_rates: Sequence[int] | None = None,
+    allowed_channels: Sequence[int] | None = None,
+) -> ValidationResult: ...
+def from_pcm(
+    samples: npt.NDArray[np.float32] | bytes,
+    channels: int,
+    sample_rate: int,
+    **options: Unpack[GenerateOptions],
+) -> AudioResult: ...
+def concat(
+    inputs: Sequence[AudioInput], **options: Unpack[GenerateOptions]
+) -> AudioResult: ...
+def read_metadata(
+    audio: AudioInput, hint: str | None = None
+) -> dict[str, str | list[Chapter]]: ...
+def list_tracks(audio: AudioInput, hint: str | None = None) -> list[TrackInfo]: ...
+def decode_pcm(
+    audio: AudioInput, hint: str | None = None
+) -> tuple[npt.NDArray[np.float32], int, int]: ...
+def resample_waveform(points: Sequence[float], target_points: int) -> list[float]: ...
+def probe_duration(audio: AudioInput, hint: str | None = None) -> float: ...